@@ -0,0 +1,201 @@
+//! A minimal ITM/DWT packet decoder
+//!
+//! This decodes the packet types that matter for most captures (synchronization, overflow,
+//! local timestamps, stimulus port pages, instrumentation and exception trace) without the `itm`
+//! crate's decoder. It operates on whole bytes and never fails on a packet: all other packet
+//! types, including malformed ones, are skipped based on their framing. This makes it a fallback
+//! for traces that the `itm` crate decoder rejects.
+use itm::{cortex_m::VectActive, ExceptionAction, TimestampDataRelation, TracePacket};
+use log::debug;
+use std::{
+    io::{BufReader, Bytes, Read},
+    time::Duration,
+};
+
+/// Minimum number of zero bytes before the final byte of a synchronization packet.
+const SYNC_ZEROS: usize = 5;
+
+/// Interval at which the input is read again at its end, see [crate::DecodeOptions::ignore_eof].
+const EOF_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Find the first synchronization packet in ITM trace data.
+///
+/// # Returns
+/// The offset of the packet, e.g. to discard the data before it that may start within a packet.
+pub(crate) fn find_sync(data: &[u8]) -> Option<usize> {
+    let mut zeros = 0;
+    for (i, byte) in data.iter().enumerate() {
+        match byte {
+            0x00 => zeros += 1,
+            0x80 if zeros >= SYNC_ZEROS => return Some(i - zeros),
+            _ => zeros = 0,
+        }
+    }
+    None
+}
+
+/// The built-in packet decoder.
+///
+/// This yields the same items as [itm::Singles] so that it can be substituted for it.
+pub(crate) struct BuiltinDecoder<R: Read> {
+    bytes: Bytes<BufReader<R>>,
+    ignore_eof: bool,
+    /// Number of packets that were skipped.
+    pub skipped: usize,
+}
+
+impl<R: Read> BuiltinDecoder<R> {
+    /// Construct a decoder reading the raw trace from `reader`.
+    ///
+    /// # Args
+    /// * `ignore_eof` - Wait for more data at the end of the input.
+    pub fn new(reader: R, ignore_eof: bool) -> Self {
+        Self {
+            bytes: BufReader::new(reader).bytes(),
+            ignore_eof,
+            skipped: 0,
+        }
+    }
+
+    /// Read the next byte of the trace.
+    fn byte(&mut self) -> Option<std::io::Result<u8>> {
+        loop {
+            match self.bytes.next() {
+                None if self.ignore_eof => std::thread::sleep(EOF_POLL_INTERVAL),
+                byte => return byte,
+            }
+        }
+    }
+
+    /// Read `len` payload bytes.
+    fn payload(&mut self, len: usize) -> Option<std::io::Result<Vec<u8>>> {
+        (0..len).map(|_| self.byte()).collect()
+    }
+
+    /// Read payload bytes up to and including the first byte without the continuation bit.
+    fn continuation(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        let mut payload = vec![];
+        loop {
+            let byte = match self.byte()? {
+                Ok(byte) => byte,
+                Err(e) => return Some(Err(e)),
+            };
+            payload.push(byte);
+            if byte & 0x80 == 0 {
+                return Some(Ok(payload));
+            }
+        }
+    }
+
+    /// Decode the packet following `header`.
+    ///
+    /// # Returns
+    /// `Ok(None)` if the packet is not supported and was skipped.
+    fn packet(&mut self, header: u8) -> Option<std::io::Result<Option<TracePacket>>> {
+        let packet = match header {
+            0x00 => {
+                let mut zeros = 1;
+                loop {
+                    match self.byte()? {
+                        Ok(0x00) => zeros += 1,
+                        Ok(0x80) if zeros >= SYNC_ZEROS => break Some(TracePacket::Sync),
+                        Ok(byte) => {
+                            debug!("Invalid synchronization: {zeros} zeros, then {byte:#04x}");
+                            break None;
+                        }
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            }
+            0x70 => Some(TracePacket::Overflow),
+            // Stimulus port page extension without continuation.
+            _ if header & 0x8F == 0x08 => Some(TracePacket::Extension {
+                page: (header >> 4) & 0b111,
+            }),
+            // Local timestamp, format 2.
+            _ if header & 0x8F == 0x00 => Some(TracePacket::LocalTimestamp2 { ts: header >> 4 }),
+            // Local timestamp, format 1.
+            _ if header & 0xCF == 0xC0 => {
+                let payload = match self.continuation()? {
+                    Ok(payload) => payload,
+                    Err(e) => return Some(Err(e)),
+                };
+                let ts = payload
+                    .iter()
+                    .take(4)
+                    .enumerate()
+                    .fold(0, |ts, (i, byte)| ts | ((byte & 0x7F) as u32) << (7 * i));
+                let data_relation = match (header >> 4) & 0b11 {
+                    0b00 => TimestampDataRelation::Sync,
+                    0b01 => TimestampDataRelation::UnknownDelay,
+                    0b10 => TimestampDataRelation::AssocEventDelay,
+                    _ => TimestampDataRelation::UnknownAssocEventDelay,
+                };
+                Some(TracePacket::LocalTimestamp1 { ts, data_relation })
+            }
+            // Source packets.
+            _ if header & 0b11 != 0 => {
+                let len = [1, 2, 4][(header & 0b11) as usize - 1];
+                let payload = match self.payload(len)? {
+                    Ok(payload) => payload,
+                    Err(e) => return Some(Err(e)),
+                };
+                let port = header >> 3;
+                match (header & 0b100 != 0, port) {
+                    (false, port) => Some(TracePacket::Instrumentation { port, payload }),
+                    (true, 1) => exception_trace(&payload),
+                    _ => None,
+                }
+            }
+            // Other protocol packets, their payload uses continuation bits.
+            _ => {
+                if header & 0x80 != 0 {
+                    if let Err(e) = self.continuation()? {
+                        return Some(Err(e));
+                    }
+                }
+                None
+            }
+        };
+        if packet.is_none() {
+            debug!("Skipping unsupported packet with header {header:#04x}");
+        }
+        Some(Ok(packet))
+    }
+}
+
+/// Decode the payload of an exception trace packet.
+fn exception_trace(payload: &[u8]) -> Option<TracePacket> {
+    if payload.len() != 2 {
+        return None;
+    }
+    let number = ((payload[1] as u16 & 1) << 8) | payload[0] as u16;
+    let action = match (payload[1] >> 4) & 0b11 {
+        0b01 => ExceptionAction::Entered,
+        0b10 => ExceptionAction::Exited,
+        0b11 => ExceptionAction::Returned,
+        _ => return None,
+    };
+    Some(TracePacket::ExceptionTrace {
+        exception: VectActive::from(number)?,
+        action,
+    })
+}
+
+impl<R: Read> Iterator for BuiltinDecoder<R> {
+    type Item = Result<TracePacket, itm::DecoderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = match self.byte()? {
+                Ok(header) => header,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match self.packet(header)? {
+                Ok(Some(packet)) => return Some(Ok(packet)),
+                Ok(None) => self.skipped += 1,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
@@ -0,0 +1,1786 @@
+//! Trace capture from a target
+//!
+//! A [CaptureSession] configures the target for tracing into trace memory once and can then be
+//! used for any number of captures without re-attaching the probe.
+use crate::{
+    builtin::find_sync,
+    etf::{Backoff, Configuration, EmbeddedTraceFifo, Mode, Status, STOP_TIMEOUT},
+    etr::{EmbeddedTraceRouter, EtrBuffer},
+    formatter::{self, Deformatter, ITM_ID},
+    output::json_string,
+    report::{self, CaptureConfig, CaptureReport},
+    EtfError,
+};
+use log::{debug, info, warn};
+use probe_rs::{
+    architecture::arm::{
+        ap::AccessPort,
+        component::{find_component, Dwt, Itm, TraceFunnel, TraceSink},
+        memory::{CoresightComponent, PeripheralType},
+        ArmError, ArmProbeInterface, DpAddress,
+    },
+    DebugProbeError, DebugProbeInfo, DebugProbeSelector, Error, MemoryInterface, Permissions,
+    Probe, Session, WireProtocol,
+};
+use probe_rs_target::CoreAccessOptions;
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+const REGISTER_OFFSET_FUNNEL_CTRL: u32 = 0x000;
+const REGISTER_OFFSET_FUNNEL_DEVID: u32 = 0xFC8;
+const REGISTER_OFFSET_ITM_TER0: u32 = 0xE00;
+const REGISTER_OFFSET_ITM_TCR: u32 = 0xE80;
+const REGISTER_OFFSET_TPIU_ACPR: u32 = 0x010;
+const REGISTER_OFFSET_REPLICATOR_IDFILTER: [u32; 2] = [0x000, 0x004];
+
+/// DEVTYPE of a trace replicator: major type trace link, sub type splitter.
+const REPLICATOR_DEVTYPE: u8 = 0x22;
+
+/// How trace data is read out of the ETF.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Readout {
+    /// This crate configured the ETF as a software FIFO using probe-rs, which then reads it.
+    ProbeRs,
+    /// The ETF was configured externally and is drained.
+    Drain,
+    /// The ETF is a circular buffer that is stopped and read completely or only its tail.
+    Circular { words: Option<u32> },
+    /// The ETF drains to an ETR that captures into a buffer in system memory, see
+    /// [CaptureSession::set_etr].
+    Etr { buffer: EtrBuffer },
+}
+
+/// Interval between fill level samples of [CaptureSession::fill_rate].
+const FILL_RATE_POLL: Duration = Duration::from_millis(1);
+
+/// Interval between checks of [CaptureSession::wait_for_trigger].
+const TRIGGER_POLL: Duration = Duration::from_millis(1);
+
+/// The rate at which trace data entered the ETF, see [CaptureSession::fill_rate].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FillRate {
+    /// Number of bytes that entered the ETF, including the formatter overhead.
+    pub bytes: u32,
+    /// The duration of the measurement.
+    pub elapsed: Duration,
+    /// The ETF ran full before the end of the measurement, the rate is a lower bound.
+    pub full: bool,
+}
+
+impl FillRate {
+    /// The data rate in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl std::fmt::Display for FillRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bound = if self.full { "at least " } else { "" };
+        write!(
+            f,
+            "{bound}{:.0} bytes/s ({} bytes in {:?})",
+            self.bytes_per_second(),
+            self.bytes,
+            self.elapsed
+        )
+    }
+}
+
+/// Trace data as read from the ETF, see [CaptureSession::read_raw].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawTrace {
+    /// The data in the order it was read, oldest first.
+    pub data: Vec<u8>,
+    /// The data consists of formatter frames. Otherwise the formatter frames were already
+    /// removed by probe-rs while reading.
+    pub formatted: bool,
+}
+
+impl RawTrace {
+    /// The data as little endian 32 bit words. A partial final word is padded with zeros.
+    pub fn words(&self) -> Vec<u32> {
+        self.data
+            .chunks(4)
+            .map(|chunk| {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(word)
+            })
+            .collect()
+    }
+
+    /// The trace data of the source with trace ID `id`.
+    ///
+    /// Unformatted data only contains the ITM trace data and is returned as is.
+    pub fn source(self, id: u8) -> Vec<u8> {
+        if self.formatted {
+            formatter::deformat(&self.data, id)
+        } else {
+            self.data
+        }
+    }
+}
+
+/// A probe session configured for ITM trace capture into the ETF.
+pub struct CaptureSession {
+    session: Session,
+    components: Vec<CoresightComponent>,
+    /// Index of the traced core.
+    core: usize,
+    /// The TMC captured into, see [find_etf].
+    etf: CoresightComponent,
+    /// The ETR the ETF drains to, see [Self::set_etr].
+    etr: Option<CoresightComponent>,
+    readout: Readout,
+    monitor: Option<Box<dyn FnMut(u8)>>,
+    backoff: Backoff,
+    timeout: Duration,
+    /// Trace ID of the ITM whose data is decoded.
+    source: u8,
+    /// The trace IDs found in the captured data.
+    sources: BTreeSet<u8>,
+    /// Demultiplexes the reads as one stream.
+    deformatter: Deformatter,
+    /// The last circular read begins within the trace, see [EmbeddedTraceFifo::read_circular].
+    seam: bool,
+    /// Wait for a trigger before a circular read, see [Self::set_trigger_timeout].
+    trigger_timeout: Option<Duration>,
+    /// See [Self::peak_fill].
+    peak_fill: usize,
+}
+
+impl CaptureSession {
+    /// Configure tracing of the first core into trace memory.
+    ///
+    /// Programmable trace replicators on the trace path are set to pass the ITM on both outputs,
+    /// as it can not be determined which of them feeds the ETF. The ETF is claimed first, see
+    /// [EmbeddedTraceFifo::claim] and [Self::release].
+    ///
+    /// # Args
+    /// * `session` - An attached probe session.
+    pub fn new(session: Session) -> Result<Self, EtfError> {
+        Self::with_core(session, 0)
+    }
+
+    /// Configure tracing of a core into trace memory, see [Self::new].
+    ///
+    /// # Args
+    /// * `session` - An attached probe session.
+    /// * `core` - Index of the core to trace, as listed by [Session::list_cores].
+    ///
+    /// # Note
+    /// probe-rs configures the first ITM and DWT of the target. On a multi-core target where the
+    /// core has an ITM of its own on its access port, that ITM and DWT are configured instead
+    /// and the first ITM is disabled again: all ITMs configured by probe-rs use the trace ID
+    /// [ITM_ID]. If the ITMs enter different ports of the funnel, the port of the core still has
+    /// to be selected with [Self::set_funnel_port].
+    pub fn with_core(mut session: Session, core: usize) -> Result<Self, EtfError> {
+        let cores = session.list_cores().len();
+        if core >= cores {
+            return Err(EtfError::CoreNotFound { core, cores });
+        }
+        let components = session.get_arm_components(DpAddress::Default)?;
+        let Some(etf) = find_etf(&components, session.get_arm_interface()?)?.cloned() else {
+            return Err(EtfError::ComponentNotFound {
+                sinks: trace_sinks(&mut session),
+            });
+        };
+        claim_etf(&mut session, &etf)?;
+        let setup = setup_trace(&mut session, &components, &etf, core);
+        release_on_error(&mut session, &etf, setup)?;
+        // The probe-rs readout reads the first TMC.
+        let readout = match find_component(&components, PeripheralType::Tmc) {
+            Ok(first) if *first == etf => Readout::ProbeRs,
+            _ => Readout::Drain,
+        };
+        Ok(Self {
+            session,
+            components,
+            core,
+            etf,
+            etr: None,
+            readout,
+            monitor: None,
+            backoff: Backoff::default(),
+            timeout: STOP_TIMEOUT,
+            source: ITM_ID,
+            sources: BTreeSet::new(),
+            deformatter: Deformatter::new(),
+            seam: false,
+            trigger_timeout: None,
+            peak_fill: 0,
+        })
+    }
+
+    /// Use the trace configuration that is already present on the target.
+    ///
+    /// # Args
+    /// * `session` - An attached probe session.
+    ///
+    /// # Note
+    /// Only the ETF capture is enabled, the ITM, DWT, funnel and formatter are left as they are.
+    /// If they are not configured, the capture will be empty. The ITM is expected to use the
+    /// trace ID [ITM_ID] unless selected with [Self::set_source], data of other trace IDs is
+    /// discarded. The ETF is claimed as with [Self::new].
+    pub fn existing(mut session: Session) -> Result<Self, EtfError> {
+        let components = session.get_arm_components(DpAddress::Default)?;
+        let Some(etf) = find_etf(&components, session.get_arm_interface()?)?.cloned() else {
+            return Err(EtfError::ComponentNotFound {
+                sinks: trace_sinks(&mut session),
+            });
+        };
+        claim_etf(&mut session, &etf)?;
+        let discarded = discard_residual(&mut session, &etf);
+        release_on_error(&mut session, &etf, discarded)?;
+        let mut capture = Self {
+            session,
+            components,
+            core: 0,
+            etf,
+            etr: None,
+            readout: Readout::Drain,
+            monitor: None,
+            backoff: Backoff::default(),
+            timeout: STOP_TIMEOUT,
+            source: ITM_ID,
+            sources: BTreeSet::new(),
+            deformatter: Deformatter::new(),
+            seam: false,
+            trigger_timeout: None,
+            peak_fill: 0,
+        };
+        let enabled = capture.etf().and_then(|mut etf| etf.enable_capture());
+        release_on_error(&mut capture.session, &capture.etf, enabled)?;
+        Ok(capture)
+    }
+
+    /// Capture into the ETF as a circular buffer.
+    ///
+    /// # Args
+    /// * `words` - Read only the most recent words instead of the whole buffer.
+    ///
+    /// # Note
+    /// A circular capture retains the most recent trace data and overwrites older data. On
+    /// [Self::read] the capture is stopped, the buffer is read and capture is restarted.
+    pub fn set_circular(&mut self, words: Option<u32>) -> Result<(), EtfError> {
+        let mut etf = self.etf()?;
+        etf.stop_capture()?;
+        etf.set_mode(Mode::Circular)?;
+        etf.enable_capture()?;
+        self.readout = Readout::Circular { words };
+        Ok(())
+    }
+
+    /// Capture into a buffer in system memory through an ETR, e.g. for captures larger than the
+    /// ETF.
+    ///
+    /// The ETF is set up as a hardware FIFO that drains to the ETR (see
+    /// [EmbeddedTraceFifo::enable_hardware_fifo]), the ETR as a circular buffer in `buffer`. On
+    /// [Self::read] the capture is stopped, the buffer read through the memory interface of the
+    /// traced core and the capture restarted with an empty buffer. The buffer has to be memory
+    /// the firmware does not use.
+    ///
+    /// # Returns
+    /// `false` if the target has no ETR, the capture then continues into the ETF unchanged.
+    pub fn set_etr(&mut self, buffer: EtrBuffer) -> Result<bool, EtfError> {
+        let Some(etr) = find_etr(&self.components, self.session.get_arm_interface()?)?.cloned()
+        else {
+            warn!("The target has no ETR, capturing into the ETF instead");
+            return Ok(false);
+        };
+        let mut router = EmbeddedTraceRouter::new(self.session.get_arm_interface()?, &etr);
+        router.set_timeout(self.timeout);
+        router.tmc().claim()?;
+        // Released with the ETF by [Self::release], also if the setup fails.
+        self.etr = Some(etr.clone());
+        router.configure(buffer)?;
+        router.tmc().enable_capture()?;
+        info!(
+            "Capturing through the ETR at {:#x} into {} bytes at {:#x}",
+            router.address(),
+            buffer.len,
+            buffer.address
+        );
+        self.etf()?.enable_hardware_fifo()?;
+        self.readout = Readout::Etr { buffer };
+        Ok(true)
+    }
+
+    /// Stop the ETR capture and read its buffer, see [Self::set_etr].
+    ///
+    /// # Args
+    /// * `restart` - Restart the capture with an empty buffer afterwards.
+    fn read_etr(&mut self, buffer: EtrBuffer, restart: bool) -> Result<Vec<u8>, EtfError> {
+        let etr = self.etr.clone().expect("The ETR readout has an ETR");
+        // The flush of the ETF pushes the data it still buffers on to the ETR.
+        self.etf()?.manual_flush()?;
+        let mut router = EmbeddedTraceRouter::new(self.session.get_arm_interface()?, &etr);
+        router.set_timeout(self.timeout);
+        router.flush_and_stop()?;
+        let (regions, wrapped) = router.regions(&buffer)?;
+        if wrapped {
+            warn!("The ETR buffer wrapped, the oldest trace data was overwritten");
+            // The data no longer continues the previous read.
+            self.deformatter = Deformatter::new();
+            self.seam = true;
+        }
+        let mut data = vec![];
+        let mut core = self.session.core(self.core)?;
+        for (address, len) in regions {
+            let start = data.len();
+            data.resize(start + len as usize, 0);
+            core.read(address, &mut data[start..])?;
+        }
+        drop(core);
+        if restart {
+            let mut router = EmbeddedTraceRouter::new(self.session.get_arm_interface()?, &etr);
+            router.set_timeout(self.timeout);
+            router.configure(buffer)?;
+            router.tmc().enable_capture()?;
+        }
+        Ok(data)
+    }
+
+    /// In circular mode, keep capturing this many 32 bit words after a trigger event, see
+    /// [EmbeddedTraceFifo::set_trigger_count].
+    pub fn set_post_trigger(&mut self, words: u32) -> Result<(), EtfError> {
+        let mut etf = self.etf()?;
+        etf.stop_capture()?;
+        etf.set_trigger_count(words)?;
+        etf.enable_capture()
+    }
+
+    /// In circular mode, wait for a trigger before every read, e.g. to capture the trace
+    /// leading up to an event.
+    ///
+    /// # Args
+    /// * `timeout` - How long to wait for the trigger. After that the buffer is read anyway,
+    ///   with a warning. `None` reads without waiting.
+    pub fn set_trigger_timeout(&mut self, timeout: Option<Duration>) {
+        self.trigger_timeout = timeout;
+    }
+
+    /// Wait until the ETF has seen a trigger event.
+    ///
+    /// # Returns
+    /// `false` if there was no trigger within `timeout`.
+    pub fn wait_for_trigger(&mut self, timeout: Duration) -> Result<bool, EtfError> {
+        let start = Instant::now();
+        let mut etf = self.etf()?;
+        while !etf.triggered()? {
+            if start.elapsed() >= timeout {
+                warn!("No trigger within {timeout:?}, reading the buffer anyway");
+                return Ok(false);
+            }
+            std::thread::sleep(TRIGGER_POLL);
+        }
+        debug!("Triggered after {:?}", start.elapsed());
+        Ok(true)
+    }
+
+    /// Route a trace funnel input port to the ETF.
+    ///
+    /// # Args
+    /// * `port` - The funnel input port the ITM is connected to.
+    ///
+    /// # Note
+    /// Only the given port is enabled, all other funnel inputs are disabled. If the target has
+    /// several funnels, the one enabled by the trace setup is used. The port is validated against
+    /// the port count of the funnel if it reports one.
+    pub fn set_funnel_port(&mut self, port: u8) -> Result<(), EtfError> {
+        let interface = self.session.get_arm_interface()?;
+        let funnel = etf_funnel(&self.components, interface)?;
+
+        // PORTCOUNT of zero means the port count is not reported.
+        let ports = match funnel.read_reg(interface, REGISTER_OFFSET_FUNNEL_DEVID)? & 0xF {
+            0 => u8::BITS as u8,
+            ports => ports as u8,
+        };
+        if port >= ports {
+            return Err(EtfError::FunnelPort { port, ports });
+        }
+
+        let mut funnel = TraceFunnel::new(interface, funnel);
+        funnel.unlock()?;
+        funnel.enable_port(1 << port)?;
+        Ok(())
+    }
+
+    /// The enabled input ports of the trace funnel feeding the ETF, see [Self::set_funnel_port].
+    pub fn funnel_ports(&mut self) -> Result<Vec<u8>, EtfError> {
+        let interface = self.session.get_arm_interface()?;
+        let funnel = etf_funnel(&self.components, interface)?;
+        let ctrl = funnel.read_reg(interface, REGISTER_OFFSET_FUNNEL_CTRL)?;
+        Ok((0..u8::BITS as u8)
+            .filter(|port| ctrl & (1 << port) != 0)
+            .collect())
+    }
+
+    /// The enabled ITM stimulus ports, from ITM_TER0 to ITM_TER7.
+    ///
+    /// # Note
+    /// The bits of ports the ITM does not implement read as zero.
+    pub fn itm_ports(&mut self) -> Result<Vec<u8>, EtfError> {
+        let ap = core_ap(&self.session, self.core);
+        let interface = self.session.get_arm_interface()?;
+        let itm = core_component(&self.components, PeripheralType::Itm, ap)?;
+        let mut ports = vec![];
+        for page in 0..8 {
+            let ter = itm.read_reg(interface, REGISTER_OFFSET_ITM_TER0 + 4 * page)?;
+            ports.extend(
+                (0..u32::BITS)
+                    .filter(|port| ter & (1 << port) != 0)
+                    .map(|port| (page * u32::BITS + port) as u8),
+            );
+        }
+        Ok(ports)
+    }
+
+    /// The clocking of the ITM local timestamp counter, from ITM_TCR.
+    pub fn timestamp_clock(&mut self) -> Result<TimestampClock, EtfError> {
+        let ap = core_ap(&self.session, self.core);
+        let interface = self.session.get_arm_interface()?;
+        let itm = core_component(&self.components, PeripheralType::Itm, ap)?;
+        let tcr = itm.read_reg(interface, REGISTER_OFFSET_ITM_TCR)?;
+        // Not all targets with an ETF have a TPIU.
+        let acpr = match find_component(&self.components, PeripheralType::Tpiu) {
+            Ok(tpiu) => Some(tpiu.read_reg(interface, REGISTER_OFFSET_TPIU_ACPR)? & 0x1FFF),
+            Err(_) => None,
+        };
+        Ok(TimestampClock {
+            prescaler: 1 << (2 * ((tcr >> 8) & 0b11)),
+            asynchronous: tcr & (1 << 4) != 0,
+            acpr,
+        })
+    }
+
+    /// The frequency of the ITM timestamp counter to decode with, see [Self::timestamp_clock].
+    ///
+    /// # Args
+    /// * `coreclk` - Frequency of the timestamp reference clock in Hz, normally the core clock.
+    ///
+    /// # Note
+    /// A timestamp prescaler is applied and logged. An asynchronous timestamp clock only causes
+    /// a warning: its frequency is not known here. If the configuration can not be read,
+    /// `coreclk` is returned unchanged.
+    pub fn timestamp_frequency(&mut self, coreclk: u32) -> u32 {
+        let clock = match self.timestamp_clock() {
+            Ok(clock) => clock,
+            Err(e) => {
+                warn!("Can not read the ITM timestamp clock configuration: {e}");
+                return coreclk;
+            }
+        };
+        if clock.asynchronous {
+            let acpr = clock
+                .acpr
+                .map_or_else(String::new, |acpr| format!(", TPIU ACPR {acpr}"));
+            warn!(
+                "The ITM timestamps count the asynchronous TPIU clock (ITM_TCR.SWOENA{acpr}), \
+                the timestamp clock frequency must be given in place of the core clock"
+            );
+        }
+        if clock.prescaler != 1 {
+            info!(
+                "ITM timestamp prescaler {}, timestamp clock {} Hz",
+                clock.prescaler,
+                clock.frequency(coreclk)
+            );
+        }
+        clock.frequency(coreclk)
+    }
+
+    /// The trace ID of the selected trace source, see [Self::set_source].
+    pub fn source(&self) -> u8 {
+        self.source
+    }
+
+    /// Select the trace source to capture by its trace ID, e.g. the ITM of another core.
+    /// Defaults to [ITM_ID].
+    ///
+    /// # Note
+    /// The probe-rs readout only extracts the data of [ITM_ID]. For other sources the ETF is
+    /// drained by this crate instead, which behaves the same otherwise.
+    pub fn set_source(&mut self, id: u8) {
+        if id != ITM_ID {
+            self.keep_frames();
+        }
+        self.source = id;
+    }
+
+    /// Drain the ETF with this crate instead of probe-rs, so that [Self::read_raw] returns the
+    /// formatter frames. The probe-rs readout removes them.
+    pub fn keep_frames(&mut self) {
+        if self.readout == Readout::ProbeRs {
+            self.readout = Readout::Drain;
+        }
+    }
+
+    /// Configure the waiting between empty reads while draining the ETF.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// Configure the maximum time to wait for the ETF capture to stop, see
+    /// [EmbeddedTraceFifo::set_timeout].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The maximum time to wait for the ETF capture to stop.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Observe the ETF fill level.
+    ///
+    /// # Args
+    /// * `monitor` - Called with the fill level in percent before every [Self::read].
+    pub fn set_monitor(&mut self, monitor: impl FnMut(u8) + 'static) {
+        self.monitor = Some(Box::new(monitor));
+    }
+
+    /// Index of the traced core, see [Self::with_core].
+    pub fn core(&self) -> usize {
+        self.core
+    }
+
+    /// The underlying probe session.
+    pub fn session(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    /// Construct a driver for the ETF of the target.
+    ///
+    /// # Note
+    /// The driver borrows the session, so it is constructed again for every capture cycle.
+    pub fn etf(&mut self) -> Result<EmbeddedTraceFifo<'_>, EtfError> {
+        let mut etf = EmbeddedTraceFifo::new(self.session.get_arm_interface()?, &self.etf);
+        etf.set_backoff(self.backoff);
+        etf.set_timeout(self.timeout);
+        Ok(etf)
+    }
+
+    /// Measure the rate at which trace data enters the ETF without reading it.
+    ///
+    /// # Args
+    /// * `interval` - The duration of the measurement.
+    ///
+    /// # Note
+    /// The ETF is emptied first, then its fill level is sampled until `interval` has passed or
+    /// the ETF is full. The data left in the ETF is part of the next [Self::read].
+    pub fn fill_rate(&mut self, interval: Duration) -> Result<FillRate, EtfError> {
+        self.read()?;
+        let start = Instant::now();
+        let mut etf = self.etf()?;
+        loop {
+            let bytes = etf.fill_level()?;
+            let elapsed = start.elapsed();
+            let full = etf.full()?;
+            if full || elapsed >= interval {
+                return Ok(FillRate {
+                    bytes,
+                    elapsed,
+                    full,
+                });
+            }
+            std::thread::sleep(FILL_RATE_POLL);
+        }
+    }
+
+    /// Drain the trace data captured since the previous read.
+    ///
+    /// # Returns
+    /// The ITM trace data, with the formatter frames already removed.
+    pub fn read(&mut self) -> Result<Vec<u8>, EtfError> {
+        let raw = self.read_raw()?;
+        self.itm(raw)
+    }
+
+    /// Extract the trace data of the selected source from a [Self::read_raw] result.
+    ///
+    /// Trace IDs that appear for the first time are logged. Fails with
+    /// [EtfError::SourceNotFound] if the data contains other sources, but the selected source has
+    /// not been seen yet.
+    pub fn itm(&mut self, raw: RawTrace) -> Result<Vec<u8>, EtfError> {
+        if !raw.formatted {
+            return Ok(raw.data);
+        }
+        // The reads form one stream: a read can start with data of the last source of the
+        // previous read.
+        let mut found = self.deformatter.push(&raw.data);
+        for (&id, data) in found.iter() {
+            if self.sources.insert(id) {
+                let selected = if id == self.source { ", selected" } else { "" };
+                info!(
+                    "Found trace source ID {id:#04x} ({} bytes{selected})",
+                    data.len()
+                );
+            }
+        }
+        if !found.is_empty() && !self.sources.contains(&self.source) {
+            return Err(EtfError::SourceNotFound {
+                id: self.source,
+                sources: found.into_keys().collect(),
+            });
+        }
+        let mut data = found.remove(&self.source).unwrap_or_default();
+        if std::mem::take(&mut self.seam) {
+            // The data at the seam may be the end of a packet that would be decoded as garbage.
+            match find_sync(&data) {
+                Some(start) => {
+                    debug!("Discarding {start} bytes before the first synchronization packet");
+                    data.drain(..start);
+                }
+                None => warn!(
+                    "The circular buffer contains no synchronization packet, \
+                    the first packets may be malformed"
+                ),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Drain the trace data captured since the previous read, as read from the ETF.
+    ///
+    /// # Note
+    /// If probe-rs does not support reading the trace data of the target, this and all later
+    /// reads drain the ETF with this crate instead, as with [Self::keep_frames].
+    pub fn read_raw(&mut self) -> Result<RawTrace, EtfError> {
+        if self.monitor.is_some() {
+            let percent = self.etf()?.fill_percent()?;
+            if let Some(monitor) = self.monitor.as_mut() {
+                monitor(percent);
+            }
+        }
+        let frames = match self.readout {
+            Readout::ProbeRs => {
+                // The data probe-rs returns is deformatted and does not show the fill level.
+                let level = self.etf()?.fill_level()?;
+                self.peak_fill = self.peak_fill.max(level as usize);
+                match self.session.read_trace_data() {
+                    Ok(data) => {
+                        return Ok(RawTrace {
+                            data,
+                            formatted: false,
+                        })
+                    }
+                    Err(e) if is_unsupported(&e) => {
+                        info!(
+                            "probe-rs can not read the trace data ({e}), draining the ETF instead"
+                        );
+                        self.readout = Readout::Drain;
+                        self.etf()?.drain()?
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Readout::Drain => {
+                let frames = self.etf()?.drain()?;
+                self.peak_fill = self.peak_fill.max(frames.len());
+                frames
+            }
+            Readout::Circular { words } => {
+                if let Some(timeout) = self.trigger_timeout {
+                    self.wait_for_trigger(timeout)?;
+                }
+                // The window of a circular read does not continue the previous read.
+                self.deformatter = Deformatter::new();
+                let mut etf = self.etf()?;
+                etf.flush_and_stop()?;
+                let (frames, seam) = etf.read_circular(words)?;
+                etf.enable_capture()?;
+                self.seam = seam;
+                frames
+            }
+            Readout::Etr { buffer } => self.read_etr(buffer, true)?,
+        };
+        Ok(RawTrace {
+            data: frames,
+            formatted: true,
+        })
+    }
+
+    /// The most trace data in bytes that accumulated in the ETF between two reads, e.g. to tell
+    /// how close a capture came to filling it. Zero in circular mode.
+    pub fn peak_fill(&self) -> usize {
+        self.peak_fill
+    }
+
+    /// Stop the capture and read the rest of the trace data, as read from the ETF.
+    ///
+    /// The formatter is flushed and the capture stopped once the pipelines are drained (see
+    /// [EmbeddedTraceFifo::flush_and_stop]), so that the data includes the last packets the
+    /// sources emitted. The capture stays disabled afterwards.
+    pub fn stop(&mut self) -> Result<RawTrace, EtfError> {
+        if let Readout::Etr { buffer } = self.readout {
+            let data = self.read_etr(buffer, false)?;
+            return Ok(RawTrace {
+                data,
+                formatted: true,
+            });
+        }
+        let circular = match self.readout {
+            Readout::Circular { words } => Some(words),
+            _ => None,
+        };
+        if circular.is_some() {
+            // As for any circular read, the window does not continue the previous read.
+            self.deformatter = Deformatter::new();
+        }
+        let mut etf = self.etf()?;
+        etf.flush_and_stop()?;
+        let data = match circular {
+            Some(words) => {
+                let (data, seam) = etf.read_circular(words)?;
+                self.seam = seam;
+                data
+            }
+            None => etf.drain()?,
+        };
+        Ok(RawTrace {
+            data,
+            formatted: true,
+        })
+    }
+
+    /// Read back the configuration of the capture, e.g. after its setup.
+    ///
+    /// # Args
+    /// * `target` - The target name to record.
+    /// * `coreclk` - The timestamp clock frequency in Hz to record.
+    pub fn config(&mut self, target: &str, coreclk: u32) -> Result<CaptureConfig, EtfError> {
+        // Targets without a funnel or with an ambiguous funnel setup can still be captured from.
+        let funnel_ports = self.funnel_ports().ok();
+        let trace_id = self.source;
+        let core = self.core;
+        let mut etf = self.etf()?;
+        Ok(CaptureConfig {
+            target: target.into(),
+            core,
+            mode: etf.mode()?,
+            coreclk,
+            funnel_ports,
+            etf_address: etf.address(),
+            fifo_size: etf.fifo_size()?,
+            formatter: etf.formatter_enabled()?,
+            trace_id,
+        })
+    }
+
+    /// Release the claim of the ETF taken when the capture was set up, see
+    /// [EmbeddedTraceFifo::claim]. This ends the capture for other users of a shared ETF.
+    pub fn release(&mut self) -> Result<(), EtfError> {
+        if let Some(etr) = self.etr.clone() {
+            EmbeddedTraceRouter::new(self.session.get_arm_interface()?, &etr)
+                .tmc()
+                .release()?;
+        }
+        self.etf()?.release()
+    }
+
+    /// Release the probe session.
+    pub fn into_session(self) -> Session {
+        self.session
+    }
+}
+
+/// Select a probe.
+///
+/// The selector is tried in this order:
+/// * The serial number of a probe.
+/// * `VID:PID` or `VID:PID:Serial` with the USB vendor and product IDs in hex, as listed by
+///   [probe_selector].
+/// * The zero-based index of a probe in `probes`, as a decimal number without leading zeros.
+/// * A prefix of the serial number of exactly one probe.
+///
+/// # Args
+/// * `probes` - The connected probes.
+/// * `selector` - The probe selector. Without it, the first probe is selected.
+pub fn select_probe<'p>(
+    probes: &'p [DebugProbeInfo],
+    selector: Option<&str>,
+) -> Result<&'p DebugProbeInfo, EtfError> {
+    let serial_number = |probe: &DebugProbeInfo| probe.serial_number.clone().unwrap_or_default();
+    let Some(selector) = selector else {
+        let probe = probes
+            .first()
+            .ok_or(Error::UnableToOpenProbe("not found"))?;
+        info!(
+            "Using probe 0 of {}: {} ({}), select one with `--probe`",
+            probes.len(),
+            probe.identifier,
+            probe_selector(probe)
+        );
+        return Ok(probe);
+    };
+    if let Some(probe) = probes
+        .iter()
+        .find(|probe| probe.serial_number.as_deref() == Some(selector))
+    {
+        return Ok(probe);
+    }
+    let matches: Vec<_> = match DebugProbeSelector::try_from(selector) {
+        Ok(usb) => probes
+            .iter()
+            .filter(|probe| probe.vendor_id == usb.vendor_id && probe.product_id == usb.product_id)
+            .filter(|probe| {
+                usb.serial_number
+                    .as_ref()
+                    .is_none_or(|serial| probe.serial_number.as_ref() == Some(serial))
+            })
+            .collect(),
+        Err(_) => match selector.parse::<usize>() {
+            Ok(index) if index.to_string() == selector && index < probes.len() => {
+                return Ok(&probes[index]);
+            }
+            _ => probes
+                .iter()
+                .filter(|probe| serial_number(probe).starts_with(selector))
+                .collect(),
+        },
+    };
+    match matches[..] {
+        [probe] => Ok(probe),
+        [] => Err(EtfError::ProbeNotFound {
+            selector: selector.into(),
+            probes: probes
+                .iter()
+                .enumerate()
+                .map(|(index, probe)| {
+                    format!("{index}: {} ({})", probe.identifier, probe_selector(probe))
+                })
+                .collect(),
+        }),
+        _ => Err(EtfError::AmbiguousProbe {
+            selector: selector.into(),
+            matches: matches.into_iter().map(serial_number).collect(),
+        }),
+    }
+}
+
+/// The `VID:PID:Serial` selector of a probe, see [select_probe]. Without a serial number it is
+/// just `VID:PID`.
+pub fn probe_selector(probe: &DebugProbeInfo) -> String {
+    let usb = format!("{:04x}:{:04x}", probe.vendor_id, probe.product_id);
+    match &probe.serial_number {
+        Some(serial) => format!("{usb}:{serial}"),
+        None => usb,
+    }
+}
+
+/// The probes as a JSON array with one object per probe and line, e.g. for tools that select a
+/// probe by its serial number. The indices are those of the `probes` command.
+pub fn probes_json(probes: &[DebugProbeInfo]) -> String {
+    let probes: Vec<_> = probes
+        .iter()
+        .enumerate()
+        .map(|(index, probe)| {
+            let serial = probe
+                .serial_number
+                .as_deref()
+                .map_or_else(|| "null".into(), json_string);
+            format!(
+                "  {{\"index\": {index}, \"identifier\": {}, \"vid\": {}, \"pid\": {}, \
+                \"serial\": {serial}}}",
+                json_string(&probe.identifier),
+                probe.vendor_id,
+                probe.product_id,
+            )
+        })
+        .collect();
+    match probes.is_empty() {
+        true => "[]".into(),
+        false => format!("[\n{}\n]", probes.join(",\n")),
+    }
+}
+
+/// Interval at which the probes are listed again while waiting for a probe, see [wait_for_probe].
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Wait for a probe to be connected.
+///
+/// # Args
+/// * `selector` - The probe selector, see [select_probe].
+/// * `timeout` - How long to wait for a matching probe. Zero only checks once.
+///
+/// # Returns
+/// The matching probe. Probes that match ambiguously fail immediately.
+pub fn wait_for_probe(
+    selector: Option<&str>,
+    timeout: Duration,
+) -> Result<DebugProbeInfo, EtfError> {
+    let start = Instant::now();
+    loop {
+        let probes = Probe::list_all();
+        match select_probe(&probes, selector) {
+            Ok(probe) => return Ok(probe.clone()),
+            Err(EtfError::ProbeNotFound { .. } | EtfError::Probe(Error::UnableToOpenProbe(_)))
+                if start.elapsed() < timeout =>
+            {
+                debug!("No matching probe found, retrying");
+                std::thread::sleep(PROBE_POLL_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Open a probe and attach to the target.
+///
+/// # Args
+/// * `probe` - The probe selector, see [select_probe].
+/// * `wait` - How long to wait for the probe to be connected, see [wait_for_probe].
+/// * `target` - The target name, as known to probe-rs.
+/// * `protocol` - The wire protocol, the probe's default if not given.
+/// * `speed` - The probe speed in kHz. The probe may not support it exactly.
+/// * `under_reset` - Hold the target in reset while attaching, to get control of a target that
+///   e.g. disables the debug port or spins with interrupts disabled.
+pub fn attach(
+    probe: Option<&str>,
+    wait: Duration,
+    target: &str,
+    protocol: Option<WireProtocol>,
+    speed: Option<u32>,
+    under_reset: bool,
+) -> Result<Session, EtfError> {
+    let mut probe = wait_for_probe(probe, wait)?.open().map_err(Error::from)?;
+
+    if let Some(protocol) = protocol {
+        probe.select_protocol(protocol).map_err(Error::from)?;
+    }
+    if let Some(speed) = speed {
+        let actual = probe.set_speed(speed).map_err(Error::from)?;
+        if actual != speed {
+            warn!("Probe does not support {speed} kHz, using {actual} kHz");
+        }
+    }
+
+    Ok(match under_reset {
+        true => probe.attach_under_reset(target, Permissions::default())?,
+        false => probe.attach(target, Permissions::default())?,
+    })
+}
+
+/// Options of a capture, see [capture_to_file].
+///
+/// Construct with [CaptureOptions::new] and change the fields as needed.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureOptions {
+    /// The target name, as known to probe-rs.
+    pub target: String,
+    /// The probe selector, see [select_probe].
+    pub probe: Option<String>,
+    /// How long to wait for the probe to be connected, see [wait_for_probe].
+    pub wait_for_probe: Duration,
+    /// The wire protocol of the probe, the probe's default if not given.
+    pub protocol: Option<WireProtocol>,
+    /// The probe speed in kHz.
+    pub speed: Option<u32>,
+    /// Attach with the target held in reset, see [attach].
+    pub under_reset: bool,
+    /// Index of the core to trace, see [CaptureSession::with_core].
+    pub core: usize,
+    /// The file the ITM trace data is written to. The [CaptureReport] is written next to it, see
+    /// [CaptureReport::sidecar_path]. Without it, the trace data is discarded.
+    pub output: Option<PathBuf>,
+    /// Frequency of the ITM timestamp reference clock in Hz, normally the core clock. The
+    /// timestamp clock recorded in the report for decoding is derived from it, see
+    /// [CaptureSession::timestamp_frequency].
+    pub coreclk: u32,
+    /// The label to record in the report.
+    pub label: Option<String>,
+    /// Use the trace configuration on the target, see [CaptureSession::existing].
+    pub no_setup: bool,
+    /// Capture into the ETF as a circular buffer, see [CaptureSession::set_circular].
+    pub circular: bool,
+    /// In circular mode, only read the most recent number of words.
+    pub words: Option<u32>,
+    /// In circular mode, wait this long for a trigger, see [CaptureSession::set_trigger_timeout].
+    pub trigger_timeout: Option<Duration>,
+    /// In circular mode, the words to capture after a trigger, see
+    /// [CaptureSession::set_post_trigger].
+    pub post_trigger: Option<u32>,
+    /// Capture through an ETR into this buffer in system memory, see [CaptureSession::set_etr].
+    pub etr_buffer: Option<EtrBuffer>,
+    /// Route this trace funnel input port to the ETF, see [CaptureSession::set_funnel_port].
+    pub funnel_port: Option<u8>,
+    /// Trace ID of the trace source to capture, see [CaptureSession::set_source].
+    pub source: Option<u8>,
+    /// The drain backoff, see [CaptureSession::set_backoff].
+    pub backoff: Backoff,
+    /// The timeout of the target, see [CaptureSession::set_timeout].
+    pub timeout: Duration,
+    /// Restore the trace configuration found before the setup after the capture.
+    pub restore_on_exit: bool,
+}
+
+impl CaptureOptions {
+    /// Options for a capture from `target` with the defaults of a [CaptureSession].
+    ///
+    /// # Args
+    /// * `target` - The target name, as known to probe-rs.
+    /// * `coreclk` - Frequency of the ITM timestamp clock in Hz.
+    pub fn new(target: impl Into<String>, coreclk: u32) -> Self {
+        Self {
+            target: target.into(),
+            probe: None,
+            wait_for_probe: Duration::ZERO,
+            protocol: None,
+            speed: None,
+            under_reset: false,
+            core: 0,
+            output: None,
+            coreclk,
+            label: None,
+            no_setup: false,
+            circular: false,
+            words: None,
+            trigger_timeout: None,
+            post_trigger: None,
+            etr_buffer: None,
+            funnel_port: None,
+            source: None,
+            backoff: Backoff::default(),
+            timeout: STOP_TIMEOUT,
+            restore_on_exit: false,
+        }
+    }
+
+    /// Set up a capture on an attached session.
+    pub fn setup(&self, session: Session) -> Result<CaptureSession, EtfError> {
+        let mut capture = if self.no_setup {
+            CaptureSession::existing(session)?
+        } else {
+            CaptureSession::with_core(session, self.core)?
+        };
+        // The claims are released if the configuration fails, see [release_on_error].
+        if let Err(e) = self.configure(&mut capture) {
+            if let Err(released) = capture.release() {
+                warn!("Failed to release the claim of the ETF: {released}");
+            }
+            return Err(e);
+        }
+        Ok(capture)
+    }
+
+    /// Apply the options to a capture that was set up.
+    fn configure(&self, capture: &mut CaptureSession) -> Result<(), EtfError> {
+        capture.set_backoff(self.backoff);
+        capture.set_timeout(self.timeout);
+        if let Some(port) = self.funnel_port {
+            capture.set_funnel_port(port)?;
+        }
+        if let Some(id) = self.source {
+            capture.set_source(id);
+        }
+        if let Some(buffer) = self.etr_buffer {
+            capture.set_etr(buffer)?;
+        }
+        if self.circular {
+            capture.set_circular(self.words)?;
+            capture.set_trigger_timeout(self.trigger_timeout);
+            if let Some(words) = self.post_trigger {
+                capture.set_post_trigger(words)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Attach, capture once and write the ITM trace data and its report.
+///
+/// This is the capture of the `capture` command without decoding. The report is also written
+/// if reading the trace failed, it describes the data written before.
+///
+/// # Returns
+/// The report of the capture.
+pub fn capture_to_file(options: CaptureOptions) -> Result<CaptureReport, EtfError> {
+    // Fail on a bad output path before attaching to the target.
+    let mut file = options
+        .output
+        .as_ref()
+        .map(std::fs::File::create)
+        .transpose()?;
+    let mut session = attach(
+        options.probe.as_deref(),
+        options.wait_for_probe,
+        &options.target,
+        options.protocol,
+        options.speed,
+        options.under_reset,
+    )?;
+    let before = TraceState::read(&mut session, options.core)?;
+    let mut capture = options.setup(session)?;
+    let coreclk = capture.timestamp_frequency(options.coreclk);
+    let config = capture.config(&options.target, coreclk)?;
+    // The CRC-32 of no data is zero.
+    let (mut bytes, mut crc32) = (0, 0);
+    let result = capture.read().and_then(|data| {
+        bytes = data.len() as u64;
+        crc32 = report::crc32(data.as_slice())?;
+        if let Some(file) = file.as_mut() {
+            file.write_all(&data)?;
+        }
+        Ok(())
+    });
+    let report = CaptureReport {
+        label: options.label,
+        config,
+        bytes,
+        crc32,
+    };
+    if let Some(path) = &options.output {
+        std::fs::write(CaptureReport::sidecar_path(path), report.to_json())?;
+    }
+    let restored = match options.restore_on_exit {
+        true => before.restore(&mut capture),
+        false => Ok(()),
+    };
+    let released = capture.release();
+    result?;
+    restored?;
+    released?;
+    Ok(report)
+}
+
+/// The clocking of the ITM local timestamp counter.
+///
+/// The counter runs on a reference clock divided by the prescaler. The reference clock is the
+/// core clock unless the counter is clocked asynchronously by the TPIU, whose SWO output clock is
+/// its input clock divided by ACPR + 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimestampClock {
+    /// The local timestamp prescaler of ITM_TCR.TSPrescale: 1, 4, 16 or 64.
+    pub prescaler: u32,
+    /// The counter is clocked by the TPIU (ITM_TCR.SWOENA) instead of the core clock.
+    pub asynchronous: bool,
+    /// The SWO prescaler of the TPIU ACPR register, `None` if there is no TPIU.
+    pub acpr: Option<u32>,
+}
+
+impl TimestampClock {
+    /// The frequency of the timestamp counter given the frequency of its reference clock in Hz.
+    pub fn frequency(&self, reference: u32) -> u32 {
+        reference / self.prescaler
+    }
+}
+
+/// The trace configuration registers changed by the setup of a capture: the component, the
+/// register offset and the register name. The TMC registers are those of the ETF, see
+/// [find_etf], the others those of the traced core and, for the ITM, also of the first ITM, which
+/// the setup disables when tracing another core.
+const TRACE_REGISTERS: [(PeripheralType, u32, &str); 7] = [
+    (PeripheralType::Scs, 0xDFC, "DEMCR"),
+    (PeripheralType::Dwt, 0x000, "DWT_CTRL"),
+    (PeripheralType::Itm, REGISTER_OFFSET_ITM_TCR, "ITM_TCR"),
+    (PeripheralType::Itm, REGISTER_OFFSET_ITM_TER0, "ITM_TER0"),
+    (PeripheralType::Tmc, 0x028, "ETF_MODE"),
+    (PeripheralType::Tmc, 0x304, "ETF_FFCR"),
+    // Written last, it enables the ETF capture with the restored configuration.
+    (PeripheralType::Tmc, 0x020, "ETF_CTL"),
+];
+
+/// The trace configuration of a target, to restore it after a capture.
+///
+/// This covers the trace enable bit in DEMCR and the DWT, ITM and ETF configuration. Registers
+/// of components the target does not have are skipped. The funnels and replicators are not
+/// restored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceState {
+    /// The values by index into [TRACE_REGISTERS], with the component they were read from.
+    values: Vec<(usize, CoresightComponent, u32)>,
+}
+
+impl TraceState {
+    /// Read the trace configuration of the target, e.g. before the [CaptureSession] sets it up.
+    ///
+    /// # Args
+    /// * `core` - Index of the traced core, see [CaptureSession::with_core].
+    pub fn read(session: &mut Session, core: usize) -> Result<Self, EtfError> {
+        let components = session.get_arm_components(DpAddress::Default)?;
+        let ap = core_ap(session, core);
+        let interface = session.get_arm_interface()?;
+        let etf = find_etf(&components, interface)?;
+        let mut values = vec![];
+        for (index, &(kind, offset, _)) in TRACE_REGISTERS.iter().enumerate() {
+            let mut registers = vec![];
+            match kind {
+                PeripheralType::Tmc => registers.extend(etf),
+                PeripheralType::Itm => {
+                    registers.extend(core_component(&components, kind, ap).ok());
+                    registers.extend(find_component(&components, kind).ok());
+                    registers.dedup();
+                }
+                _ => registers.extend(core_component(&components, kind, ap).ok()),
+            }
+            for component in registers {
+                let value = component.read_reg(interface, offset)?;
+                values.push((index, component.clone(), value));
+            }
+        }
+        Ok(Self { values })
+    }
+
+    /// The registers that have a different value in `other`.
+    ///
+    /// # Returns
+    /// The register names with the values here and in `other`.
+    pub fn changes(&self, other: &Self) -> Vec<(&'static str, u32, u32)> {
+        self.values
+            .iter()
+            .filter_map(|(index, component, value)| {
+                let (_, _, new) = other
+                    .values
+                    .iter()
+                    .find(|(i, c, _)| i == index && c == component)?;
+                (new != value).then_some((TRACE_REGISTERS[*index].2, *value, *new))
+            })
+            .collect()
+    }
+
+    /// Write the trace configuration back to the target of a capture.
+    ///
+    /// # Note
+    /// The ETF capture is stopped first, as the ETF configuration can only be written while the
+    /// capture is disabled.
+    pub fn restore(&self, capture: &mut CaptureSession) -> Result<(), EtfError> {
+        capture.etf()?.stop_capture()?;
+        let interface = capture.session.get_arm_interface()?;
+        for (index, component, value) in &self.values {
+            let (_, offset, _) = TRACE_REGISTERS[*index];
+            component.write_reg(interface, offset, *value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A programmable trace replicator, which fans the trace out to two trace sinks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Replicator {
+    /// Base address of the replicator.
+    pub address: u64,
+    /// The ID filters of the two outputs, IDFILTER0 and IDFILTER1. Bit `n` set discards the trace
+    /// IDs `16 * n` to `16 * n + 15` at that output.
+    pub filters: [u32; 2],
+}
+
+impl Replicator {
+    /// Check if an output passes a trace ID.
+    pub fn passes(&self, output: usize, id: u8) -> bool {
+        self.filters[output] & (1 << (id / 16)) == 0
+    }
+}
+
+impl std::fmt::Display for Replicator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Replicator at {:#010x}: IDFILTER0 {:#04x}, IDFILTER1 {:#04x}",
+            self.address, self.filters[0], self.filters[1]
+        )
+    }
+}
+
+/// The programmable trace replicators in the ROM table. Non-programmable replicators do not
+/// appear in it and always pass all trace data.
+fn replicator_components(components: &[CoresightComponent]) -> Vec<&CoresightComponent> {
+    components
+        .iter()
+        .flat_map(|component| component.iter())
+        .filter(|component| {
+            component.component.id().peripheral_id().dev_type() == REPLICATOR_DEVTYPE
+        })
+        .collect()
+}
+
+/// Read the programmable trace replicators of the target.
+pub fn replicators(session: &mut Session) -> Result<Vec<Replicator>, EtfError> {
+    let components = session.get_arm_components(DpAddress::Default)?;
+    let interface = session.get_arm_interface()?;
+    let mut replicators = vec![];
+    for component in replicator_components(&components) {
+        let mut filters = [0; 2];
+        for (filter, offset) in filters.iter_mut().zip(REGISTER_OFFSET_REPLICATOR_IDFILTER) {
+            *filter = component.read_reg(interface, offset)?;
+        }
+        replicators.push(Replicator {
+            address: component.component.id().component_address(),
+            filters,
+        });
+    }
+    Ok(replicators)
+}
+
+/// A trace funnel, which merges the trace of several sources.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Funnel {
+    /// Base address of the funnel.
+    pub address: u64,
+    /// The enabled input ports.
+    pub ports: Vec<u8>,
+    /// The funnel is taken to feed the ETF, see [CaptureSession::funnel_ports].
+    pub selected: bool,
+}
+
+impl std::fmt::Display for Funnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Funnel at {:#010x}: enabled inputs {:?}",
+            self.address, self.ports
+        )?;
+        if self.selected {
+            write!(f, ", feeds the ETF")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the trace funnels of the target.
+pub fn funnels(session: &mut Session) -> Result<Vec<Funnel>, EtfError> {
+    let components = session.get_arm_components(DpAddress::Default)?;
+    let interface = session.get_arm_interface()?;
+    // There is no funnel to select if the funnel setup is ambiguous.
+    let selected = etf_funnel(&components, interface)
+        .ok()
+        .map(|funnel| funnel.component.id().component_address());
+    let mut funnels = vec![];
+    for component in components
+        .iter()
+        .flat_map(|component| component.iter())
+        .filter(|component| {
+            component
+                .component
+                .id()
+                .peripheral_id()
+                .is_of_type(PeripheralType::TraceFunnel)
+        })
+    {
+        let address = component.component.id().component_address();
+        let ctrl = component.read_reg(interface, REGISTER_OFFSET_FUNNEL_CTRL)?;
+        funnels.push(Funnel {
+            address,
+            ports: (0..u8::BITS as u8)
+                .filter(|port| ctrl & (1 << port) != 0)
+                .collect(),
+            selected: selected == Some(address),
+        });
+    }
+    Ok(funnels)
+}
+
+/// The state of a trace memory controller (TMC): an ETB, ETF or ETR.
+#[derive(Clone, Debug)]
+pub struct Tmc {
+    /// Base address of the TMC.
+    pub address: u64,
+    /// The part number of the peripheral ID.
+    pub part: u16,
+    /// The configuration, `None` for a reserved configuration type.
+    pub configuration: Option<Configuration>,
+    /// Size of the trace memory in bytes, of the buffer in system memory for an ETR. `None` if
+    /// the size reads as zero, e.g. for an ETR without a buffer.
+    pub size: Option<u32>,
+    /// The current mode, `None` if it is not a valid mode.
+    pub mode: Option<Mode>,
+    /// The capture is enabled.
+    pub capture_enabled: bool,
+    /// The formatter is enabled.
+    pub formatter: bool,
+    /// The status register.
+    pub status: Status,
+    /// The TMC is captured into, see [find_etf].
+    pub selected: bool,
+}
+
+impl std::fmt::Display for Tmc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let configuration = self
+            .configuration
+            .map_or_else(|| "TMC".into(), |c| format!("{c:?}").to_uppercase());
+        let flags: Vec<_> = [
+            (self.status.full(), "full"),
+            (self.status.trigd(), "triggered"),
+            (self.status.ready(), "ready"),
+            (self.status.ftempty(), "formatter empty"),
+            (self.status.empty(), "empty"),
+        ]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect();
+        write!(
+            f,
+            "{configuration} at {:#010x} (part {:#05x}): {}, mode {:?}, capture {}, \
+             formatter {}, status {:#x} [{}]",
+            self.address,
+            self.part,
+            self.size
+                .map_or_else(|| "no size".into(), |size| format!("{size} bytes")),
+            self.mode,
+            if self.capture_enabled {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            if self.formatter {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            self.status.0,
+            flags.join(", ")
+        )?;
+        if self.selected {
+            write!(f, ", captured into")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the state of the TMCs of the target, without changing their configuration.
+pub fn tmcs(session: &mut Session) -> Result<Vec<Tmc>, EtfError> {
+    let components = session.get_arm_components(DpAddress::Default)?;
+    let interface = session.get_arm_interface()?;
+    let selected =
+        find_etf(&components, interface)?.map(|etf| etf.component.id().component_address());
+    let mut tmcs = vec![];
+    for component in components
+        .iter()
+        .flat_map(|component| component.iter())
+        .filter(|component| {
+            component
+                .component
+                .id()
+                .peripheral_id()
+                .is_of_type(PeripheralType::Tmc)
+        })
+    {
+        let id = component.component.id();
+        let mut tmc = EmbeddedTraceFifo::new(interface, component);
+        tmcs.push(Tmc {
+            address: id.component_address(),
+            part: id.peripheral_id().part(),
+            configuration: tmc.configuration()?,
+            size: match tmc.fifo_size() {
+                Err(EtfError::UnsupportedRegister { .. }) => None,
+                size => Some(size?),
+            },
+            mode: tmc.mode()?,
+            capture_enabled: tmc.capture_enabled()?,
+            formatter: tmc.formatter_enabled()?,
+            status: tmc.status()?,
+            selected: selected == Some(id.component_address()),
+        });
+    }
+    Ok(tmcs)
+}
+
+/// Clear the ID filters of all programmable replicators for trace ID `id` on both outputs.
+fn pass_replicators(
+    session: &mut Session,
+    components: &[CoresightComponent],
+    id: u8,
+) -> Result<(), EtfError> {
+    let interface = session.get_arm_interface()?;
+    for component in replicator_components(components) {
+        for (index, offset) in REGISTER_OFFSET_REPLICATOR_IDFILTER.into_iter().enumerate() {
+            let filter = component.read_reg(interface, offset)?;
+            let group = 1 << (id / 16);
+            if filter & group == 0 {
+                continue;
+            }
+            component.write_reg(interface, offset, filter & !group)?;
+            let value = component.read_reg(interface, offset)?;
+            if value & group != 0 {
+                return Err(EtfError::Replicator {
+                    address: component.component.id().component_address(),
+                    register: ["IDFILTER0", "IDFILTER1"][index],
+                    value,
+                    id,
+                });
+            }
+            info!(
+                "Cleared the trace ID filter of replicator output {index}: {filter:#04x} -> \
+                 {value:#04x}"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The access port of core `core`, `None` if the target description does not give one.
+fn core_ap(session: &Session, core: usize) -> Option<u8> {
+    match session.target().cores.get(core)?.core_access_options {
+        CoreAccessOptions::Arm(ref options) => Some(options.ap),
+        _ => None,
+    }
+}
+
+/// Find the first component of a type on access port `ap`.
+///
+/// Falls back to the first component of the type on any access port, e.g. if the cores share
+/// their trace components.
+fn core_component(
+    components: &[CoresightComponent],
+    peripheral: PeripheralType,
+    ap: Option<u8>,
+) -> Result<&CoresightComponent, EtfError> {
+    let first = find_component(components, peripheral)?;
+    Ok(components
+        .iter()
+        .flat_map(|component| component.iter())
+        .filter(|component| Some(component.ap.ap_address().ap) == ap)
+        .find(|component| {
+            component
+                .component
+                .id()
+                .peripheral_id()
+                .is_of_type(peripheral)
+        })
+        .unwrap_or(first))
+}
+
+/// Configure the ITM and DWT of core `core` if probe-rs configured those of another core, see
+/// [CaptureSession::with_core].
+fn setup_core_itm(
+    session: &mut Session,
+    components: &[CoresightComponent],
+    core: usize,
+) -> Result<(), EtfError> {
+    let ap = core_ap(session, core);
+    let first = find_component(components, PeripheralType::Itm)?;
+    let itm = core_component(components, PeripheralType::Itm, ap)?;
+    if itm == first {
+        return Ok(());
+    }
+    info!(
+        "Tracing core {core} with the ITM at {:#x}",
+        itm.component.id().component_address()
+    );
+    let interface = session.get_arm_interface()?;
+    // Clear ITMENA of the first ITM, it uses the same trace ID.
+    let tcr = first.read_reg(interface, REGISTER_OFFSET_ITM_TCR)?;
+    first.write_reg(interface, REGISTER_OFFSET_ITM_TCR, tcr & !1)?;
+    let dwt = core_component(components, PeripheralType::Dwt, ap)?;
+    let mut dwt = Dwt::new(interface, dwt);
+    dwt.enable()?;
+    dwt.enable_exception_trace()?;
+    let mut itm = Itm::new(interface, itm);
+    itm.unlock()?;
+    itm.tx_enable()?;
+    Ok(())
+}
+
+/// Find the trace funnel feeding the ETF.
+///
+/// If the target has several funnels, the one with enabled inputs is used.
+fn etf_funnel<'c>(
+    components: &'c [CoresightComponent],
+    interface: &mut dyn ArmProbeInterface,
+) -> Result<&'c CoresightComponent, EtfError> {
+    // Fails if there is no funnel at all.
+    find_component(components, PeripheralType::TraceFunnel)?;
+    let funnels: Vec<&CoresightComponent> = components
+        .iter()
+        .flat_map(|component| component.iter())
+        .filter(|component| {
+            component
+                .component
+                .id()
+                .peripheral_id()
+                .is_of_type(PeripheralType::TraceFunnel)
+        })
+        .collect();
+    if let [funnel] = funnels[..] {
+        return Ok(funnel);
+    }
+    let mut enabled = vec![];
+    for funnel in funnels.iter() {
+        if funnel.read_reg(interface, REGISTER_OFFSET_FUNNEL_CTRL)? & 0xFF != 0 {
+            enabled.push(*funnel);
+        }
+    }
+    match enabled[..] {
+        [funnel] => Ok(funnel),
+        _ => Err(EtfError::AmbiguousFunnel {
+            addresses: funnels
+                .iter()
+                .map(|funnel| funnel.component.id().component_address())
+                .collect(),
+        }),
+    }
+}
+
+/// Find the TMC to capture into.
+///
+/// A target can have several TMCs, e.g. an ETF in front of an ETR or one ETB per cluster. All
+/// TMCs of the ROM table are identified by their configuration: the first ETF is used, otherwise
+/// the first ETB. ETRs route the trace to system memory and are not used.
+///
+/// # Returns
+/// `None` if the target has no ETF or ETB.
+pub fn find_etf<'c>(
+    components: &'c [CoresightComponent],
+    interface: &mut dyn ArmProbeInterface,
+) -> Result<Option<&'c CoresightComponent>, EtfError> {
+    let mut etb = None;
+    for tmc in components
+        .iter()
+        .flat_map(|component| component.iter())
+        .filter(|component| {
+            component
+                .component
+                .id()
+                .peripheral_id()
+                .is_of_type(PeripheralType::Tmc)
+        })
+    {
+        let address = tmc.component.id().component_address();
+        let configuration = EmbeddedTraceFifo::new(interface, tmc).configuration()?;
+        debug!("Found a TMC at {address:#x} configured as {configuration:?}");
+        match configuration {
+            Some(Configuration::Etf) => return Ok(Some(tmc)),
+            Some(Configuration::Etb) => {
+                etb.get_or_insert(tmc);
+            }
+            _ => {}
+        }
+    }
+    Ok(etb)
+}
+
+/// Find the first TMC configured as an ETR, see [find_etf].
+///
+/// # Returns
+/// `None` if the target has no ETR.
+pub fn find_etr<'c>(
+    components: &'c [CoresightComponent],
+    interface: &mut dyn ArmProbeInterface,
+) -> Result<Option<&'c CoresightComponent>, EtfError> {
+    for tmc in components
+        .iter()
+        .flat_map(|component| component.iter())
+        .filter(|component| {
+            component
+                .component
+                .id()
+                .peripheral_id()
+                .is_of_type(PeripheralType::Tmc)
+        })
+    {
+        if EmbeddedTraceFifo::new(interface, tmc).configuration()? == Some(Configuration::Etr) {
+            return Ok(Some(tmc));
+        }
+    }
+    Ok(None)
+}
+
+/// Claim the ETF before it is used.
+fn claim_etf(session: &mut Session, etf: &CoresightComponent) -> Result<(), EtfError> {
+    EmbeddedTraceFifo::new(session.get_arm_interface()?, etf).claim()
+}
+
+/// Release the claim of [claim_etf] if the setup that followed it failed, so that the next
+/// capture does not find the ETF claimed.
+fn release_on_error<T>(
+    session: &mut Session,
+    etf: &CoresightComponent,
+    result: Result<T, EtfError>,
+) -> Result<T, EtfError> {
+    if result.is_err() {
+        let released = session
+            .get_arm_interface()
+            .map_err(EtfError::from)
+            .and_then(|interface| EmbeddedTraceFifo::new(interface, etf).release());
+        if let Err(e) = released {
+            warn!("Failed to release the claim of the ETF: {e}");
+        }
+    }
+    result
+}
+
+/// Set up the tracing of `core` into the claimed `etf`, see [CaptureSession::with_core].
+fn setup_trace(
+    session: &mut Session,
+    components: &[CoresightComponent],
+    etf: &CoresightComponent,
+    core: usize,
+) -> Result<(), EtfError> {
+    // Residual data has to be removed before this capture's trace is enabled.
+    discard_residual(session, etf)?;
+    match session.setup_tracing(core, TraceSink::TraceMemory) {
+        Err(Error::Arm(ArmError::RomTable(_))) => {
+            return Err(EtfError::ComponentNotFound {
+                sinks: trace_sinks(session),
+            });
+        }
+        r => r?,
+    }
+    setup_core_itm(session, components, core)?;
+    pass_replicators(session, components, ITM_ID)
+}
+
+/// Drain and discard trace data a previous capture left in the ETF.
+///
+/// A capture that was not read out completely, e.g. because the tool was interrupted, leaves the
+/// ETF capturing with data in the FIFO. That data would otherwise appear at the start of the next
+/// capture and is unlikely to be aligned to a packet boundary.
+fn discard_residual(session: &mut Session, etf: &CoresightComponent) -> Result<(), EtfError> {
+    let mut etf = EmbeddedTraceFifo::new(session.get_arm_interface()?, etf);
+    if !etf.capture_enabled()? || etf.mode()? != Some(Mode::Software) || etf.empty()? {
+        return Ok(());
+    }
+    let stale = etf.drain()?;
+    warn!(
+        "Discarded {} bytes of stale trace data left in the ETF by a previous capture",
+        stale.len()
+    );
+    Ok(())
+}
+
+/// List the trace sink components present in the target's ROM table.
+pub fn trace_sinks(session: &mut Session) -> Vec<PeripheralType> {
+    let Ok(components) = session.get_arm_components(DpAddress::Default) else {
+        return vec![];
+    };
+    [
+        PeripheralType::Tmc,
+        PeripheralType::Etb,
+        PeripheralType::Tpiu,
+        PeripheralType::Swo,
+    ]
+    .into_iter()
+    .filter(|&kind| find_component(&components, kind).is_ok())
+    .collect()
+}
+
+/// Check whether an error of the probe-rs trace readout means that it is not supported for the
+/// target or probe, so that the ETF can be drained instead.
+fn is_unsupported(error: &ArmError) -> bool {
+    matches!(
+        error,
+        ArmError::TracingUnconfigured
+            | ArmError::ArchitectureRequired(_)
+            | ArmError::Probe(
+                DebugProbeError::NotImplemented(_) | DebugProbeError::CommandNotSupportedByProbe(_)
+            )
+    )
+}
+
+/// Check whether an error is caused by the debug probe disconnecting.
+pub fn is_disconnect(error: &EtfError) -> bool {
+    matches!(
+        error,
+        EtfError::Probe(
+            Error::Probe(DebugProbeError::Usb(_))
+                | Error::Arm(ArmError::Probe(DebugProbeError::Usb(_)))
+        )
+    )
+}
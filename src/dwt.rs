@@ -0,0 +1,193 @@
+//! Profile of the DWT packets of a trace
+//!
+//! A [DwtSummary] aggregates the PC samples, the exception trace and the event counter wraps of
+//! a decoded trace, e.g. to find where the firmware spends its time. The PC samples can also be
+//! written as a [folded](DwtSummary::folded) profile for flame graph tools.
+use crate::symbols::Symbols;
+use itm::{cortex_m::VectActive, ExceptionAction, TracePacket};
+use std::collections::BTreeMap;
+
+/// Names of the DWT event counters in the order of [DwtSummary::counter_wraps].
+pub const COUNTERS: [&str; 6] = ["cpi", "exc", "sleep", "lsu", "fold", "cyc"];
+
+/// The DWT event counters other than the POSTCNT of `cyc` are 8 bits wide.
+const COUNTER_PERIOD: usize = 256;
+
+/// Exception events by exception number, see [DwtSummary::exceptions].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExceptionEvents {
+    /// Number of times the exception was entered.
+    pub entered: usize,
+    /// Number of times the exception was exited.
+    pub exited: usize,
+    /// Number of times the processor returned to the exception.
+    pub returned: usize,
+}
+
+/// Counts of the DWT packets of a trace.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DwtSummary {
+    /// Number of PC samples by program counter.
+    pub pc_samples: BTreeMap<u32, usize>,
+    /// Number of PC samples taken while the core was sleeping.
+    pub sleep_samples: usize,
+    /// The exception events by exception number, see [exception_number].
+    pub exceptions: BTreeMap<u16, ExceptionEvents>,
+    /// Number of wraps of each event counter, in the order of [COUNTERS].
+    pub counter_wraps: [usize; 6],
+}
+
+impl DwtSummary {
+    /// Account for a packet, other than DWT packets are ignored.
+    pub fn add(&mut self, packet: &TracePacket) {
+        match packet {
+            TracePacket::PCSample { pc: Some(pc) } => *self.pc_samples.entry(*pc).or_default() += 1,
+            TracePacket::PCSample { pc: None } => self.sleep_samples += 1,
+            TracePacket::ExceptionTrace { exception, action } => {
+                let events = self
+                    .exceptions
+                    .entry(exception_number(exception))
+                    .or_default();
+                match action {
+                    ExceptionAction::Entered => events.entered += 1,
+                    ExceptionAction::Exited => events.exited += 1,
+                    ExceptionAction::Returned => events.returned += 1,
+                }
+            }
+            TracePacket::EventCounterWrap {
+                cyc,
+                fold,
+                lsu,
+                sleep,
+                exc,
+                cpi,
+            } => {
+                for (wraps, wrapped) in self
+                    .counter_wraps
+                    .iter_mut()
+                    .zip([cpi, exc, sleep, lsu, fold, cyc])
+                {
+                    *wraps += *wrapped as usize;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Check if the trace had no DWT packets.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The `count` most sampled program counters with their number of samples, the most sampled
+    /// first.
+    pub fn hottest(&self, count: usize) -> Vec<(u32, usize)> {
+        let mut samples: Vec<_> = self.pc_samples.iter().map(|(pc, n)| (*pc, *n)).collect();
+        samples.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        samples.truncate(count);
+        samples
+    }
+
+    /// The PC samples in the folded (collapsed stack) format of `inferno` and `flamegraph.pl`.
+    ///
+    /// Each line is a single frame and its number of samples. With `symbols`, the samples are
+    /// accounted for by the function containing the program counter, otherwise by the program
+    /// counter itself. The samples taken while sleeping are accounted for in a `sleep` frame.
+    pub fn folded(&self, symbols: Option<&Symbols>) -> String {
+        let mut frames = BTreeMap::<String, usize>::new();
+        for (pc, n) in &self.pc_samples {
+            let frame = symbols
+                .and_then(|symbols| symbols.lookup(*pc as _))
+                .map_or_else(|| format!("{pc:#010x}"), |(name, _)| name.to_string());
+            *frames.entry(frame).or_default() += n;
+        }
+        if self.sleep_samples > 0 {
+            *frames.entry("sleep".into()).or_default() += self.sleep_samples;
+        }
+        frames
+            .iter()
+            .map(|(frame, n)| format!("{frame} {n}\n"))
+            .collect()
+    }
+
+    /// The summary as text tables.
+    ///
+    /// # Args
+    /// * `symbols` - Symbolicate the program counters.
+    /// * `top` - The number of most sampled program counters to list.
+    pub fn table(&self, symbols: Option<&Symbols>, top: usize) -> String {
+        let mut lines = vec![];
+        let samples = self.pc_samples.values().sum::<usize>() + self.sleep_samples;
+        if samples > 0 {
+            lines.push(format!("{:>8} {:>6}  pc", "samples", "%"));
+            let percent = |n: usize| 100.0 * n as f64 / samples as f64;
+            let hottest = self.hottest(top);
+            for (pc, n) in &hottest {
+                let pc = symbols.map_or_else(|| format!("{pc:#010x}"), |s| s.describe(*pc as _));
+                lines.push(format!("{n:>8} {:>6.2}  {pc}", percent(*n)));
+            }
+            let others = self.pc_samples.len() - hottest.len();
+            if others > 0 {
+                let n =
+                    samples - self.sleep_samples - hottest.iter().map(|(_, n)| n).sum::<usize>();
+                lines.push(format!(
+                    "{n:>8} {:>6.2}  {others} other program counters",
+                    percent(n)
+                ));
+            }
+            if self.sleep_samples > 0 {
+                let n = self.sleep_samples;
+                lines.push(format!("{n:>8} {:>6.2}  sleep", percent(n)));
+            }
+            lines.push(String::new());
+        }
+        if !self.exceptions.is_empty() {
+            lines.push(format!(
+                "{:>9} {:>8} {:>8} {:>8}  name",
+                "exception", "entered", "exited", "returned"
+            ));
+            for (number, events) in &self.exceptions {
+                lines.push(format!(
+                    "{number:>9} {:>8} {:>8} {:>8}  {}",
+                    events.entered,
+                    events.exited,
+                    events.returned,
+                    exception_name(*number)
+                ));
+            }
+            lines.push(String::new());
+        }
+        if self.counter_wraps.iter().any(|wraps| *wraps > 0) {
+            lines.push(format!("{:>9} {:>8} {:>10}", "counter", "wraps", "events"));
+            for (name, wraps) in COUNTERS.iter().zip(self.counter_wraps) {
+                // The POSTCNT period depends on the DWT_CTRL configuration.
+                let events = match *name {
+                    "cyc" => "-".into(),
+                    _ => format!(">={}", wraps * COUNTER_PERIOD),
+                };
+                lines.push(format!("{name:>9} {wraps:>8} {events:>10}"));
+            }
+            lines.push(String::new());
+        }
+        lines.join("\n")
+    }
+}
+
+/// The name of an exception by its number, see [exception_number].
+fn exception_name(number: u16) -> String {
+    match VectActive::from(number) {
+        Some(VectActive::ThreadMode) => "thread".into(),
+        Some(VectActive::Exception(exception)) => format!("{exception:?}"),
+        Some(VectActive::Interrupt { irqn }) => format!("IRQ{irqn}"),
+        None => "reserved".into(),
+    }
+}
+
+/// The exception number of an exception: 0 for thread mode, 16 and above for interrupts.
+pub fn exception_number(exception: &VectActive) -> u16 {
+    match *exception {
+        VectActive::ThreadMode => 0,
+        VectActive::Exception(exception) => (exception.irqn() + 16) as u16,
+        VectActive::Interrupt { irqn } => irqn + 16,
+    }
+}
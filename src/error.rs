@@ -0,0 +1,174 @@
+//! Error types
+use crate::etf::Mode;
+use probe_rs::architecture::arm::{memory::PeripheralType, ArmError};
+use std::time::Duration;
+
+/// Errors of trace capture and decoding.
+#[derive(Debug, thiserror::Error)]
+pub enum EtfError {
+    /// The target has no trace memory to capture into.
+    #[error(
+        "Target does not support tracing to trace memory (TMC/ETF). \
+         Trace sinks found on this target: {sinks:?}"
+    )]
+    ComponentNotFound {
+        /// The trace sinks that were found instead.
+        sinks: Vec<PeripheralType>,
+    },
+
+    /// No connected probe matches the probe selector.
+    #[error(
+        "No probe matches {selector}, the connected probes are:{}",
+        .probes.iter().map(|probe| format!("\n  {probe}")).collect::<String>()
+    )]
+    ProbeNotFound {
+        /// The requested probe selector.
+        selector: String,
+        /// The connected probes, each with its index and `VID:PID:Serial` selector.
+        probes: Vec<String>,
+    },
+
+    /// Several connected probes match the probe selector.
+    #[error("Several probes match {selector}, pass one of the serial numbers {matches:?}")]
+    AmbiguousProbe {
+        /// The requested probe selector.
+        selector: String,
+        /// The serial numbers of the matching probes.
+        matches: Vec<String>,
+    },
+
+    /// The ETF does not implement the requested mode.
+    #[error("{register} reads back {readback:#04b} after writing {mode:?}, mode not supported")]
+    UnsupportedMode {
+        /// Name of the mode register.
+        register: &'static str,
+        /// The requested mode.
+        mode: Mode,
+        /// The raw mode read back after writing.
+        readback: u8,
+    },
+
+    /// The target does not have the requested core.
+    #[error("Core {core} out of range, the target has {cores} cores")]
+    CoreNotFound {
+        /// The requested core index.
+        core: usize,
+        /// The number of cores of the target.
+        cores: usize,
+    },
+
+    /// The trace funnel does not have the requested input port.
+    #[error("Trace funnel port {port} out of range, the funnel has {ports} input ports")]
+    FunnelPort {
+        /// The requested port.
+        port: u8,
+        /// The number of input ports of the funnel.
+        ports: u8,
+    },
+
+    /// The trace funnel feeding the ETF can not be determined.
+    #[error(
+        "Can not determine the trace funnel feeding the ETF among the funnels at {addresses:#x?}"
+    )]
+    AmbiguousFunnel {
+        /// The addresses of the trace funnels found.
+        addresses: Vec<u64>,
+    },
+
+    /// A trace replicator keeps discarding the captured trace ID.
+    #[error(
+        "Trace replicator at {address:#x} still discards trace ID {id:#04x}: {register} reads \
+         {value:#x} after clearing its filter"
+    )]
+    Replicator {
+        /// Base address of the replicator.
+        address: u64,
+        /// Name of the ID filter register.
+        register: &'static str,
+        /// The value read back.
+        value: u32,
+        /// The trace ID.
+        id: u8,
+    },
+
+    /// The ETF is claimed by software on the target, see [crate::etf::EmbeddedTraceFifo::claim].
+    #[error("The ETF is in use by software on the target, its claim tags are {tags:#x}")]
+    Claimed {
+        /// The claim tags that are set.
+        tags: u32,
+    },
+
+    /// A register does not hold a valid value and is likely not implemented.
+    #[error("{register} reads {value:#x}, which is not a valid value")]
+    UnsupportedRegister {
+        /// Name of the register.
+        register: &'static str,
+        /// The value read.
+        value: u32,
+    },
+
+    /// The streaming watermarks are not ordered or out of range.
+    #[error(
+        "Invalid watermarks: low {low}% must be below high {high}%, which must be at most 100%"
+    )]
+    Watermarks {
+        /// The low watermark in percent.
+        low: u8,
+        /// The high watermark in percent.
+        high: u8,
+    },
+
+    /// The selected trace source produced no data, while others did.
+    #[error(
+        "No data of trace source ID {id:#04x}, the capture contains trace IDs {sources:#04x?}"
+    )]
+    SourceNotFound {
+        /// The selected trace ID.
+        id: u8,
+        /// The trace IDs found in the capture.
+        sources: Vec<u8>,
+    },
+
+    /// A clock frequency is zero.
+    #[error("The {0} frequency must not be zero")]
+    ZeroFrequency(&'static str),
+
+    /// The capture did not stop in time.
+    #[error("Capture did not stop within {0:?}")]
+    CaptureTimeout(Duration),
+
+    /// The FIFO filled up and trace data was lost.
+    #[error("Trace FIFO overflow, trace data was lost")]
+    Overflow,
+
+    /// The header of a trace dump is truncated or of an unsupported version.
+    #[error("Invalid dump header: {0}")]
+    DumpHeader(String),
+
+    /// The trace data could not be decoded.
+    #[error("Decoder error")]
+    Decode(#[from] itm::DecoderError),
+
+    /// The firmware ELF file could not be parsed.
+    #[error("Invalid ELF file")]
+    Elf(#[from] object::read::Error),
+
+    /// A file could not be read or written.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The SQLite database could not be written.
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite database error")]
+    Sqlite(#[from] rusqlite::Error),
+
+    /// Communication with the probe or target failed.
+    #[error(transparent)]
+    Probe(#[from] probe_rs::Error),
+}
+
+impl From<ArmError> for EtfError {
+    fn from(error: ArmError) -> Self {
+        Self::Probe(error.into())
+    }
+}
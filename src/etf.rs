@@ -0,0 +1,1092 @@
+//! Embedded Trace FIFO driver
+//!
+//! The ETF is a CoreSight Trace Memory Controller (TMC) configured as a FIFO. It buffers trace
+//! data in on-chip SRAM and makes it available either to the TPIU or to the debug interface
+//! through the RAM Read Data (RRD) register.
+//!
+//! The driver only needs an [ArmProbeInterface] and the [CoresightComponent] of the ETF, e.g. as
+//! found by [crate::capture::find_etf], so it can be used for a capture loop of its own: claim
+//! the ETF, select [Mode::Software] and enable the capture, then [EmbeddedTraceFifo::drain] it
+//! repeatedly. [EmbeddedTraceFifo::flush_and_stop] ends the capture, the data still in the FIFO
+//! can be drained afterwards. The drained data is formatted if the formatter is enabled, see
+//! [crate::formatter].
+use crate::{formatter::FRAME_SIZE, EtfError};
+use bitfield::bitfield;
+use log::{debug, warn};
+use probe_rs::{
+    architecture::arm::{
+        ap::{AccessPort, AddressIncrement, DataSize, CSW, DRW, TAR},
+        memory::CoresightComponent,
+        ArmError, ArmProbeInterface, Register,
+    },
+    Error,
+};
+use std::time::{Duration, Instant};
+
+const REGISTER_OFFSET_RSZ: u32 = 0x04;
+const REGISTER_OFFSET_RRD: u32 = 0x10;
+const REGISTER_OFFSET_RRP: u32 = 0x14;
+const REGISTER_OFFSET_RWP: u32 = 0x18;
+const REGISTER_OFFSET_TRG: u32 = 0x1C;
+const REGISTER_OFFSET_CTL: u32 = 0x20;
+const REGISTER_OFFSET_CBUFLVL: u32 = 0x30;
+const REGISTER_OFFSET_CLAIMSET: u32 = 0xFA0;
+const REGISTER_OFFSET_CLAIMCLR: u32 = 0xFA4;
+const REGISTER_OFFSET_DEVID: u32 = 0xFC8;
+
+/// The claim tag of an external debugger, see [EmbeddedTraceFifo::claim].
+const CLAIM_EXTERNAL: u32 = 1 << 0;
+/// The claim tag of self-hosted software on the target, e.g. a driver of another core.
+const CLAIM_SELF_HOSTED: u32 = 1 << 1;
+
+/// The value of the RRD register when the FIFO is empty.
+pub const EMPTY: u32 = 0xFFFF_FFFF;
+
+/// Number of words of the RRD register read in a single block transfer, see
+/// [EmbeddedTraceFifo::read_block].
+pub const BLOCK_WORDS: usize = 256;
+
+/// Default maximum time to wait for the capture to stop and the pipelines to drain.
+pub const STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Waiting between reads of an empty FIFO while draining the rest of a formatter frame.
+///
+/// The final bytes of a frame may still be in the trace pipelines when the FIFO runs empty.
+/// Every read of the empty FIFO costs a full probe round trip, so instead of reading again
+/// immediately, the drain waits after each short read. The wait doubles up to a maximum and is
+/// reset by a read that returns all the data it asked for. A zero initial wait disables the
+/// backoff.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// The wait after the first empty read.
+    pub initial: Duration,
+    /// The longest wait.
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_micros(10),
+            max: Duration::from_millis(1),
+        }
+    }
+}
+
+/// A memory mapped register of a CoreSight debug component.
+pub trait DebugRegister: Clone + From<u32> + Into<u32> + Sized + std::fmt::Debug {
+    /// Offset of the register from the component base address.
+    const ADDRESS: u32;
+    /// Register name as used in the TRM.
+    const NAME: &'static str;
+
+    /// Read the register from the component.
+    fn load(
+        component: &CoresightComponent,
+        interface: &mut dyn ArmProbeInterface,
+    ) -> Result<Self, Error> {
+        Ok(Self::from(component.read_reg(interface, Self::ADDRESS)?))
+    }
+
+    /// Write the register to the component.
+    fn store(
+        &self,
+        component: &CoresightComponent,
+        interface: &mut dyn ArmProbeInterface,
+    ) -> Result<(), Error> {
+        component.write_reg(interface, Self::ADDRESS, self.clone().into())?;
+        Ok(())
+    }
+}
+
+/// The operational mode of the ETF.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Trace memory is used as a circular buffer. When the buffer fills, incoming trace data will
+    /// overwrite older trace memory until the trace is stopped.
+    Circular = 0b00,
+
+    /// The trace memory is used as a FIFO that can be manually read through the RRD register. When
+    /// the buffer fills, the incoming trace stream is stalled.
+    Software = 0b01,
+
+    /// Trace memory is used as a FIFO that is drained through hardware to the TPIU. Trace data
+    /// is captured until the buffer fills, at which point the incoming trace stream is stalled.
+    /// Whenever the buffer is non-empty, trace data is drained to the TPIU.
+    Hardware = 0b10,
+}
+
+/// The configuration a TMC was implemented in, see the CONFIGTYPE field of its DEVID register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Configuration {
+    /// Embedded Trace Buffer: a circular buffer or software FIFO in dedicated trace memory.
+    Etb = 0b00,
+    /// Embedded Trace Router: routes the trace to system memory, it has no trace memory of its
+    /// own to read through RRD.
+    Etr = 0b01,
+    /// Embedded Trace FIFO: an ETB that can also drain to a downstream sink.
+    Etf = 0b10,
+}
+
+/// The embedded trace FIFO.
+pub struct EmbeddedTraceFifo<'a> {
+    component: &'a CoresightComponent,
+    interface: &'a mut dyn ArmProbeInterface,
+    fifo_size: Option<u32>,
+    backoff: Backoff,
+    timeout: Duration,
+}
+
+impl<'a> EmbeddedTraceFifo<'a> {
+    /// Construct a new embedded trace FIFO controller.
+    pub fn new(
+        interface: &'a mut dyn ArmProbeInterface,
+        component: &'a CoresightComponent,
+    ) -> Self {
+        Self {
+            component,
+            interface,
+            fifo_size: None,
+            backoff: Backoff::default(),
+            timeout: STOP_TIMEOUT,
+        }
+    }
+
+    /// Configure the waiting between empty reads of [Self::drain].
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// Configure the maximum time to wait for the capture to stop, see [Self::stop_capture]
+    /// and [Self::flush_and_stop]. Defaults to [STOP_TIMEOUT].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The base address of the ETF.
+    pub fn address(&self) -> u64 {
+        self.component.component.id().component_address()
+    }
+
+    /// Configure the FIFO operational mode.
+    ///
+    /// # Args
+    /// * `mode` - The desired operational mode of the FIFO.
+    ///
+    /// # Note
+    /// The mode is read back after writing it. Parts that do not implement the requested mode
+    /// ignore the write, which is reported as an error.
+    pub fn set_mode(&mut self, mode: Mode) -> Result<(), EtfError> {
+        let mut mode_reg = EtfMode::load(self.component, self.interface)?;
+        mode_reg.set_mode(mode as _);
+        mode_reg.store(self.component, self.interface)?;
+
+        let readback = EtfMode::load(self.component, self.interface)?;
+        if readback.mode() != mode as u8 {
+            return Err(EtfError::UnsupportedMode {
+                register: EtfMode::NAME,
+                mode,
+                readback: readback.mode(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the FIFO operational mode.
+    ///
+    /// # Returns
+    /// The mode, or `None` for the reserved mode encoding.
+    pub fn mode(&mut self) -> Result<Option<Mode>, EtfError> {
+        let mode = EtfMode::load(self.component, self.interface)?;
+        Ok(match mode.mode() {
+            0b00 => Some(Mode::Circular),
+            0b01 => Some(Mode::Software),
+            0b10 => Some(Mode::Hardware),
+            _ => None,
+        })
+    }
+
+    /// Check if trace capture is enabled.
+    pub fn capture_enabled(&mut self) -> Result<bool, EtfError> {
+        let ctl = self
+            .component
+            .read_reg(self.interface, REGISTER_OFFSET_CTL)?;
+        Ok(ctl & 1 != 0)
+    }
+
+    /// Enable trace captures using the FIFO.
+    pub fn enable_capture(&mut self) -> Result<(), EtfError> {
+        self.component
+            .write_reg(self.interface, REGISTER_OFFSET_CTL, 1)?;
+        Ok(())
+    }
+
+    /// Disable trace captures using the FIFO.
+    pub fn disable_capture(&mut self) -> Result<(), EtfError> {
+        self.component
+            .write_reg(self.interface, REGISTER_OFFSET_CTL, 0)?;
+        Ok(())
+    }
+
+    /// Attempt to read a value out of the FIFO
+    pub fn read(&mut self) -> Result<Option<u32>, EtfError> {
+        // Read the RRD register.
+        match self
+            .component
+            .read_reg(self.interface, REGISTER_OFFSET_RRD)?
+        {
+            // The register has a sentinel value to indicate no more data is available in the FIFO.
+            EMPTY => Ok(None),
+
+            value => Ok(Some(value)),
+        }
+    }
+
+    /// Read words out of the FIFO into a buffer.
+    ///
+    /// # Args
+    /// * `buf` - The buffer to fill with trace data.
+    ///
+    /// # Note
+    /// The RRD register is read with a single repeated DRW transfer of the memory AP with the
+    /// address auto-increment disabled. If the block transfer fails, the words are read one by one
+    /// using [Self::read] instead.
+    ///
+    /// # Returns
+    /// The number of words read. This is less than the buffer size if the FIFO ran empty.
+    pub fn read_words(&mut self, buf: &mut [u32]) -> Result<usize, EtfError> {
+        match self.read_block(buf) {
+            Ok(count) => {
+                debug!("Read {count} words from RRD using a block transfer");
+                Ok(count)
+            }
+            Err(e) => {
+                debug!("Block transfer of RRD failed ({e}), falling back to single reads");
+                for (count, word) in buf.iter_mut().enumerate() {
+                    match self.read()? {
+                        Some(value) => *word = value,
+                        None => return Ok(count),
+                    }
+                }
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// Read the RRD register repeatedly into `buf` through the DRW register of the memory AP.
+    ///
+    /// Unlike [Self::read_words], this does not fall back to single reads. The words are
+    /// transferred in blocks of [BLOCK_WORDS], and no further block is read once a block contained
+    /// an [EMPTY] sentinel.
+    ///
+    /// # Returns
+    /// The number of trace data words, moved in order to the start of `buf`, see
+    /// [remove_empty].
+    pub fn read_block(&mut self, buf: &mut [u32]) -> Result<usize, EtfError> {
+        let ap = self.component.ap.ap_address();
+        let address = u32::try_from(
+            self.component.component.id().component_address() + REGISTER_OFFSET_RRD as u64,
+        )
+        .map_err(|_| ArmError::OutOfBounds)?;
+
+        let raw_csw = self.interface.read_raw_ap_register(ap, CSW::ADDRESS)?;
+        let mut csw = CSW::try_from(raw_csw).map_err(|e| ArmError::Other(e.into()))?;
+        csw.AddrInc = AddressIncrement::Off;
+        csw.SIZE = DataSize::U32;
+        let mut transfer = || -> Result<usize, ArmError> {
+            self.interface
+                .write_raw_ap_register(ap, CSW::ADDRESS, csw.into())?;
+            self.interface
+                .write_raw_ap_register(ap, TAR::ADDRESS, address)?;
+            let mut count = 0;
+            for start in (0..buf.len()).step_by(BLOCK_WORDS) {
+                let end = buf.len().min(start + BLOCK_WORDS);
+                let block = &mut buf[start..end];
+                self.interface
+                    .read_raw_ap_register_repeated(ap, DRW::ADDRESS, block)?;
+                let words = remove_empty(block);
+                buf.copy_within(start..start + words, count);
+                count += words;
+                if start + words < end {
+                    break;
+                }
+            }
+            Ok(count)
+        };
+        let res = transfer();
+
+        // Memory interfaces cache neither CSW nor TAR across instances, but restore the
+        // original configuration regardless, also after a failed transfer.
+        let restored = self
+            .interface
+            .write_raw_ap_register(ap, CSW::ADDRESS, raw_csw);
+        let count = res?;
+        restored?;
+        Ok(count)
+    }
+
+    /// Read all trace data that is currently in the FIFO.
+    ///
+    /// # Note
+    /// Reading continues until the FIFO is empty on a formatter frame boundary, or until a full
+    /// FIFO size of data was read, so that a producer faster than the drain does not keep this
+    /// from returning. If the FIFO runs empty within a frame, reads are spaced according to the
+    /// [Backoff].
+    ///
+    /// # Returns
+    /// The raw formatted trace data, a whole number of formatter frames.
+    pub fn drain(&mut self) -> Result<Vec<u8>, EtfError> {
+        let mut data = Vec::new();
+        self.drain_with(|word| data.extend_from_slice(&word.to_le_bytes()))?;
+        Ok(data)
+    }
+
+    /// Read all trace data that is currently in the FIFO into `writer`, see [Self::drain_with].
+    ///
+    /// The words are written one by one, `writer` should be buffered. After a write error the
+    /// FIFO is still drained, the error is returned at the end.
+    ///
+    /// # Returns
+    /// The number of bytes written.
+    pub fn drain_to(&mut self, writer: &mut impl std::io::Write) -> Result<usize, EtfError> {
+        let mut written = Ok(());
+        let words = self.drain_with(|word| {
+            if written.is_ok() {
+                written = writer.write_all(&word.to_le_bytes());
+            }
+        })?;
+        written?;
+        Ok(words * core::mem::size_of::<u32>())
+    }
+
+    /// Read all trace data that is currently in the FIFO, passing each word to `f` as it is read.
+    ///
+    /// This ends on the same conditions as [Self::drain] but does not collect the data.
+    ///
+    /// # Returns
+    /// The number of words read, a whole number of formatter frames unless the capture stopped
+    /// within a frame, e.g. without a flush or with the formatter bypassed.
+    pub fn drain_with<F: FnMut(u32)>(&mut self, mut f: F) -> Result<usize, EtfError> {
+        const WORD: usize = core::mem::size_of::<u32>();
+        let size = self.fifo_size()? as usize;
+        let mut words = 0;
+        let mut buf = vec![0; size / WORD];
+        let mut delay = self.backoff.initial;
+        // The FIFO was found drained after the capture stopped.
+        let mut stopped = false;
+        loop {
+            let level = self.fill_level()? as usize / WORD;
+            let len = level.clamp(1, buf.len());
+            let count = self.read_words(&mut buf[..len])?;
+            buf[..count].iter().copied().for_each(&mut f);
+            words += count;
+
+            let frame_boundary = (words * WORD).is_multiple_of(FRAME_SIZE);
+            if frame_boundary && (count < len || words * WORD >= size) {
+                break;
+            }
+            if count < len && stopped {
+                debug!("The capture stopped within a formatter frame after {words} words");
+                break;
+            }
+            if count < len && self.drained()? {
+                // Read once more in case the last data arrived since the short read.
+                stopped = true;
+            } else if count < len {
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(self.backoff.max);
+            } else {
+                delay = self.backoff.initial;
+            }
+        }
+        Ok(words)
+    }
+
+    /// Claim the ETF for this debugger with the CoreSight claim tags.
+    ///
+    /// On parts where the ETF is shared, e.g. between the cores or with a trace driver on the
+    /// target, the claim tags arbitrate its use: the external debugger sets tag 0, self-hosted
+    /// software tag 1. This fails with [EtfError::Claimed] if the target software holds the ETF.
+    /// An existing claim of an external debugger, e.g. left by an aborted capture, is only
+    /// warned about. Without claim tags this does nothing.
+    pub fn claim(&mut self) -> Result<(), EtfError> {
+        // CLAIMSET reads as the implemented tags.
+        let implemented = self
+            .component
+            .read_reg(self.interface, REGISTER_OFFSET_CLAIMSET)?;
+        if implemented & CLAIM_EXTERNAL == 0 {
+            debug!("The ETF has no claim tags ({implemented:#x})");
+            return Ok(());
+        }
+        let tags = self.claim_tags()?;
+        if tags & CLAIM_SELF_HOSTED != 0 {
+            return Err(EtfError::Claimed { tags });
+        }
+        if tags & CLAIM_EXTERNAL != 0 {
+            warn!("The ETF is already claimed by a debugger, possibly by an aborted capture");
+        }
+        self.component
+            .write_reg(self.interface, REGISTER_OFFSET_CLAIMSET, CLAIM_EXTERNAL)?;
+        // The target software may have claimed it at the same time.
+        let tags = self.claim_tags()?;
+        if tags & CLAIM_SELF_HOSTED != 0 {
+            self.release()?;
+            return Err(EtfError::Claimed { tags });
+        }
+        Ok(())
+    }
+
+    /// Release the claim of [Self::claim].
+    pub fn release(&mut self) -> Result<(), EtfError> {
+        self.component
+            .write_reg(self.interface, REGISTER_OFFSET_CLAIMCLR, CLAIM_EXTERNAL)?;
+        Ok(())
+    }
+
+    /// The claim tags that are set, read from CLAIMCLR.
+    pub fn claim_tags(&mut self) -> Result<u32, EtfError> {
+        Ok(self
+            .component
+            .read_reg(self.interface, REGISTER_OFFSET_CLAIMCLR)?)
+    }
+
+    /// Drain the FIFO word by word with [Self::read], as an iterator.
+    ///
+    /// The iterator ends on the same conditions as [Self::drain]. It also ends within a
+    /// formatter frame if the capture has stopped and the FIFO is drained (see [Self::drained]),
+    /// as the rest of the frame can then not arrive. It ends after the first error.
+    pub fn words(&mut self) -> Result<Drain<'_, 'a>, EtfError> {
+        let size = self.fifo_size()? as usize / core::mem::size_of::<u32>();
+        let delay = self.backoff.initial;
+        Ok(Drain {
+            fifo: self,
+            size,
+            words: 0,
+            delay,
+            stopped: false,
+            done: false,
+        })
+    }
+
+    /// Flush the formatter and stop the capture once all data preceding the flush was written.
+    pub fn flush_and_stop(&mut self) -> Result<(), EtfError> {
+        self.stop_on_flush(true)?;
+        self.manual_flush()?;
+        let start = Instant::now();
+        while !self.drained()? {
+            if start.elapsed() > self.timeout {
+                return Err(EtfError::CaptureTimeout(self.timeout));
+            }
+        }
+        self.disable_capture()
+    }
+
+    /// Read the trace memory of a stopped circular buffer capture.
+    ///
+    /// # Args
+    /// * `words` - Only read the most recent words. Clamped to the FIFO size.
+    ///
+    /// # Note
+    /// The capture must have been stopped, e.g. with [Self::flush_and_stop]. The data is returned
+    /// oldest first, see [circular_window].
+    ///
+    /// # Returns
+    /// The raw formatted trace data, and whether it begins within the trace, i.e. at the wrap
+    /// seam of the buffer or within the window of `words`, instead of at the start of the
+    /// capture.
+    pub fn read_circular(&mut self, words: Option<u32>) -> Result<(Vec<u8>, bool), EtfError> {
+        const WORD: u32 = core::mem::size_of::<u32>() as u32;
+        let size = self.fifo_size()?;
+        let write_pointer = self.write_pointer()?;
+        // In circular mode the full flag indicates that the write pointer has wrapped.
+        let wrapped = self.full()?;
+        if let Some(words) = words.filter(|words| words.saturating_mul(WORD) > size) {
+            warn!("Requested {words} words exceed the FIFO size of {size} bytes, clamping");
+        }
+        let (start, len) = circular_window(size, write_pointer, wrapped, words);
+        self.set_read_pointer(start)?;
+
+        let mut buf = vec![0; (len / WORD) as usize];
+        match self.read_block(&mut buf) {
+            Ok(count) => buf.truncate(count),
+            Err(e) => {
+                debug!("Block transfer of RRD failed ({e}), falling back to single reads");
+                // The block transfer may have advanced the read pointer.
+                self.set_read_pointer(start)?;
+                for word in buf.iter_mut() {
+                    *word = self
+                        .component
+                        .read_reg(self.interface, REGISTER_OFFSET_RRD)?;
+                }
+            }
+        }
+        let data = buf.iter().flat_map(|word| word.to_le_bytes()).collect();
+        Ok((data, wrapped || len < write_pointer))
+    }
+
+    /// Get the trace RAM read pointer.
+    ///
+    /// # Returns
+    /// The byte offset in trace RAM of the next word read from the RAM read data register.
+    pub fn read_pointer(&mut self) -> Result<u32, EtfError> {
+        self.pointer("ETF_RRP", REGISTER_OFFSET_RRP)
+    }
+
+    /// Set the trace RAM read pointer.
+    ///
+    /// # Args
+    /// * `pointer` - The byte offset in trace RAM to read from next. Must be word aligned.
+    ///
+    /// # Note
+    /// The pointer may only be changed while the capture is disabled.
+    pub fn set_read_pointer(&mut self, pointer: u32) -> Result<(), EtfError> {
+        self.component
+            .write_reg(self.interface, REGISTER_OFFSET_RRP, pointer)?;
+        let readback = self.read_pointer()?;
+        if readback != pointer {
+            return Err(EtfError::UnsupportedRegister {
+                register: "ETF_RRP",
+                value: readback,
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the trace RAM write pointer.
+    ///
+    /// # Returns
+    /// The byte offset in trace RAM the next trace data word will be written to.
+    pub fn write_pointer(&mut self) -> Result<u32, EtfError> {
+        self.pointer("ETF_RWP", REGISTER_OFFSET_RWP)
+    }
+
+    /// Read a trace RAM pointer register and check that it points into the trace RAM.
+    ///
+    /// Registers that are not implemented read as a constant that typically fails this check.
+    fn pointer(&mut self, register: &'static str, offset: u32) -> Result<u32, EtfError> {
+        let size = self.fifo_size()?;
+        let value = self.component.read_reg(self.interface, offset)?;
+        if value >= size || !value.is_multiple_of(core::mem::size_of::<u32>() as u32) {
+            return Err(EtfError::UnsupportedRegister { register, value });
+        }
+        Ok(value)
+    }
+
+    /// Read the status register.
+    ///
+    /// Each of [Self::full], [Self::empty], [Self::ready], [Self::drained] and [Self::triggered]
+    /// reads it again. A loop that checks several of the flags reads it once with this instead.
+    pub fn status(&mut self) -> Result<Status, EtfError> {
+        Ok(Status::load(self.component, self.interface)?)
+    }
+
+    /// Check if the FIFO is full.
+    pub fn full(&mut self) -> Result<bool, EtfError> {
+        Ok(self.status()?.full())
+    }
+
+    /// Check if the FIFO is empty.
+    pub fn empty(&mut self) -> Result<bool, EtfError> {
+        Ok(self.status()?.empty())
+    }
+
+    /// Check if the ET capture has stopped and all internal pipelines and buffers have been
+    /// drained.
+    pub fn ready(&mut self) -> Result<bool, EtfError> {
+        Ok(self.status()?.ready())
+    }
+
+    /// Check if the capture has stopped and all trace data has been written to trace memory.
+    ///
+    /// # Note
+    /// Unlike [Self::ready], this also requires the formatter pipeline to be empty so that the
+    /// final formatter frame is not lost when draining the FIFO.
+    pub fn drained(&mut self) -> Result<bool, EtfError> {
+        Ok(self.status()?.drained())
+    }
+
+    /// Disable the capture and wait until all trace data has been written to trace memory.
+    ///
+    /// # Note
+    /// Fails with [EtfError::CaptureTimeout] if the ETF does not become drained within the
+    /// timeout, see [Self::set_timeout].
+    pub fn stop_capture(&mut self) -> Result<(), EtfError> {
+        self.disable_capture()?;
+        let start = Instant::now();
+        while !self.drained()? {
+            if start.elapsed() > self.timeout {
+                return Err(EtfError::CaptureTimeout(self.timeout));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if the ETF has triggered.
+    ///
+    /// # Note
+    /// This will only be set when operating in circular buffer modes.
+    pub fn triggered(&mut self) -> Result<bool, EtfError> {
+        Ok(self.status()?.trigd())
+    }
+
+    /// Set the number of words to capture after a trigger event in circular buffer mode.
+    ///
+    /// The TMC counts down the words written after a trigger event and only then reports
+    /// [Self::triggered] (and stops the capture, if configured to). This places the trigger in
+    /// the buffer: zero leaves it at the end, a value close to the FIFO size at the start.
+    ///
+    /// # Args
+    /// * `words` - The number of 32 bit words, like [Self::fill_level] and [Self::fifo_size] in
+    ///   words. Clamped to the FIFO size with a warning.
+    ///
+    /// # Note
+    /// The counter may only be written while the capture is disabled.
+    pub fn set_trigger_count(&mut self, words: u32) -> Result<(), EtfError> {
+        let size = self.fifo_size()? / core::mem::size_of::<u32>() as u32;
+        if words > size {
+            warn!("{words} words after the trigger exceed the ETF size, using {size} words");
+        }
+        self.component
+            .write_reg(self.interface, REGISTER_OFFSET_TRG, words.min(size))?;
+        Ok(())
+    }
+
+    /// Get the current number of bytes within the FIFO.
+    ///
+    /// # Note
+    /// This will always return zero if the capture is disabled.
+    pub fn fill_level(&mut self) -> Result<u32, EtfError> {
+        let level = self
+            .component
+            .read_reg(self.interface, REGISTER_OFFSET_CBUFLVL)?;
+        Ok(level * core::mem::size_of::<u32>() as u32)
+    }
+
+    /// Get the fill level of the FIFO in percent of its size.
+    pub fn fill_percent(&mut self) -> Result<u8, EtfError> {
+        let size = self.fifo_size()?;
+        let level = self.fill_level()?;
+        Ok((level as u64 * 100 / size as u64) as u8)
+    }
+
+    /// Get the number of bytes that can still be captured before the FIFO is full.
+    ///
+    /// # Note
+    /// Like [Self::fill_level], this is only meaningful while the capture is enabled.
+    pub fn free_space(&mut self) -> Result<u32, EtfError> {
+        let size = self.fifo_size()?;
+        Ok(size.saturating_sub(self.fill_level()?))
+    }
+
+    /// Use the ETF as a hardware FIFO that drains to the TPIU, e.g. for an external trace
+    /// capture device, see [Mode::Hardware].
+    ///
+    /// The capture is stopped, the mode set, the formatter enabled with trigger insertion (the
+    /// TPIU expects formatted frames) and the capture enabled again. The trace data is then no
+    /// longer available through RRD.
+    pub fn enable_hardware_fifo(&mut self) -> Result<(), EtfError> {
+        self.stop_capture()?;
+        self.set_mode(Mode::Hardware)?;
+        self.set_formatter(true)?;
+        self.set_trigger_insertion(true)?;
+        self.enable_capture()
+    }
+
+    /// Check if the formatter is enabled, i.e. the trace data is wrapped in formatter frames.
+    pub fn formatter_enabled(&mut self) -> Result<bool, EtfError> {
+        let ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        Ok(ffcr.enft())
+    }
+
+    /// Enable or disable the formatter.
+    ///
+    /// # Note
+    /// Without the formatter, the trace data of all sources is stored without trace IDs and
+    /// can not be demultiplexed.
+    pub fn set_formatter(&mut self, enable: bool) -> Result<(), EtfError> {
+        let mut ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        ffcr.set_enft(enable);
+        ffcr.store(self.component, self.interface)?;
+        Ok(())
+    }
+
+    /// Configure the insertion of triggers into the formatted trace stream.
+    ///
+    /// # Args
+    /// * `insert` - Specified true if triggers should be indicated in the formatter frames.
+    ///   This requires the formatter, see [Self::set_formatter].
+    pub fn set_trigger_insertion(&mut self, insert: bool) -> Result<(), EtfError> {
+        let mut ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        ffcr.set_enti(insert);
+        ffcr.store(self.component, self.interface)?;
+        Ok(())
+    }
+
+    /// Read the formatter and flush control register, e.g. to check the effective
+    /// configuration.
+    pub fn format_flush_control(&mut self) -> Result<FormatFlushControl, EtfError> {
+        Ok(FormatFlushControl::load(self.component, self.interface)?)
+    }
+
+    /// Configure the capture stop-on-flush semantics.
+    ///
+    /// # Args
+    /// * `stop` - Specified true if the capture should stop on flush events.
+    pub fn stop_on_flush(&mut self, stop: bool) -> Result<(), EtfError> {
+        let mut ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        ffcr.set_stoponfl(stop);
+        ffcr.store(self.component, self.interface)?;
+        Ok(())
+    }
+
+    /// Configure flushing on trigger events.
+    ///
+    /// # Args
+    /// * `flush` - Specified true if a flush should be generated when a trigger event occurs.
+    ///
+    /// # Note
+    /// Together with [Self::stop_on_flush] this stops the capture on a trigger event once all
+    /// trace data preceding the trigger has been flushed into the FIFO.
+    pub fn set_flush_on_trigger_event(&mut self, flush: bool) -> Result<(), EtfError> {
+        let mut ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        ffcr.set_fontrgev(flush);
+        ffcr.store(self.component, self.interface)?;
+        Ok(())
+    }
+
+    /// Configure trigger insertion on flush completion.
+    ///
+    /// # Args
+    /// * `trigger` - Specified true if a trigger should be inserted into the trace stream when a
+    ///   flush completes.
+    ///
+    /// # Note
+    /// When combined with [Self::stop_on_flush], the trigger marks the end of the captured data
+    /// in the stream.
+    pub fn set_trigger_on_flush(&mut self, trigger: bool) -> Result<(), EtfError> {
+        let mut ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        ffcr.set_trigonfl(trigger);
+        ffcr.store(self.component, self.interface)?;
+        Ok(())
+    }
+
+    /// Generate a manual flush event.
+    pub fn manual_flush(&mut self) -> Result<(), EtfError> {
+        let mut ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        ffcr.set_flushman(true);
+        ffcr.store(self.component, self.interface)?;
+        Ok(())
+    }
+
+    /// Get the size of the FIFO in bytes.
+    ///
+    /// # Note
+    /// The size is read from the device once and cached afterwards. A size of zero, as read from a
+    /// TMC that does not implement RSZ or an ETR without a buffer, is an error.
+    pub fn fifo_size(&mut self) -> Result<u32, EtfError> {
+        if let Some(size) = self.fifo_size {
+            return Ok(size);
+        }
+        let size_words = self
+            .component
+            .read_reg(self.interface, REGISTER_OFFSET_RSZ)?;
+        if size_words == 0 {
+            return Err(EtfError::UnsupportedRegister {
+                register: "ETF_RSZ",
+                value: size_words,
+            });
+        }
+        let size = size_words * core::mem::size_of::<u32>() as u32;
+        self.fifo_size = Some(size);
+        Ok(size)
+    }
+
+    /// Get the configuration of the TMC.
+    ///
+    /// # Returns
+    /// `None` for a reserved configuration type.
+    pub fn configuration(&mut self) -> Result<Option<Configuration>, EtfError> {
+        let devid = self
+            .component
+            .read_reg(self.interface, REGISTER_OFFSET_DEVID)?;
+        Ok(match (devid >> 6) & 0b11 {
+            0b00 => Some(Configuration::Etb),
+            0b01 => Some(Configuration::Etr),
+            0b10 => Some(Configuration::Etf),
+            _ => None,
+        })
+    }
+
+    /// Read all known registers and describe them.
+    ///
+    /// # Returns
+    /// A labeled block of lines with the raw register values and the decoded bitfields.
+    pub fn dump_registers(&mut self) -> Result<String, EtfError> {
+        let mut lines = vec![format!(
+            "ETF registers at {:#010x}:",
+            self.component.component.id().component_address()
+        )];
+        for (name, offset) in [
+            ("ETF_RSZ", REGISTER_OFFSET_RSZ),
+            ("ETF_CTL", REGISTER_OFFSET_CTL),
+            ("ETF_CBUFLVL", REGISTER_OFFSET_CBUFLVL),
+            ("ETF_RRP", REGISTER_OFFSET_RRP),
+            ("ETF_RWP", REGISTER_OFFSET_RWP),
+            ("ETF_TRG", REGISTER_OFFSET_TRG),
+            ("ETF_DEVID", REGISTER_OFFSET_DEVID),
+        ] {
+            let value = self.component.read_reg(self.interface, offset)?;
+            lines.push(format!("  {name:<12} {value:#010x}"));
+        }
+        let status = self.status()?;
+        lines.push(format!(
+            "  {:<12} {:#010x} {status:?}",
+            Status::NAME,
+            status.0
+        ));
+        let mode = EtfMode::load(self.component, self.interface)?;
+        lines.push(format!("  {:<12} {:#010x} {mode:?}", EtfMode::NAME, mode.0));
+        let ffcr = FormatFlushControl::load(self.component, self.interface)?;
+        lines.push(format!(
+            "  {:<12} {:#010x} {ffcr:?}",
+            FormatFlushControl::NAME,
+            ffcr.0
+        ));
+        Ok(lines.join("\n"))
+    }
+
+    /// Limit a requested capture window to the FIFO size.
+    ///
+    /// # Args
+    /// * `bytes` - The number of bytes the capture is expected to retain.
+    ///
+    /// # Note
+    /// In circular mode only the most recent [Self::fifo_size] bytes are retained and older data
+    /// is overwritten. A warning is emitted if the requested window does not fit.
+    ///
+    /// # Returns
+    /// The window clamped to the FIFO size.
+    pub fn clamp_window(&mut self, bytes: u32) -> Result<u32, EtfError> {
+        let size = self.fifo_size()?;
+        if bytes > size {
+            warn!(
+                "Requested capture window of {bytes} bytes exceeds the FIFO size of {size} bytes, \
+                 older data will be overwritten"
+            );
+        }
+        Ok(bytes.min(size))
+    }
+}
+
+/// An iterator over the words drained from the FIFO, see [EmbeddedTraceFifo::words].
+pub struct Drain<'f, 'a> {
+    fifo: &'f mut EmbeddedTraceFifo<'a>,
+    /// Size of the FIFO in words.
+    size: usize,
+    /// Number of words read.
+    words: usize,
+    /// The wait before the next read of the empty FIFO.
+    delay: Duration,
+    /// The FIFO was found drained after the capture stopped.
+    stopped: bool,
+    done: bool,
+}
+
+impl Iterator for Drain<'_, '_> {
+    type Item = Result<u32, EtfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        const WORD: usize = core::mem::size_of::<u32>();
+        while !self.done {
+            let frame_boundary = (self.words * WORD).is_multiple_of(FRAME_SIZE);
+            if frame_boundary && self.words >= self.size {
+                break;
+            }
+            match self.fifo.read() {
+                Ok(Some(word)) => {
+                    self.words += 1;
+                    self.delay = self.fifo.backoff.initial;
+                    return Some(Ok(word));
+                }
+                Ok(None) if frame_boundary || self.stopped => break,
+                Ok(None) => match self.fifo.drained() {
+                    // Read once more in case the last data arrived since the empty read.
+                    Ok(true) => self.stopped = true,
+                    Ok(false) => {
+                        std::thread::sleep(self.delay);
+                        self.delay = (self.delay * 2).min(self.fifo.backoff.max);
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        if self.stopped && !(self.words * WORD).is_multiple_of(FRAME_SIZE) {
+            debug!(
+                "The capture stopped within a formatter frame after {} words",
+                self.words
+            );
+        }
+        self.done = true;
+        None
+    }
+}
+
+bitfield! {
+    /// The formatter and flush control register.
+    #[derive(Clone)]
+    pub struct FormatFlushControl(u32);
+    impl Debug;
+
+    pub drainbuf, set_drainbuf: 14;
+    pub stpontrgev, set_stpontrgev: 13;
+    pub stoponfl, set_stoponfl: 12;
+    pub trigonfl, set_trigonfl: 10;
+    pub trgontrgev, set_trgontrgev: 9;
+    pub flushman, set_flushman: 6;
+    pub fontrgev, set_fontrgev: 5;
+    pub fonflin, set_fonflin: 4;
+    pub enti, set_enti: 1;
+    pub enft, set_enft: 0;
+}
+
+impl From<u32> for FormatFlushControl {
+    fn from(raw: u32) -> FormatFlushControl {
+        FormatFlushControl(raw)
+    }
+}
+
+impl From<FormatFlushControl> for u32 {
+    fn from(reg: FormatFlushControl) -> u32 {
+        reg.0
+    }
+}
+
+impl DebugRegister for FormatFlushControl {
+    const ADDRESS: u32 = 0x304;
+    const NAME: &'static str = "ETF_FFCR";
+}
+
+bitfield! {
+    /// The status register.
+    #[derive(Clone)]
+    pub struct Status(u32);
+    impl Debug;
+
+    pub empty, _: 4;
+    pub ftempty, _: 3;
+    pub ready, _: 2;
+    pub trigd, _: 1;
+    pub full, _: 0;
+}
+
+impl Status {
+    /// Check if the capture has stopped and both the trace memory pipeline and the formatter
+    /// pipeline are empty.
+    pub fn drained(&self) -> bool {
+        self.ready() && self.ftempty()
+    }
+}
+
+impl From<u32> for Status {
+    fn from(raw: u32) -> Status {
+        Status(raw)
+    }
+}
+
+/// The window of a circular buffer read, see [EmbeddedTraceFifo::read_circular].
+///
+/// The oldest data of a wrapped buffer is at the write pointer, so the window ends at the write
+/// pointer and, as the read pointer wraps at the end of the trace RAM, reading from its start
+/// yields the data oldest first. The window is rounded down to whole formatter frames and so
+/// begins on a frame boundary, but not necessarily on a trace packet boundary.
+///
+/// # Args
+/// * `size` - Size of the trace RAM in bytes, an empty window if zero.
+/// * `write_pointer` - The RAM write pointer, see [EmbeddedTraceFifo::write_pointer].
+/// * `wrapped` - The write pointer has wrapped, i.e. all of the trace RAM holds trace data.
+/// * `words` - Only the most recent words. Clamped to the RAM size.
+///
+/// # Returns
+/// The byte offset of the start of the window, the read pointer to read from, and its length in
+/// bytes.
+pub fn circular_window(
+    size: u32,
+    write_pointer: u32,
+    wrapped: bool,
+    words: Option<u32>,
+) -> (u32, u32) {
+    let mut len = if wrapped { size } else { write_pointer };
+    if let Some(words) = words {
+        len = len.min(words.saturating_mul(core::mem::size_of::<u32>() as u32));
+    }
+    len -= len % FRAME_SIZE as u32;
+    if size == 0 {
+        return (0, 0);
+    }
+    ((write_pointer + size - len) % size, len)
+}
+
+/// Remove the [EMPTY] sentinels from words read out of the FIFO.
+///
+/// Data may arrive after the FIFO ran empty during a block transfer, so all the sentinels are
+/// removed, not just a trailing run of them.
+///
+/// # Returns
+/// The number of trace data words, moved in order to the start of `buf`.
+pub fn remove_empty(buf: &mut [u32]) -> usize {
+    let mut count = 0;
+    for i in 0..buf.len() {
+        if buf[i] != EMPTY {
+            buf[count] = buf[i];
+            count += 1;
+        }
+    }
+    count
+}
+
+impl From<Status> for u32 {
+    fn from(reg: Status) -> u32 {
+        reg.0
+    }
+}
+
+impl DebugRegister for Status {
+    const ADDRESS: u32 = 0xC;
+    const NAME: &'static str = "ETF_STS";
+}
+
+bitfield! {
+    /// The mode register.
+    #[derive(Clone)]
+    pub struct EtfMode(u32);
+    impl Debug;
+
+    // The Mode register configures the operational mode of the FIFO.
+    pub u8, mode, set_mode: 1, 0;
+}
+
+impl From<u32> for EtfMode {
+    fn from(raw: u32) -> EtfMode {
+        EtfMode(raw)
+    }
+}
+
+impl From<EtfMode> for u32 {
+    fn from(reg: EtfMode) -> u32 {
+        reg.0
+    }
+}
+
+impl DebugRegister for EtfMode {
+    const ADDRESS: u32 = 0x28;
+    const NAME: &'static str = "ETF_MODE";
+}
@@ -0,0 +1,232 @@
+//! Embedded Trace Router driver
+//!
+//! The ETR is a CoreSight Trace Memory Controller (TMC) that writes the trace data to a buffer in
+//! system memory of the target, e.g. SRAM or DRAM, instead of trace memory of its own. The buffer
+//! can be much larger than the trace memory of an ETF.
+//!
+//! The ETR has the registers of the ETF, see [EmbeddedTraceFifo], and in addition the base
+//! address of the buffer and the AXI bus configuration. The buffer is used as a circular buffer:
+//! the capture is stopped with [EmbeddedTraceRouter::flush_and_stop], the data is read from
+//! system memory in the order of [EmbeddedTraceRouter::regions] and the capture restarted with
+//! [EmbeddedTraceRouter::configure].
+use crate::{
+    etf::{DebugRegister, EmbeddedTraceFifo, Mode},
+    EtfError,
+};
+use bitfield::bitfield;
+use probe_rs::architecture::arm::{memory::CoresightComponent, ArmProbeInterface};
+use std::time::Duration;
+
+const REGISTER_OFFSET_RSZ: u32 = 0x04;
+const REGISTER_OFFSET_STS: u32 = 0x0C;
+const REGISTER_OFFSET_RWP: u32 = 0x18;
+const REGISTER_OFFSET_RWPHI: u32 = 0x3C;
+const REGISTER_OFFSET_DBALO: u32 = 0x118;
+const REGISTER_OFFSET_DBAHI: u32 = 0x11C;
+
+/// The buffer in system memory an ETR writes the trace data to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EtrBuffer {
+    /// Address of the buffer in the system memory map.
+    pub address: u64,
+    /// Size of the buffer in bytes.
+    pub len: u32,
+}
+
+impl std::str::FromStr for EtrBuffer {
+    type Err = String;
+
+    /// Parse from `<address>:<len>`, both decimal or hex with `0x`, e.g. `0x30000000:0x40000`.
+    /// The address has to be word aligned and the size a multiple of a formatter frame.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, len) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected `<address>:<len>`, got `{s}`"))?;
+        let number = |s: &str| match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        let address = number(address).map_err(|e| format!("invalid address `{address}`: {e}"))?;
+        let len = number(len)
+            .ok()
+            .and_then(|len| u32::try_from(len).ok())
+            .ok_or_else(|| format!("invalid size `{len}`"))?;
+        if !address.is_multiple_of(4) {
+            return Err(format!("the address {address:#x} is not word aligned"));
+        }
+        if len == 0 || !len.is_multiple_of(crate::formatter::FRAME_SIZE as u32) {
+            return Err(format!(
+                "the size {len} is no multiple of the {} byte formatter frames",
+                crate::formatter::FRAME_SIZE
+            ));
+        }
+        Ok(Self { address, len })
+    }
+}
+
+impl EtrBuffer {
+    /// The regions of the buffer that hold trace data, oldest first.
+    ///
+    /// # Args
+    /// * `write_pointer` - The address the next trace data is written to, see
+    ///   [EmbeddedTraceRouter::write_pointer].
+    /// * `wrapped` - The write pointer has wrapped, i.e. all of the buffer holds trace data.
+    ///
+    /// # Returns
+    /// The address and length in bytes of each region, to be read in this order.
+    pub fn regions(&self, write_pointer: u64, wrapped: bool) -> Vec<(u64, u32)> {
+        let end = self.address + self.len as u64;
+        let write_pointer = write_pointer.clamp(self.address, end);
+        let written = (write_pointer - self.address) as u32;
+        let mut regions = vec![];
+        if wrapped && written < self.len {
+            // The oldest data is at the write pointer.
+            regions.push((write_pointer, self.len - written));
+        }
+        if written > 0 {
+            regions.push((self.address, written));
+        }
+        regions
+    }
+}
+
+/// The embedded trace router.
+pub struct EmbeddedTraceRouter<'a> {
+    component: &'a CoresightComponent,
+    interface: &'a mut dyn ArmProbeInterface,
+    timeout: Duration,
+}
+
+impl<'a> EmbeddedTraceRouter<'a> {
+    /// Construct a new embedded trace router controller.
+    pub fn new(
+        interface: &'a mut dyn ArmProbeInterface,
+        component: &'a CoresightComponent,
+    ) -> Self {
+        Self {
+            component,
+            interface,
+            timeout: crate::etf::STOP_TIMEOUT,
+        }
+    }
+
+    /// Configure the maximum time to wait for the capture to stop, see
+    /// [EmbeddedTraceFifo::set_timeout].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The base address of the ETR.
+    pub fn address(&self) -> u64 {
+        self.component.component.id().component_address()
+    }
+
+    /// The registers the ETR shares with the ETF.
+    pub fn tmc(&mut self) -> EmbeddedTraceFifo<'_> {
+        let mut tmc = EmbeddedTraceFifo::new(&mut *self.interface, self.component);
+        tmc.set_timeout(self.timeout);
+        tmc
+    }
+
+    /// Stop the capture and set up a circular buffer capture into `buffer`, starting empty.
+    ///
+    /// The formatter is enabled with trigger insertion, the buffer accessed with contiguous
+    /// transfers instead of a scatter gather table. The capture has to be enabled afterwards,
+    /// see [EmbeddedTraceFifo::enable_capture].
+    pub fn configure(&mut self, buffer: EtrBuffer) -> Result<(), EtfError> {
+        let mut tmc = self.tmc();
+        tmc.stop_capture()?;
+        tmc.set_mode(Mode::Circular)?;
+        tmc.set_formatter(true)?;
+        tmc.set_trigger_insertion(true)?;
+
+        let mut axictl = AxiControl::load(self.component, self.interface)?;
+        axictl.set_scattergather(false);
+        axictl.store(self.component, self.interface)?;
+        let axictl = AxiControl::load(self.component, self.interface)?;
+        if axictl.scattergather() {
+            return Err(EtfError::UnsupportedRegister {
+                register: AxiControl::NAME,
+                value: axictl.0,
+            });
+        }
+
+        let words = buffer.len / core::mem::size_of::<u32>() as u32;
+        self.write(REGISTER_OFFSET_RSZ, words)?;
+        let readback = self.read(REGISTER_OFFSET_RSZ)?;
+        if readback != words {
+            return Err(EtfError::UnsupportedRegister {
+                register: "ETR_RSZ",
+                value: readback,
+            });
+        }
+        let (low, high) = (buffer.address as u32, (buffer.address >> 32) as u32);
+        self.write(REGISTER_OFFSET_DBALO, low)?;
+        self.write(REGISTER_OFFSET_DBAHI, high)?;
+        self.write(REGISTER_OFFSET_RWP, low)?;
+        self.write(REGISTER_OFFSET_RWPHI, high)?;
+        // Clear the full flag of a previous capture.
+        self.write(REGISTER_OFFSET_STS, 0)
+    }
+
+    /// Flush the formatter and stop the capture, see [EmbeddedTraceFifo::flush_and_stop].
+    pub fn flush_and_stop(&mut self) -> Result<(), EtfError> {
+        self.tmc().flush_and_stop()
+    }
+
+    /// Get the address in system memory the next trace data word will be written to.
+    pub fn write_pointer(&mut self) -> Result<u64, EtfError> {
+        let low = self.read(REGISTER_OFFSET_RWP)?;
+        let high = self.read(REGISTER_OFFSET_RWPHI)?;
+        Ok((high as u64) << 32 | low as u64)
+    }
+
+    /// The regions of a stopped capture into `buffer` that hold trace data, oldest first, see
+    /// [EtrBuffer::regions].
+    ///
+    /// # Returns
+    /// The regions and whether the buffer has wrapped, i.e. the oldest data was overwritten.
+    pub fn regions(&mut self, buffer: &EtrBuffer) -> Result<(Vec<(u64, u32)>, bool), EtfError> {
+        let write_pointer = self.write_pointer()?;
+        // In circular mode the full flag indicates that the write pointer has wrapped.
+        let wrapped = self.tmc().full()?;
+        Ok((buffer.regions(write_pointer, wrapped), wrapped))
+    }
+
+    fn read(&mut self, offset: u32) -> Result<u32, EtfError> {
+        Ok(self.component.read_reg(self.interface, offset)?)
+    }
+
+    fn write(&mut self, offset: u32, value: u32) -> Result<(), EtfError> {
+        Ok(self.component.write_reg(self.interface, offset, value)?)
+    }
+}
+
+bitfield! {
+    /// The AXI control register.
+    #[derive(Clone)]
+    pub struct AxiControl(u32);
+    impl Debug;
+
+    pub wrburstlen, set_wrburstlen: 11, 8;
+    pub scattergather, set_scattergather: 7;
+    pub cachectrl, set_cachectrl: 5, 2;
+    pub protctrl, set_protctrl: 1, 0;
+}
+
+impl From<u32> for AxiControl {
+    fn from(raw: u32) -> AxiControl {
+        AxiControl(raw)
+    }
+}
+
+impl From<AxiControl> for u32 {
+    fn from(reg: AxiControl) -> u32 {
+        reg.0
+    }
+}
+
+impl DebugRegister for AxiControl {
+    const ADDRESS: u32 = 0x110;
+    const NAME: &'static str = "ETR_AXICTL";
+}
@@ -0,0 +1,245 @@
+//! CoreSight trace formatter
+//!
+//! The formatter of the ETF multiplexes the trace data of all sources into 16 byte frames. Each
+//! byte of a frame either carries data of the current source or switches to another source,
+//! identified by its trace ID (ATID).
+//!
+//! In continuous mode the formatter also inserts synchronization sequences: full frame syncs
+//! between frames and halfword syncs within frames. Both carry no data and are removed.
+//!
+//! The trace IDs from 0x70 are reserved. The formatter uses them for its own bytes, e.g. 0x7D for
+//! a trigger. These bytes are not trace data and are removed as well.
+use log::debug;
+use std::collections::BTreeMap;
+
+/// Size of a formatter frame in bytes.
+pub const FRAME_SIZE: usize = 16;
+
+/// Trace ID of the ITM as configured by probe-rs.
+pub const ITM_ID: u8 = 13;
+
+/// Trace ID that marks bytes without data.
+const NULL_ID: u8 = 0;
+
+/// The reserved trace IDs.
+const RESERVED_IDS: std::ops::RangeInclusive<u8> = 0x70..=0x7F;
+
+/// Trace ID of the bytes the formatter inserts on a trigger.
+const TRIGGER_ID: u8 = 0x7D;
+
+/// Full frame synchronization sequence, inserted between frames.
+const FRAME_SYNC: [u8; 4] = [0xFF, 0xFF, 0xFF, 0x7F];
+
+/// Halfword synchronization sequence, inserted at an even position of a frame.
+const HALFWORD_SYNC: [u8; 2] = [0xFF, 0x7F];
+
+/// Extract the trace data of one source from formatted frames.
+///
+/// # Args
+/// * `data` - Formatter frames, starting on a frame boundary.
+/// * `id` - Trace ID of the source to extract.
+///
+/// # Note
+/// Synchronization sequences, padding (data of the null ID) and the data of the reserved IDs,
+/// e.g. trigger bytes, are removed. A trailing partial
+/// frame, e.g. after a manual flush, can not be decoded without its auxiliary byte and is
+/// ignored.
+pub fn deformat(data: &[u8], id: u8) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut current = NULL_ID;
+    demultiplex(data, &mut current, |source, byte| {
+        if source == id {
+            out.push(byte);
+        }
+    });
+    out
+}
+
+/// Wrap the trace data of one source in formatter frames, the inverse of [deformat].
+///
+/// Each frame starts with the trace ID of the source. The last frame is padded with data of the
+/// null ID.
+pub fn format(data: &[u8], id: u8) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in data.chunks(FRAME_SIZE - 2) {
+        let mut frame = [0; FRAME_SIZE];
+        frame[0] = (id << 1) | 1;
+        let mut aux = 0;
+        let mut put = |frame: &mut [u8; FRAME_SIZE], i: usize, byte: u8| {
+            if i.is_multiple_of(2) {
+                // Even bytes carry their LSB in the auxiliary byte.
+                aux |= (byte & 1) << (i / 2);
+                frame[i] = byte & !1;
+            } else {
+                frame[i] = byte;
+            }
+        };
+        let len = chunk.len();
+        if len % 2 == 1 || len == FRAME_SIZE - 2 {
+            for (i, &byte) in chunk.iter().enumerate() {
+                put(&mut frame, i + 1, byte);
+            }
+            if len < FRAME_SIZE - 2 {
+                frame[len + 1] = (NULL_ID << 1) | 1;
+            }
+        } else {
+            // A new ID can only be set at an even byte. The last data byte follows the switch to
+            // the null ID, with the auxiliary bit assigning it to the previous ID.
+            for (i, &byte) in chunk[..len - 1].iter().enumerate() {
+                put(&mut frame, i + 1, byte);
+            }
+            frame[len] = (NULL_ID << 1) | 1;
+            aux |= 1 << (len / 2);
+            frame[len + 1] = chunk[len - 1];
+        }
+        frame[FRAME_SIZE - 1] = aux;
+        out.extend(frame);
+    }
+    out
+}
+
+/// Count the data bytes of each source in formatted frames.
+///
+/// # Returns
+/// The number of data bytes by trace ID, without the null and reserved IDs. See [deformat] for
+/// the handling of the frames.
+pub fn sources(data: &[u8]) -> BTreeMap<u8, usize> {
+    let mut sources = BTreeMap::new();
+    let mut current = NULL_ID;
+    demultiplex(data, &mut current, |source, _| {
+        *sources.entry(source).or_default() += 1;
+    });
+    sources
+}
+
+/// Demultiplex formatted frames that continue the frames of a previous read.
+///
+/// The formatter only emits a trace ID when the source changes. The data at the start of a read
+/// therefore belongs to the source at the end of the previous read, which [deformat] and
+/// [sources] do not know: they drop it as data without a source. A trailing partial frame is
+/// ignored, see [Deformatter] for reads that do not end on a frame boundary.
+///
+/// # Args
+/// * `data` - Formatter frames, starting on a frame boundary.
+/// * `current` - The trace ID at the end of the previous read, zero before the first read.
+///   Updated to the trace ID at the end of `data`.
+///
+/// # Returns
+/// The data bytes by trace ID, without the null and reserved IDs.
+pub fn split(data: &[u8], current: &mut u8) -> BTreeMap<u8, Vec<u8>> {
+    let mut sources = BTreeMap::<_, Vec<_>>::new();
+    demultiplex(data, current, |source, byte| {
+        sources.entry(source).or_default().push(byte);
+    });
+    sources
+}
+
+/// Demultiplex formatted frames received in arbitrary chunks, e.g. as read from a probe.
+///
+/// This keeps the trace ID across chunks like [split] and, in addition, keeps a partial frame at
+/// the end of a chunk until the rest of it arrives with the next chunk.
+#[derive(Clone, Debug, Default)]
+pub struct Deformatter {
+    /// The trace ID at the end of the data pushed so far.
+    current: u8,
+    /// The start of a frame that is not complete yet.
+    partial: Vec<u8>,
+}
+
+impl Deformatter {
+    /// Construct a deformatter for a stream starting on a frame boundary.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Demultiplex the next chunk of the stream.
+    ///
+    /// # Returns
+    /// The data bytes of the complete frames by trace ID, without the null and reserved IDs.
+    pub fn push(&mut self, data: &[u8]) -> BTreeMap<u8, Vec<u8>> {
+        let mut sources = BTreeMap::<_, Vec<_>>::new();
+        let mut f = |source, byte| sources.entry(source).or_default().push(byte);
+        if self.partial.is_empty() {
+            let used = demultiplex(data, &mut self.current, &mut f);
+            self.partial.extend_from_slice(&data[used..]);
+        } else {
+            self.partial.extend_from_slice(data);
+            let used = demultiplex(&self.partial, &mut self.current, &mut f);
+            self.partial.drain(..used);
+        }
+        sources
+    }
+
+    /// The number of bytes of a partial frame kept for the next [Self::push].
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+/// Demultiplex formatted frames, calling `f` with the trace ID and value of each data byte.
+///
+/// Bytes of the null and reserved IDs are dropped. `current` is the trace ID at the start of the
+/// data, updated to the ID at its end.
+///
+/// # Returns
+/// The number of bytes demultiplexed, all but a trailing partial frame.
+fn demultiplex(data: &[u8], current: &mut u8, mut f: impl FnMut(u8, u8)) -> usize {
+    let mut f = |source, byte| {
+        if source == TRIGGER_ID {
+            debug!("Dropping a trigger byte {byte:#04x}");
+        } else if source != NULL_ID && !RESERVED_IDS.contains(&source) {
+            f(source, byte);
+        }
+    };
+    let mut rest = data;
+    loop {
+        while let Some(after) = rest.strip_prefix(&FRAME_SYNC) {
+            rest = after;
+        }
+        if rest.len() < FRAME_SIZE {
+            if !rest.is_empty() {
+                debug!("Partial formatter frame of {} bytes at the end", rest.len());
+            }
+            return data.len() - rest.len();
+        }
+        let (frame, after) = rest.split_at(FRAME_SIZE);
+        deformat_frame(frame, current, &mut f);
+        rest = after;
+    }
+}
+
+/// Demultiplex the bytes of a single frame.
+///
+/// # Args
+/// * `frame` - The frame of [FRAME_SIZE] bytes.
+/// * `id` - The trace ID at the start of the frame, updated to the ID at its end.
+/// * `f` - Called with the trace ID and value of each data byte.
+fn deformat_frame(frame: &[u8], id: &mut u8, mut f: impl FnMut(u8, u8)) {
+    // See the CoreSight Architecture Specification, section D4.2 "Formatter protocol".
+    let aux = frame[FRAME_SIZE - 1];
+    let mut i = 0;
+    while i < FRAME_SIZE - 1 {
+        let byte = frame[i];
+        let lsb = (aux >> (i / 2)) & 1;
+        if i % 2 == 1 {
+            // Odd bytes always contain data of the current source.
+            f(*id, byte);
+        } else if frame[i..FRAME_SIZE - 1].starts_with(&HALFWORD_SYNC) {
+            // A halfword sync neither carries data nor changes the source.
+            i += 1;
+        } else if byte & 1 == 0 {
+            // Even bytes with a cleared LSB contain data, the LSB is in the auxiliary byte.
+            f(*id, byte | lsb);
+        } else {
+            // Even bytes with a set LSB switch to a new source. The auxiliary bit selects whether
+            // the following data byte still belongs to the previous source.
+            let new = byte >> 1;
+            if i + 1 < FRAME_SIZE - 1 {
+                f(if lsb == 1 { *id } else { new }, frame[i + 1]);
+            }
+            *id = new;
+            i += 1;
+        }
+        i += 1;
+    }
+}
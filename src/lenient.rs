@@ -0,0 +1,152 @@
+//! Removal of packet types the decoder does not model
+//!
+//! The ITM/DWT packet framing is self describing: the header of every packet determines its
+//! length, either through a payload size field or through continuation bits. This allows
+//! skipping packets of reserved or unknown types without understanding them, so that such
+//! packets do not derail the decoding of the rest of the trace.
+use log::warn;
+use std::io::Read;
+
+/// Hardware source packet discriminator IDs that the decoder knows.
+fn known_hardware_disc(disc_id: u8) -> bool {
+    matches!(disc_id, 0..=2 | 8..=23)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    /// The next byte is a packet header.
+    Header,
+    /// Within the zeros of a synchronization packet.
+    Sync,
+    /// Within the payload of a packet that uses continuation bits.
+    Continuation { drop: bool },
+    /// Within the payload of a packet of a fixed size.
+    Payload { remaining: usize, drop: bool },
+}
+
+/// A [Read] adapter that removes packets of unknown or reserved types from the trace stream.
+///
+/// Removed packets are logged with their raw bytes. Everything else, including data that is not
+/// validly framed, is passed through to the decoder.
+pub(crate) struct UnknownPacketFilter<R> {
+    reader: R,
+    enabled: bool,
+    state: State,
+    /// The raw bytes of the packet being removed.
+    packet: Vec<u8>,
+    /// Number of removed packets.
+    pub removed: usize,
+}
+
+impl<R: Read> UnknownPacketFilter<R> {
+    /// Construct a filter. If not `enabled`, all data is passed through.
+    pub fn new(reader: R, enabled: bool) -> Self {
+        Self {
+            reader,
+            enabled,
+            state: State::Header,
+            packet: vec![],
+            removed: 0,
+        }
+    }
+
+    /// Classify a header byte.
+    ///
+    /// # Returns
+    /// The state for the payload of the packet and whether the packet is removed.
+    fn header(header: u8) -> (State, bool) {
+        let continuation = header & 0x80 != 0;
+        let more = |drop| {
+            if continuation {
+                State::Continuation { drop }
+            } else {
+                State::Header
+            }
+        };
+        match header {
+            0x00 => (State::Sync, false),
+            // Source packets with a payload of 1, 2 or 4 bytes.
+            _ if header & 0b11 != 0 => {
+                let remaining = [1, 2, 4][(header & 0b11) as usize - 1];
+                let drop = header & 0b100 != 0 && !known_hardware_disc(header >> 3);
+                (State::Payload { remaining, drop }, drop)
+            }
+            // Overflow and local timestamps.
+            0x70 => (State::Header, false),
+            _ if header & 0x8F == 0x00 => (State::Header, false),
+            _ if header & 0xCF == 0xC0 => (more(false), false),
+            // Global timestamps.
+            0x94 | 0xB4 => (more(false), false),
+            // Extension packets. Only single byte stimulus port page extensions are known.
+            _ if header & 0x0F == 0x08 && !continuation => (State::Header, false),
+            _ if header & 0x0B == 0x08 => (more(true), true),
+            // Reserved protocol packets.
+            _ if header & 0x0F == 0x04 => (more(true), true),
+            // Not validly framed, leave it to the decoder to report.
+            _ => (State::Header, false),
+        }
+    }
+
+    /// Process a byte and return whether it is kept.
+    fn keep(&mut self, byte: u8) -> bool {
+        let drop = match self.state {
+            State::Header => {
+                let (state, drop) = Self::header(byte);
+                self.state = state;
+                drop
+            }
+            State::Sync => {
+                if byte != 0x00 {
+                    self.state = State::Header;
+                }
+                false
+            }
+            State::Continuation { drop } => {
+                if byte & 0x80 == 0 {
+                    self.state = State::Header;
+                }
+                drop
+            }
+            State::Payload { remaining, drop } => {
+                self.state = match remaining - 1 {
+                    0 => State::Header,
+                    remaining => State::Payload { remaining, drop },
+                };
+                drop
+            }
+        };
+        if drop {
+            self.packet.push(byte);
+            if self.state == State::Header {
+                warn!("Ignoring unknown packet {:02x?}", self.packet);
+                self.packet.clear();
+                self.removed += 1;
+            }
+        }
+        !drop
+    }
+}
+
+impl<R: Read> Read for UnknownPacketFilter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.enabled {
+            return self.reader.read(buf);
+        }
+        loop {
+            let len = self.reader.read(buf)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut kept = 0;
+            for i in 0..len {
+                if self.keep(buf[i]) {
+                    buf[kept] = buf[i];
+                    kept += 1;
+                }
+            }
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
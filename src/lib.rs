@@ -0,0 +1,561 @@
+//! ITM/DWT trace capture and decoding using the Embedded Trace FIFO
+//!
+//! This library contains the parts of `etf-trace` that do not depend on the command line
+//! interface, so that captured trace data can be decoded and inspected by other tools.
+//!
+//! The [etf] module is a driver of the Embedded Trace FIFO for probe-rs, [capture] builds a
+//! complete capture on top of it. Captures larger than the ETF go through the Embedded Trace
+//! Router of the [etr] module into system memory.
+use builtin::BuiltinDecoder;
+use itm::{MalformedPacket, Timestamp, TimestampDataRelation, TracePacket};
+use lenient::UnknownPacketFilter;
+use log::warn;
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+mod builtin;
+pub mod capture;
+pub mod dwt;
+mod error;
+pub mod etf;
+pub mod etr;
+pub mod formatter;
+mod lenient;
+pub mod output;
+#[cfg(feature = "plot")]
+pub mod plot;
+pub mod report;
+pub mod resume;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stream;
+pub mod symbols;
+
+pub use capture::{capture_to_file, CaptureOptions};
+pub use error::EtfError;
+pub use etf::{EmbeddedTraceFifo, Mode};
+
+/// Behavior of the decoder when it encounters a malformed packet.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnError {
+    /// Stop decoding and return the error.
+    Abort,
+    /// Log the error and continue decoding with the next packet.
+    Skip,
+    /// Log the error and discard the data up to the next synchronization packet, where the
+    /// decoder is aligned to the packets again.
+    #[default]
+    Resync,
+}
+
+/// A class of packets, to select the malformed packets that are tolerated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PacketClass {
+    /// Hardware source packets: DWT event counters, exception trace, PC samples and data trace.
+    Hardware,
+    /// Software source (instrumentation) packets.
+    Software,
+    /// Timestamp packets.
+    Timestamp,
+    /// Synchronization packets.
+    Sync,
+    /// Packets with an invalid header.
+    Header,
+}
+
+impl PacketClass {
+    /// The class of the packet a decoder error occurred in.
+    pub fn of(error: &MalformedPacket) -> Self {
+        match error {
+            MalformedPacket::InvalidHardwarePacket { .. }
+            | MalformedPacket::InvalidHardwareDisc { .. }
+            | MalformedPacket::InvalidExceptionTrace { .. }
+            | MalformedPacket::InvalidPCSampleSize { .. } => Self::Hardware,
+            MalformedPacket::InvalidSourcePayload { header, .. } if header & 0b100 != 0 => {
+                Self::Hardware
+            }
+            MalformedPacket::InvalidSourcePayload { .. } => Self::Software,
+            MalformedPacket::InvalidGTS2Size { .. } => Self::Timestamp,
+            MalformedPacket::InvalidSync(_) => Self::Sync,
+            MalformedPacket::InvalidHeader(_) => Self::Header,
+        }
+    }
+}
+
+/// The packet decoder implementation.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Decoder {
+    /// The decoder of the `itm` crate, which supports all packet types.
+    #[default]
+    Itm,
+    /// A minimal decoder of synchronization, overflow, local timestamp, instrumentation and
+    /// exception trace packets. All other packets are skipped and counted as unknown, so it never
+    /// fails on a malformed packet.
+    Builtin,
+}
+
+/// Trace decoder configuration.
+#[derive(Clone, Debug)]
+pub struct DecodeOptions {
+    /// Frequency of the ITM timestamp clock in Hz.
+    pub coreclk: u32,
+    /// Behavior on malformed packets.
+    pub on_error: OnError,
+    /// Skip malformed packets of these classes like with [OnError::Skip], regardless of
+    /// `on_error`.
+    pub tolerate: Vec<PacketClass>,
+    /// Use global timestamp packets to correct the time reconstructed from local timestamps.
+    ///
+    /// If disabled, global timestamp packets are passed through like any other packet.
+    pub global_timestamps: bool,
+    /// Remove packets of reserved or unknown types (e.g. extension packets other than stimulus
+    /// port pages, unknown hardware sources) based on their framing before decoding.
+    ///
+    /// Without this, such packets are malformed and their payload is decoded as packets.
+    pub lenient: bool,
+    /// The packet decoder implementation.
+    pub decoder: Decoder,
+    /// Wait for more data at the end of the input instead of ending the decoding, e.g. to decode
+    /// a dump while it is being written.
+    ///
+    /// The decoding then only ends on an error. Without this, a trailing partial packet, e.g. of
+    /// a capture ending after a manual flush, ends the decoding without an error.
+    pub ignore_eof: bool,
+    /// Counters updated while decoding, e.g. to report the progress from another thread.
+    pub progress: Option<Arc<Progress>>,
+    /// Report every packet on its own, with its sequence index in nanoseconds as the time, e.g.
+    /// for traces without local timestamps. Timestamp packets are then reported like any other
+    /// packet.
+    pub sequence: bool,
+}
+
+impl Default for DecodeOptions {
+    /// The defaults of [OnError] and [Decoder], a 1 MHz timestamp clock, i.e. one tick per
+    /// microsecond, and all options disabled.
+    fn default() -> Self {
+        Self {
+            coreclk: 1_000_000,
+            on_error: OnError::default(),
+            tolerate: vec![],
+            global_timestamps: false,
+            lenient: false,
+            decoder: Decoder::default(),
+            ignore_eof: false,
+            progress: None,
+            sequence: false,
+        }
+    }
+}
+
+/// Counters of a running decoding, see [DecodeOptions::progress].
+///
+/// The final counts are also part of the [DecodeStats].
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// Number of packets decoded, other than the timestamps.
+    pub packets: AtomicUsize,
+    /// Number of malformed packets that were skipped.
+    pub skipped: AtomicUsize,
+}
+
+/// Number of stimulus ports of a page, see [TracePacket::Extension].
+const STIMULUS_PAGE_PORTS: u8 = 32;
+
+/// The valid bits of a stimulus port page, the ITM has at most 256 stimulus ports.
+const STIMULUS_PAGE_MASK: u8 = 0b111;
+
+/// Number of global timestamp bits carried by a GTS1 packet.
+const GTS1_BITS: u32 = 26;
+
+/// Reconstruction of the trace time from local and global timestamps.
+struct TraceClock {
+    frequency: u32,
+    current: Duration,
+    prev: Duration,
+    gts_lower: Option<u64>,
+    gts_upper: Option<u64>,
+}
+
+impl TraceClock {
+    fn new(frequency: u32) -> Self {
+        Self {
+            frequency,
+            current: Duration::ZERO,
+            prev: Duration::ZERO,
+            gts_lower: None,
+            gts_upper: None,
+        }
+    }
+
+    /// Convert timestamp clock ticks to a duration.
+    ///
+    /// This rounds up so as to not report an event before it occurred.
+    fn duration(&self, ticks: u64) -> Duration {
+        Duration::from_nanos((ticks as f64 / self.frequency as f64 * 1e9).ceil() as u64)
+    }
+
+    /// Advance the clock by a local timestamp delta.
+    fn local(&mut self, ticks: u64, relation: TimestampDataRelation) -> Timestamp {
+        self.current += self.duration(ticks);
+        let (prev, curr) = (self.prev, self.current);
+        self.prev = self.current;
+        match relation {
+            TimestampDataRelation::Sync => Timestamp::Sync(curr),
+            TimestampDataRelation::UnknownDelay => Timestamp::UnknownDelay { prev, curr },
+            TimestampDataRelation::AssocEventDelay => Timestamp::AssocEventDelay(curr),
+            TimestampDataRelation::UnknownAssocEventDelay => {
+                Timestamp::UnknownAssocEventDelay { prev, curr }
+            }
+        }
+    }
+
+    /// Update the lower bits of the global timestamp.
+    fn global1(&mut self, ticks: u64, wrap: bool, clkch: bool) {
+        self.gts_lower = Some(ticks);
+        if wrap {
+            // The upper bits have changed, a GTS2 packet follows.
+            self.gts_upper = None;
+        } else if clkch {
+            // The timestamp clock ratio has changed, a full global timestamp follows.
+            self.gts_lower = None;
+            self.gts_upper = None;
+        } else {
+            self.apply_global();
+        }
+    }
+
+    /// Update the upper bits of the global timestamp.
+    fn global2(&mut self, ticks: u64) {
+        self.gts_upper = Some(ticks);
+        self.apply_global();
+    }
+
+    /// Move the clock forward to a complete global timestamp.
+    ///
+    /// The global timestamp is never allowed to move the clock backwards. Local timestamps are
+    /// rounded up and may run slightly ahead, while lost local timestamps (e.g. on overflow) make
+    /// the clock fall behind until the next global timestamp.
+    fn apply_global(&mut self) {
+        if let (Some(lower), Some(upper)) = (self.gts_lower, self.gts_upper) {
+            let global = self.duration((upper << GTS1_BITS) | lower);
+            self.current = self.current.max(global);
+        }
+    }
+}
+
+/// Minimum number of local timestamps between two global timestamps for [ClockCheck].
+const CLOCK_CHECK_TIMESTAMPS: usize = 16;
+
+/// Relative deviation of the local from the global timestamp rate that [ClockCheck] reports.
+const CLOCK_CHECK_TOLERANCE: f64 = 0.05;
+
+/// A consistency check of the timestamp clock against the global timestamps of a trace.
+///
+/// The trace time is reconstructed from the local timestamps, in ticks of the timestamp clock,
+/// and moved forward by the global timestamps, in ticks of the same frequency (see
+/// [DecodeOptions::global_timestamps]). If the local timestamps advance at a different rate than
+/// the global timestamps between two of them, the timestamp clock frequency is likely wrong, e.g.
+/// the local timestamp prescaler was not taken into account. This is a heuristic: it assumes the
+/// global timestamp clock is the frequency given for decoding.
+#[derive(Clone, Debug, Default)]
+pub struct ClockCheck {
+    gts_lower: Option<u64>,
+    gts_upper: Option<u64>,
+    /// The first complete global timestamp of the measurement.
+    first: Option<u64>,
+    /// The last complete global timestamp of the measurement, with the local timestamp ticks
+    /// and the number of local timestamps up to it.
+    last: Option<(u64, u64, usize)>,
+    /// Local timestamp ticks since the first global timestamp.
+    ticks: u64,
+    /// Number of local timestamps since the first global timestamp.
+    timestamps: usize,
+}
+
+impl ClockCheck {
+    /// Account for a decoded packet.
+    pub fn add(&mut self, packet: &TracePacket) {
+        match *packet {
+            TracePacket::LocalTimestamp1 { ts, .. } => self.local(ts.into()),
+            TracePacket::LocalTimestamp2 { ts } => self.local(ts.into()),
+            TracePacket::GlobalTimestamp1 { ts, wrap, clkch } => {
+                self.gts_lower = Some(ts);
+                if clkch {
+                    // The clock ratio has changed, the measurement starts over.
+                    *self = Self::default();
+                } else if wrap {
+                    self.gts_upper = None;
+                } else {
+                    self.global();
+                }
+            }
+            TracePacket::GlobalTimestamp2 { ts } => {
+                self.gts_upper = Some(ts);
+                self.global();
+            }
+            // Local timestamps may have been lost.
+            TracePacket::Overflow => *self = Self::default(),
+            _ => {}
+        }
+    }
+
+    fn local(&mut self, ticks: u64) {
+        if self.first.is_some() {
+            self.ticks += ticks;
+            self.timestamps += 1;
+        }
+    }
+
+    fn global(&mut self) {
+        let (Some(lower), Some(upper)) = (self.gts_lower, self.gts_upper) else {
+            return;
+        };
+        let global = (upper << GTS1_BITS) | lower;
+        match self.first {
+            None => self.first = Some(global),
+            Some(_) => self.last = Some((global, self.ticks, self.timestamps)),
+        }
+    }
+
+    /// The rate of the local timestamps relative to the global timestamps.
+    ///
+    /// # Returns
+    /// `None` if there are not enough timestamps to tell.
+    pub fn ratio(&self) -> Option<f64> {
+        let first = self.first?;
+        let (last, ticks, timestamps) = self.last?;
+        if timestamps < CLOCK_CHECK_TIMESTAMPS || last <= first {
+            return None;
+        }
+        Some(ticks as f64 / (last - first) as f64)
+    }
+
+    /// Check the timestamp clock frequency used for decoding.
+    ///
+    /// # Returns
+    /// The frequency in Hz that matches the rate of the local timestamps, if it deviates from
+    /// `frequency` by more than 5%.
+    pub fn suggest(&self, frequency: u32) -> Option<u32> {
+        let ratio = self.ratio()?;
+        if (ratio - 1.0).abs() <= CLOCK_CHECK_TOLERANCE {
+            return None;
+        }
+        Some((frequency as f64 * ratio).round() as u32)
+    }
+}
+
+/// Statistics of a decoded trace stream.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Number of malformed packets that were skipped.
+    pub skipped: usize,
+    /// Number of times the decoder discarded data until the next synchronization packet, after
+    /// an overflow or with [OnError::Resync].
+    pub resyncs: usize,
+    /// Number of packets of unknown type that were removed with [DecodeOptions::lenient].
+    pub unknown: usize,
+    /// The timestamp clock frequency in Hz the global timestamps suggest, if it deviates from
+    /// [DecodeOptions::coreclk], see [ClockCheck].
+    pub clock_suggestion: Option<u32>,
+}
+
+/// Decode an ITM/DWT trace stream.
+///
+/// # Args
+/// * `reader` - The source of the raw ITM trace data.
+/// * `options` - The decoder configuration.
+/// * `f` - Called for every set of timestamped packets in the stream.
+///
+/// # Note
+/// Packets are grouped by the local timestamp that follows them. Packets after the final local
+/// timestamp of the stream are not reported. The reported timestamps are monotonic.
+///
+/// A malformed packet following an overflow packet is taken as a loss of alignment: all data up to
+/// the next synchronization packet is discarded, regardless of [DecodeOptions::on_error].
+///
+/// The stimulus port page of extension packets is applied to the following instrumentation
+/// packets: their port is the port number within the page plus 32 times the page.
+pub fn decode_trace<R: Read>(
+    reader: R,
+    options: &DecodeOptions,
+    f: impl FnMut(itm::TimestampedTracePackets),
+) -> Result<DecodeStats, EtfError> {
+    let mut filter = UnknownPacketFilter::new(reader, options.lenient);
+    let mut stats = match options.decoder {
+        Decoder::Itm => {
+            let decoder = itm::Decoder::new(
+                &mut filter,
+                itm::DecoderOptions {
+                    ignore_eof: options.ignore_eof,
+                },
+            );
+            decode_packets(decoder.singles(), options, f)?
+        }
+        Decoder::Builtin => {
+            let mut decoder = BuiltinDecoder::new(&mut filter, options.ignore_eof);
+            let mut stats = decode_packets(&mut decoder, options, f)?;
+            stats.unknown += decoder.skipped;
+            stats
+        }
+    };
+    stats.unknown += filter.removed;
+    Ok(stats)
+}
+
+/// Reconstruct the timestamps of a sequence of decoded packets, see [decode_trace].
+fn decode_packets(
+    decoder: impl Iterator<Item = Result<TracePacket, itm::DecoderError>>,
+    options: &DecodeOptions,
+    mut f: impl FnMut(itm::TimestampedTracePackets),
+) -> Result<DecodeStats, EtfError> {
+    let mut clock = TraceClock::new(options.coreclk);
+    let mut check = ClockCheck::default();
+    let mut packets = vec![];
+    let mut consumed_packets = 0;
+    let mut stats = DecodeStats::default();
+    // An overflow was seen since the last synchronization packet.
+    let mut overflow = false;
+    // Data is being discarded until the next synchronization packet.
+    let mut resync = false;
+    // The stimulus port page of the last extension packet.
+    let mut page = 0;
+    // Index of the next packet in sequence mode.
+    let mut sequence = 0;
+    for packet in decoder {
+        consumed_packets += 1;
+        let packet = match packet {
+            Err(itm::DecoderError::MalformedPacket(e)) if overflow || resync => {
+                if !resync {
+                    warn!("Malformed packet after overflow ({e}), resynchronizing");
+                    stats.resyncs += 1;
+                    resync = true;
+                }
+                continue;
+            }
+            Err(itm::DecoderError::MalformedPacket(e))
+                if options.on_error == OnError::Skip
+                    || options.tolerate.contains(&PacketClass::of(&e)) =>
+            {
+                warn!("Skipping malformed packet: {e}");
+                stats.skipped += 1;
+                if let Some(progress) = &options.progress {
+                    progress.skipped.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            Err(itm::DecoderError::MalformedPacket(e)) if options.on_error == OnError::Resync => {
+                warn!("Malformed packet ({e}), resynchronizing");
+                stats.resyncs += 1;
+                resync = true;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+            Ok(packet) => packet,
+        };
+        if resync && packet != TracePacket::Sync {
+            continue;
+        }
+        check.add(&packet);
+        let timestamp = match packet {
+            TracePacket::LocalTimestamp1 { ts, data_relation } if !options.sequence => {
+                clock.local(ts.into(), data_relation)
+            }
+            TracePacket::LocalTimestamp2 { ts } if !options.sequence => {
+                clock.local(ts.into(), TimestampDataRelation::Sync)
+            }
+            TracePacket::GlobalTimestamp1 { ts, wrap, clkch }
+                if options.global_timestamps && !options.sequence =>
+            {
+                clock.global1(ts, wrap, clkch);
+                continue;
+            }
+            TracePacket::GlobalTimestamp2 { ts }
+                if options.global_timestamps && !options.sequence =>
+            {
+                clock.global2(ts);
+                continue;
+            }
+            mut packet => {
+                match &mut packet {
+                    TracePacket::Sync => (overflow, resync) = (false, false),
+                    TracePacket::Overflow => overflow = true,
+                    TracePacket::Extension { page: p } => page = *p & STIMULUS_PAGE_MASK,
+                    TracePacket::Instrumentation { port, .. } => {
+                        *port += page * STIMULUS_PAGE_PORTS
+                    }
+                    _ => {}
+                }
+                if let Some(progress) = &options.progress {
+                    progress.packets.fetch_add(1, Ordering::Relaxed);
+                }
+                packets.push(packet);
+                if !options.sequence {
+                    continue;
+                }
+                sequence += 1;
+                Timestamp::Sync(Duration::from_nanos(sequence - 1))
+            }
+        };
+        f(itm::TimestampedTracePackets {
+            timestamp,
+            packets: std::mem::take(&mut packets),
+            malformed_packets: vec![],
+            consumed_packets: std::mem::take(&mut consumed_packets),
+        });
+    }
+    stats.clock_suggestion = check.suggest(options.coreclk).filter(|_| !options.sequence);
+    if let Some(frequency) = stats.clock_suggestion {
+        warn!(
+            "The local timestamps advance {:.3} times as fast as the global timestamps, \
+             the timestamp clock may be {frequency} Hz instead of {} Hz",
+            check.ratio().unwrap_or_default(),
+            options.coreclk
+        );
+    }
+    Ok(stats)
+}
+
+/// A [Read] implementation over chunks of trace data received from a channel.
+///
+/// Reads block until more data arrives. The end of the stream is reached once all senders
+/// have been dropped. This allows decoding trace data while it is still being captured.
+pub struct ChannelReader {
+    receiver: std::sync::mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    /// Construct a reader from the receiving end of a channel.
+    pub fn new(receiver: std::sync::mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
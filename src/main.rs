@@ -24,59 +24,2480 @@
 //! then read out from the device via the debug probe.
 use anyhow::Context;
 use clap::Parser;
-use log::info;
-use probe_rs::{architecture::arm::component::TraceSink, Error, Probe};
-use std::io::Write;
+use etf_trace::{
+    capture::{self, is_disconnect, CaptureOptions, CaptureSession, RawTrace, TraceState},
+    decode_trace,
+    dwt::DwtSummary,
+    etf::{Backoff, EmbeddedTraceFifo, STOP_TIMEOUT},
+    etr::EtrBuffer,
+    formatter::{self, ITM_ID},
+    output::{
+        self, BinaryPort, BinaryRecords, Color, Console, CycleClock, Format, Marker, MarkerWindow,
+        PacketType, RecordFormat, Summary, TimestampDeltas,
+    },
+    report::{self, CaptureReport, DumpHeader},
+    resume::{ResumeState, SEAM},
+    stream::{Pacer, Watermarks},
+    symbols::{Symbols, TraceUsage},
+    ChannelReader, DecodeOptions, DecodeStats, Decoder, EtfError, OnError, PacketClass, Progress,
+};
+use itm::{
+    cortex_m::{Exception, VectActive},
+    ExceptionAction, Timestamp, TracePacket,
+};
+use log::{debug, info, warn};
+use probe_rs::{architecture::arm::DpAddress, Probe, Session, WireProtocol};
+use std::{
+    io::{BufWriter, IsTerminal, LineWriter, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+#[cfg(feature = "plot")]
+use etf_trace::plot::FillPlot;
+#[cfg(feature = "sqlite")]
+use etf_trace::sqlite::Database;
+
+/// Number of fill level samples shown by `--plot`.
+#[cfg(feature = "plot")]
+const PLOT_WIDTH: usize = 60;
 
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
+    /// Check the deformatting and decoding of a built-in trace, without a probe.
+    #[clap(long, exclusive = true)]
+    self_test: bool,
+    /// List the connected debug probes and exit, the same as the `probes` command.
+    #[clap(long, exclusive = true)]
+    list_probes: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Capture trace data from a target, store and decode it.
+    Capture(CaptureArgs),
+    /// Decode a previously captured trace dump.
+    Decode(DecodeArgs),
+    /// List the connected debug probes.
+    Probes(ProbesArgs),
+    /// Print the CoreSight components of a target.
+    Topology(TopologyArgs),
+    /// Capture continuously and send the decoded packets as NDJSON to TCP clients.
+    Serve(ServeArgs),
+    /// Measure the throughput of draining the ETF with single register and block reads.
+    Bench(BenchArgs),
+}
+
+/// Probe and target selection.
+#[derive(clap::Args)]
+struct ProbeArgs {
     #[clap(short, long, default_value = "STM32H743ZITx")]
     target: String,
+    /// Debug probe clock speed in kHz. Not all probes support arbitrary speeds; the closest
+    /// supported speed is used. Lower speeds can help with unreliable register accesses.
+    #[clap(long)]
+    speed: Option<u32>,
+    /// Debug probe wire protocol. Defaults to the probe's default (usually SWD).
+    #[clap(long, value_enum)]
+    protocol: Option<Protocol>,
+    /// The debug probe to use, see the `probes` command: its serial number (or a unique prefix of
+    /// it), `VID:PID[:Serial]` with the IDs in hex, or its index. Defaults to the first probe.
+    #[clap(long)]
+    probe: Option<String>,
+    /// Wait up to this many seconds for a (matching) probe to be connected, e.g. while the
+    /// board powers up, instead of failing immediately.
+    #[clap(long, default_value_t = 0)]
+    wait_for_probe: u64,
+    /// Retry attaching to the target (and setting up the capture) this many times if the
+    /// communication with the probe or target fails, e.g. on a cold target or a flaky USB
+    /// connection. The wait between the attempts doubles from 100 ms up to 2 s.
+    #[clap(long, default_value_t = 0)]
+    connect_retries: u32,
+    /// Hold the target in reset while attaching, to get control of a target that spins in a
+    /// tight loop or disables the debug port.
+    #[clap(long)]
+    connect_under_reset: bool,
+}
+
+#[derive(clap::Args)]
+struct ProbesArgs {
+    /// Print the probes as a JSON array of `{index, identifier, vid, pid, serial}` objects.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct TopologyArgs {
+    #[clap(flatten)]
+    probe: ProbeArgs,
+    /// Also print all ETF registers with their decoded fields, e.g. for a bug report.
+    #[clap(long)]
+    dump_fifo_config: bool,
+}
+
+/// Trace decoding and presentation.
+#[derive(clap::Args)]
+struct DecoderArgs {
+    /// Core clock frequency in Hz. Defaults to a typical value for the target family.
+    ///
+    /// This is also taken as the reference clock of the ITM timestamps. A capture divides it by
+    /// the timestamp prescaler found on the target. When decoding a dump, this is the timestamp
+    /// clock and defaults to the `coreclk` recorded in the sidecar file of the dump.
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    coreclk: Option<u32>,
+    /// Behavior on malformed packets.
+    #[clap(long, value_enum, default_value_t)]
+    on_error: OnError,
+    /// Skip malformed packets of these classes even with `--on-error abort`, e.g. `hardware` to
+    /// still fail on corrupt instrumentation packets.
+    #[clap(long, value_enum, value_delimiter = ',')]
+    tolerate: Vec<PacketClass>,
+    /// Output format of the decoded packets. Defaults to `log`, except for a capture to an
+    /// `--output` file with an extension that implies a format: `.json` and `.ndjson` for json,
+    /// `.sql` for sql, `.csv` for csv, `.vcd` for vcd. The decoded packets are then written to
+    /// that file instead of the raw trace. Any other extension, e.g. `.dump` or `.bin`, stores
+    /// the raw trace.
+    #[clap(long, value_enum)]
+    format: Option<Format>,
+    /// The SQLite database file the packets of `--format sqlite` are written to. It is created
+    /// if it does not exist, the packets are added to those of earlier traces otherwise.
+    #[cfg(feature = "sqlite")]
+    #[clap(long)]
+    database: Option<String>,
+    /// Correct the local timestamp time base with global timestamp packets. This keeps
+    /// timestamps accurate over long captures where local timestamps were lost.
+    #[clap(long, conflicts_with = "no_timestamps")]
+    global_timestamps: bool,
+    /// Decode a trace without timestamps, e.g. from firmware that disables them for throughput.
+    /// Every packet is reported on its own, in order, with its sequence index in place of the
+    /// time: the printed times count packets, not nanoseconds. `--relative-time` and
+    /// `--timestamp-offset` therefore require `--cycles` for the time.
+    #[clap(long)]
+    no_timestamps: bool,
+    /// The ITM local timestamp prescaler the firmware configured (TSPrescale of ITM_TCR): the
+    /// timestamp clock is the core clock divided by it. `off` is the same as `--no-timestamps`.
+    /// Defaults to the prescaler found on the target, or 1 when decoding a dump. This overrides
+    /// the timestamp clock recorded in the sidecar file of a dump.
+    #[clap(long, value_enum, conflicts_with = "no_timestamps")]
+    lts_prescaler: Option<LtsPrescaler>,
+    /// Expect malformed packets, e.g. in a capture that does not start on a packet boundary:
+    /// skip them instead of failing, the same as `--on-error skip`.
+    #[clap(long, conflicts_with = "on_error")]
+    expect_malformed: bool,
+    /// Fail on the first malformed packet instead of resynchronizing, the same as
+    /// `--on-error abort`.
+    #[clap(long, conflicts_with_all = ["on_error", "expect_malformed"])]
+    strict: bool,
+    /// Ignore packets of reserved or unknown types, e.g. from newer ITM/DWT revisions, instead
+    /// of failing on them. They are logged with their raw bytes.
+    #[clap(long)]
+    lenient: bool,
+    /// The packet decoder. The built-in decoder is a fallback for traces the default decoder
+    /// fails on: it only decodes the common packet types and skips all others.
+    #[clap(long, value_enum, default_value_t)]
+    decoder: Decoder,
+    /// Print a single pretty-printed JSON array of packets instead of one object per line.
+    /// Implies `--format json`.
+    #[clap(long)]
+    json_pretty: bool,
+    /// Only print DWT data trace events, i.e. reads and writes of the variables watched by the
+    /// DWT comparators. The comparators have to be configured by the firmware.
+    #[clap(long)]
+    watch_var: bool,
+    /// Only print the text the firmware writes to this stimulus port, decoded as UTF-8, as it
+    /// arrives: a console over the debug probe. All other packets are not printed.
+    #[clap(long, conflicts_with_all = ["watch_var", "binary_port", "summary_only", "json_pretty"])]
+    console: Option<u8>,
+    /// Firmware ELF file used to name the functions in `--watch-var` events and of the PC
+    /// samples of `--dwt-summary` and `--profile`. A capture also warns if the firmware does not
+    /// appear to use the trace units it relies on.
+    #[clap(long)]
+    elf: Option<String>,
+    /// A free-form label of the capture, e.g. the firmware build. It is recorded in the capture
+    /// report and in the header of the table and SQL formats.
+    #[clap(long)]
+    label: Option<String>,
+    /// Print times relative to the first decoded timestamp of the capture instead of the
+    /// absolute trace time.
+    #[clap(long)]
+    relative_time: bool,
+    /// Add this offset in nanoseconds to all printed times, e.g. to align the capture with an
+    /// external clock. With `--relative-time`, the first timestamp is printed at the offset.
+    #[clap(long, default_value_t = 0)]
+    timestamp_offset: u64,
+    /// Take the printed times from the DWT cycle counter (CYCCNT) values that the firmware
+    /// writes as 32 bit values to this stimulus port, instead of from the ITM timestamps. The
+    /// cycles since the first value are converted with the core clock. The counter values
+    /// themselves are printed as the instrumentation packets of the port.
+    #[clap(long)]
+    cycles: Option<u8>,
+    /// Only print the instrumentation packets of these stimulus ports, e.g. `0,2` or
+    /// `--port 0 --port 2`. All other packets, e.g. timestamps and overflows, are printed.
+    #[clap(long, visible_alias = "port", value_delimiter = ',')]
+    ports: Vec<u8>,
+    /// Only print the packets of these types, e.g. `exception,overflow`. The number of packets
+    /// left out is reported at the end. This applies after `--ports`.
+    #[clap(long, value_enum, value_delimiter = ',')]
+    only_types: Vec<PacketType>,
+    /// Reassemble the byte stream of a stimulus port into fixed-length binary records, as
+    /// `<port>:<record-len>`, e.g. for packed structs written by the firmware. The records are
+    /// printed as hex, or with `--binary-format`, instead of the instrumentation packets of the
+    /// port.
+    #[clap(long, conflicts_with = "watch_var")]
+    binary_port: Option<BinaryPort>,
+    /// The format of the `--binary-port` records: `{u8}`, `{u16}`, `{u32}`, `{u64}`, the
+    /// signed `{i8}` to `{i64}`, `{f32}` and `{f64}` are replaced by consecutive little endian
+    /// values of the record, `{hex}` by the remaining bytes, e.g. `x={i16} y={i16}`.
+    #[clap(long, requires = "binary_port")]
+    binary_format: Option<RecordFormat>,
+    /// Color the packets of the table format by their type: exceptions red, timestamps dim.
+    /// `auto` colors only if the output is a terminal.
+    #[clap(long, value_enum, default_value_t)]
+    color: Color,
+    /// Decode the trace but only print the summary statistics, not the packets.
+    #[clap(long)]
+    summary_only: bool,
+    /// After decoding, print a profile of the DWT packets: the most sampled program counters
+    /// (with their symbols, see `--elf`), the exception events by exception number and the
+    /// event counter wraps. It follows the packets of the table format, for the other formats
+    /// it is printed to stderr.
+    #[clap(long, visible_alias = "summary")]
+    dwt_summary: bool,
+    /// After decoding, write the PC samples to this file as a profile in the folded (collapsed
+    /// stack) format, e.g. for `inferno-flamegraph`. With `--elf`, the samples are accounted
+    /// for by function, otherwise by program counter.
+    #[clap(long)]
+    profile: Option<String>,
+    /// Only print packets after an instrumentation packet with this value on this stimulus
+    /// port, as `<port>:<value>`.
+    #[clap(long)]
+    start_marker: Option<Marker>,
+    /// Stop printing packets at an instrumentation packet with this value on this stimulus
+    /// port, as `<port>:<value>`.
+    #[clap(long)]
+    stop_marker: Option<Marker>,
+    /// Compare the decoded packet sequence against this golden file (one JSON record per
+    /// packet) and fail on any difference. Timestamps are not compared by default.
+    #[clap(long)]
+    golden: Option<String>,
+    /// Write the decoded packet sequence to the `--golden` file instead of comparing.
+    #[clap(long, requires = "golden")]
+    update_golden: bool,
+    /// Include the timestamps in the golden file comparison.
+    #[clap(long, requires = "golden")]
+    golden_timestamps: bool,
+}
+
+#[derive(clap::Args)]
+struct BenchArgs {
+    #[clap(flatten)]
+    probe: ProbeArgs,
+    /// Number of drains of the full ETF with each read path.
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    rounds: u32,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// The address to listen on for clients. Clients receive the packets decoded after they
+    /// connected.
+    #[clap(long, default_value = "127.0.0.1:9000")]
+    listen: std::net::SocketAddr,
+    #[clap(flatten)]
+    capture: CaptureArgs,
+}
+
+#[derive(clap::Args)]
+struct CaptureArgs {
+    #[clap(flatten)]
+    probe: ProbeArgs,
+    #[clap(flatten)]
+    decoder: DecoderArgs,
+    /// The file the ITM trace data is written to, the input of the `decode` command. Without
+    /// any output file, the trace is only decoded.
     #[clap(short, long)]
-    output: String,
-    #[clap(short, long, default_value_t = 400_000_000)]
-    coreclk: u32,
+    output: Option<String>,
+    /// The file the data read from the ETF is written to, including the formatter frames and the
+    /// data of all trace sources.
+    #[clap(long)]
+    raw_output: Option<String>,
+    /// The file the decoded packets are written to instead of stdout, in the `--format` or the
+    /// format implied by the extension (see `--format`), json otherwise. With this, the
+    /// `--output` extension does not select a format.
+    #[clap(long)]
+    decoded_output: Option<String>,
+    /// Operational mode of the ETF.
+    #[clap(long, value_enum, default_value_t)]
+    mode: CaptureMode,
+    /// In circular mode, only read the most recent number of 32 bit words. This is clamped to
+    /// the FIFO size and rounded down to whole formatter frames.
+    #[clap(long)]
+    words: Option<u32>,
+    /// In circular mode, wait up to this many milliseconds for a trigger event of the ETF
+    /// before every read, to capture the trace leading up to the event. Without a trigger the
+    /// buffer is read anyway, with a warning. Use e.g. with `--flush-on-trigger`.
+    #[clap(long)]
+    trigger_timeout: Option<u64>,
+    /// In circular mode, keep capturing this many 32 bit words after a trigger event before the
+    /// ETF reports it, to place the trigger in the buffer. Clamped to the FIFO size. Use with
+    /// `--trigger-timeout` to read the buffer once they are captured.
+    #[clap(long)]
+    post_trigger: Option<u32>,
+    /// Capture through the Embedded Trace Router (ETR) into this buffer in system memory of the
+    /// target, as `<address>:<len>` in bytes, e.g. `0x30000000:0x40000`, for captures larger
+    /// than the ETF. The buffer must not be used by the firmware. Each read stops the capture,
+    /// reads the buffer and restarts it. Without an ETR, the ETF is used.
+    #[clap(long, conflicts_with_all = ["mode", "no_setup"])]
+    etr_buffer: Option<EtrBuffer>,
+    /// Do not configure tracing on the target, only enable the ETF capture and drain it. This
+    /// keeps a trace configuration made by the firmware or another tool. If the ITM, DWT, funnel
+    /// or formatter are not set up correctly (the ITM must use trace ID 13), the capture is
+    /// empty or fails to decode, and the ETF mode is used as found.
+    #[clap(long)]
+    no_setup: bool,
+    /// Initial wait in microseconds after the ETF ran empty within a formatter frame while
+    /// draining it, doubled for every further empty read. Zero reads again immediately. This
+    /// applies whenever this tool drains the ETF instead of probe-rs reading it: with
+    /// `--no-setup`, `--follow`, `--repeat`, `--raw-output` and a `--source` other than the ITM,
+    /// when the capture stops, and when the ETF is not the first TMC or probe-rs can not read it.
+    #[clap(long, default_value_t = Backoff::default().initial.as_micros() as u64)]
+    drain_backoff: u64,
+    /// Longest wait in microseconds between empty reads while draining the ETF.
+    #[clap(long, default_value_t = Backoff::default().max.as_micros() as u64)]
+    drain_backoff_max: u64,
+    /// Maximum time in milliseconds to wait for the target: for the ETF capture to stop and
+    /// the trace pipelines to drain (e.g. for a circular capture), and for the core to halt
+    /// with `--halt-compare`. Increase it for slow probes. The timeouts of the individual probe
+    /// transfers are fixed by probe-rs.
+    #[clap(long, default_value_t = STOP_TIMEOUT.as_millis() as u64)]
+    session_timeout: u64,
+    /// Log the ETF fill level and trace RAM pointers after the setup and after every capture.
+    #[clap(long)]
+    trace_regs: bool,
+    /// Trace ID of the trace source to decode, e.g. of the ITM of another core configured by
+    /// the firmware. The trace IDs found in the capture are logged. Defaults to the ITM set up
+    /// by this tool.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..0x70))]
+    source: Option<u8>,
+    /// Index of the core to trace, e.g. 1 for the Cortex-M4 of an STM32H745. On multi-core
+    /// targets the trace of each core usually enters its own port of the funnel, select it
+    /// with `--funnel-port`.
+    #[clap(long, default_value_t = 0, conflicts_with = "no_setup")]
+    core: usize,
+    /// Trace funnel input port of the ITM. Only this funnel input is routed to the ETF. The
+    /// default is to keep the routing of the target's trace setup.
+    #[clap(long, conflicts_with = "no_setup")]
+    funnel_port: Option<u8>,
+    /// Create the parent directory of the output file if it does not exist.
+    #[clap(long)]
+    mkdir: bool,
+    /// Start the ITM trace `--output` with a header that describes the capture: the tool
+    /// version, the time and the configuration of the capture as in the sidecar file. The
+    /// `decode` command reads it, so the dump can be decoded without the sidecar file.
+    #[clap(long, conflicts_with = "resume")]
+    with_header: bool,
+    /// Append the ITM trace to the `--output` dump instead of replacing it, to collect several
+    /// captures in one dump. Each appended capture starts with a synchronization and an overflow
+    /// packet, so that the decoder realigns at the boundary, which marks the trace data lost
+    /// between the captures. With `--with-header`, only a new dump gets a header. The sidecar
+    /// file then describes the whole dump with the configuration of the last capture.
+    #[clap(long, requires = "output", conflicts_with = "resume")]
+    append: bool,
+    /// Record the progress in this state file and, if it exists, append to the `--output` dump
+    /// of the previous run instead of replacing it. The trace data between the runs is lost, the
+    /// dump marks it with an overflow packet. The state only holds the size of the dump and the
+    /// number of resumptions: the ETF write pointer and the formatter frame position are not
+    /// saved, so the resumed run does not continue the ETF contents of the previous one and
+    /// only checks that the dump has not shrunk.
+    #[clap(long, requires = "output")]
+    resume: Option<String>,
+    /// Restore the trace configuration of the target (trace enable, DWT, ITM and ETF) to its
+    /// state before the capture when the capture ends. Otherwise the changes are reported.
+    #[clap(long)]
+    restore_on_exit: bool,
+    /// Flush the formatter when a trigger event occurs.
+    #[clap(long)]
+    flush_on_trigger: bool,
+    /// Insert a trigger into the trace stream when a flush completes.
+    #[clap(long)]
+    trigger_on_flush: bool,
+    /// Stop the capture when a flush completes, e.g. with `--flush-on-trigger` to end the capture
+    /// at a trigger event.
+    #[clap(long)]
+    stop_on_flush: bool,
+    /// Enable the ETF formatter in case the target does not enable it, so that the trace data
+    /// of the sources can be told apart.
+    #[clap(long)]
+    enable_formatting: bool,
+    /// Keep capturing and decode trace data as it arrives. On `--duration` or Ctrl-C the capture
+    /// is stopped and the remaining trace data decoded.
+    #[clap(long)]
+    follow: bool,
+    /// With `--follow`, stop after this many seconds: the formatter is flushed, the capture
+    /// stopped and the rest of the trace data read, so that the last packets are not lost.
+    /// With `--mode hardware`, stop the drain to the TPIU after this many seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    duration: Option<u64>,
+    /// With `--follow`, log the number of bytes drained, packets decoded and malformed packets
+    /// skipped so far and the ETF fill level every this many seconds.
+    #[clap(long, requires = "follow", value_parser = clap::value_parser!(u64).range(1..))]
+    stats_interval: Option<u64>,
+    /// Do not log the progress of the capture: the fill level of the ETF, the stalls of
+    /// `--follow` and the captures of `--repeat`. Only a summary of the stalls is logged.
+    #[clap(long, conflicts_with = "stats_interval")]
+    quiet: bool,
+    /// With `--follow`, defer draining until the ETF holds at least this many bytes, to batch
+    /// the reads. Data below this is still drained after the `--session-timeout`.
+    #[clap(long, default_value_t = 0)]
+    min_fill: u32,
+    /// With `--follow`, the ETF fill level in percent below which the ETF is polled less often.
+    #[clap(long, default_value_t = Watermarks::default().low)]
+    low_watermark: u8,
+    /// With `--follow`, the ETF fill level in percent from which the ETF is drained again
+    /// immediately and polled more often, so that the trace source does not stall.
+    #[clap(long, default_value_t = Watermarks::default().high)]
+    high_watermark: u8,
+    /// Number of consecutive captures to take without re-attaching the probe. Each capture
+    /// drains the data collected since the previous one and is appended to the output. The
+    /// captures form one continuous trace, e.g. to capture more than the FIFO size: the trace
+    /// source stalls while the ETF is full and continues once it is drained.
+    #[clap(long, default_value_t = 1, conflicts_with = "follow")]
+    repeat: usize,
+    /// Capture once with the core running and once while halting it periodically, and compare
+    /// the distributions of the time between timestamps. This shows how debugger interaction
+    /// perturbs the trace timing.
+    #[clap(long, conflicts_with_all = ["follow", "repeat"])]
+    halt_compare: bool,
+    /// Print the words read from the ETF (with the formatter frames, if they are not removed by
+    /// probe-rs) to stderr after every capture, with their index in read order.
+    #[clap(long, conflicts_with_all = ["follow", "halt_compare"])]
+    list_words: bool,
+    /// List the words of `--list-words` newest first, e.g. to see what happened last in a
+    /// circular capture. This only affects the listing, not the output or the decoding.
+    #[clap(long, requires = "list_words")]
+    reverse: bool,
+    /// Only measure the rate at which trace data enters the ETF during a short interval, without
+    /// reading or decoding it. This shows whether the trace data rate fits the ETF and the probe.
+    #[clap(long, conflicts_with_all = [
+        "output", "raw_output", "decoded_output", "follow", "repeat", "halt_compare"
+    ])]
+    count_only: bool,
+    /// Drain whatever is in the ETF to the `--output` file and exit, for the fastest readout of a
+    /// trace set up and running externally. There is no setup, check or decoding, and no
+    /// sidecar. The data is written as read, including any formatter frames.
+    #[clap(long, requires = "output", conflicts_with_all = [
+        "raw_output", "decoded_output", "follow", "repeat", "halt_compare", "count_only", "resume"
+    ])]
+    dump_raw_and_exit: bool,
+    /// Print the CoreSight components found on the target and the state of the funnels and
+    /// TMCs the capture would use, then exit without setting up or enabling a capture. This is
+    /// the output of the `topology` command.
+    #[clap(long, conflicts_with_all = [
+        "output", "raw_output", "decoded_output", "follow", "repeat", "dump_raw_and_exit"
+    ])]
+    inspect: bool,
+    /// Stop the capture once this many bytes of ITM trace data were captured, to bound the
+    /// output and the data buffered for decoding. The read reaching the limit is truncated to
+    /// it. In circular mode every repeated read counts with its whole window.
+    #[clap(long, conflicts_with = "halt_compare")]
+    max_bytes: Option<u64>,
+    /// Plot the ETF fill level on every poll as a sparkline on stderr. Nothing is drawn if stderr
+    /// is not a terminal.
+    #[cfg(feature = "plot")]
+    #[clap(long)]
+    plot: bool,
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("etf_trace=info"))
-        .init();
+#[derive(clap::Args)]
+struct DecodeArgs {
+    /// Target the trace was captured from, used for the default core clock if the dump has no
+    /// sidecar file. No probe is opened.
+    #[clap(short, long, default_value = "STM32H743ZITx")]
+    target: String,
+    #[clap(flatten)]
+    decoder: DecoderArgs,
+    /// The trace dump to decode.
+    input: String,
+    /// Wait for more data at the end of the dump instead of ending, to decode a dump while it is
+    /// being written, e.g. by `capture --follow`. Without this, a trailing partial packet ends
+    /// the decoding without an error.
+    #[clap(long)]
+    ignore_eof: bool,
+}
+
+/// Operational mode of the ETF during a capture.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+enum CaptureMode {
+    /// Use the ETF as a FIFO that stalls the trace when full and is drained by the probe.
+    #[default]
+    Software,
+    /// Use the ETF as a circular buffer that retains the most recent trace data.
+    Circular,
+    /// Use the ETF as an elastic buffer that drains to the TPIU, for an external trace capture
+    /// device on the trace port. Nothing is read over the debug port: the capture runs until
+    /// `--duration` or Ctrl-C. The TPIU and the trace port pins are not configured.
+    Hardware,
+}
+
+/// The ITM local timestamp prescaler, see `--lts-prescaler`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LtsPrescaler {
+    /// Local timestamps are disabled.
+    Off,
+    #[value(name = "1")]
+    Div1,
+    #[value(name = "4")]
+    Div4,
+    #[value(name = "16")]
+    Div16,
+    #[value(name = "64")]
+    Div64,
+}
+
+impl LtsPrescaler {
+    /// The divisor of the core clock, `None` if local timestamps are disabled.
+    fn divisor(self) -> Option<u32> {
+        match self {
+            Self::Off => None,
+            Self::Div1 => Some(1),
+            Self::Div4 => Some(4),
+            Self::Div16 => Some(16),
+            Self::Div64 => Some(64),
+        }
+    }
+}
+
+impl DecoderArgs {
+    /// The behavior on malformed packets, see `--expect-malformed` and `--strict`.
+    fn on_error(&self) -> OnError {
+        match (self.expect_malformed, self.strict) {
+            (true, _) => OnError::Skip,
+            (_, true) => OnError::Abort,
+            _ => self.on_error,
+        }
+    }
+
+    /// Decode without timestamps, see `--no-timestamps` and `--lts-prescaler off`.
+    fn sequence(&self) -> bool {
+        self.no_timestamps || self.lts_prescaler == Some(LtsPrescaler::Off)
+    }
+
+    /// The timestamp clock given by `--lts-prescaler` for the core clock `coreclk`.
+    fn timestamp_clock(&self, coreclk: u32) -> Option<u32> {
+        let divisor = self.lts_prescaler?.divisor()?;
+        Some(coreclk / divisor)
+    }
+}
+
+/// Debug probe wire protocol.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum Protocol {
+    Swd,
+    Jtag,
+}
+
+impl From<Protocol> for WireProtocol {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Swd => WireProtocol::Swd,
+            Protocol::Jtag => WireProtocol::Jtag,
+        }
+    }
+}
+
+/// Typical core clock frequencies (Hz) by target name prefix.
+///
+/// The first matching prefix wins, so more specific prefixes have to come first.
+const FAMILY_CORECLK: &[(&str, u32)] = &[
+    ("STM32H72", 550_000_000),
+    ("STM32H73", 550_000_000),
+    ("STM32H7", 400_000_000),
+    ("STM32F7", 216_000_000),
+    ("STM32F40", 168_000_000),
+    ("STM32F41", 168_000_000),
+    ("STM32F4", 180_000_000),
+    ("STM32F3", 72_000_000),
+    ("STM32F1", 72_000_000),
+    ("STM32G4", 170_000_000),
+    ("STM32L4", 80_000_000),
+    ("STM32L5", 110_000_000),
+    ("STM32U5", 160_000_000),
+    ("nRF52", 64_000_000),
+    ("nRF53", 128_000_000),
+    ("RP2040", 125_000_000),
+    ("ATSAME5", 120_000_000),
+];
+
+/// Core clock used when the target is not in [FAMILY_CORECLK].
+const DEFAULT_CORECLK: u32 = 400_000_000;
 
-    let cli = Args::parse();
+/// Look up the typical core clock for a target.
+fn family_coreclk(target: &str) -> Option<u32> {
+    let target = target.to_ascii_uppercase();
+    FAMILY_CORECLK
+        .iter()
+        .find(|(prefix, _)| target.starts_with(&prefix.to_ascii_uppercase()))
+        .map(|(_, clk)| *clk)
+}
+
+/// Resolve the core clock from the command line or the target family.
+fn coreclk(coreclk: Option<u32>, target: &str) -> u32 {
+    match coreclk {
+        Some(clk) => clk,
+        None => {
+            let clk = family_coreclk(target).unwrap_or_else(|| {
+                warn!("Unknown core clock for target {target}, assuming {DEFAULT_CORECLK} Hz");
+                DEFAULT_CORECLK
+            });
+            info!("Using core clock {clk} Hz, override with `--coreclk`");
+            clk
+        }
+    }
+}
 
-    let probe = Probe::list_all()
-        .get(0)
-        .ok_or(Error::UnableToOpenProbe("not found"))?
-        .open()?;
+/// Set by the SIGINT handler, see [catch_interrupt].
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    let mut session = probe.attach(cli.target, probe_rs::Permissions::default())?;
-    session.setup_tracing(0, TraceSink::TraceMemory)?;
+/// Catch the first SIGINT (Ctrl-C) so that a capture is stopped cleanly.
+///
+/// The loops reading the ETF check [interrupted]: they stop the capture, read the rest of the
+/// trace data and end with an error after the dumps and the report are written and the target
+/// is torn down. A second SIGINT terminates the process as usual.
+#[cfg(unix)]
+fn catch_interrupt() {
+    extern "C" fn handler(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        // SAFETY: Restoring the default disposition is async-signal-safe.
+        unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+    }
+    // SAFETY: The handler only does an atomic store and calls an async-signal-safe function.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handler as extern "C" fn(_) as libc::sighandler_t,
+        )
+    };
+}
 
-    let itm_trace = session.read_trace_data()?;
+/// Catch the first SIGINT (Ctrl-C) so that a capture is stopped cleanly. Not supported on this
+/// platform, an interrupt terminates the process.
+#[cfg(not(unix))]
+fn catch_interrupt() {}
 
+/// Whether the capture was interrupted, see [catch_interrupt].
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Open the first probe and attach to the target.
+fn attach(args: &ProbeArgs) -> anyhow::Result<Session> {
+    connect(args, Ok)
+}
+
+/// First wait between two connection attempts, see `--connect-retries`.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Longest wait between two connection attempts.
+const CONNECT_RETRY_DELAY_MAX: Duration = Duration::from_secs(2);
+
+/// Attach to the target and set up the session with `setup`, retrying if the communication
+/// with the probe or target fails, see `--connect-retries`.
+///
+/// Other errors, e.g. a target without an ETF, are not retried.
+fn connect<T>(
+    args: &ProbeArgs,
+    mut setup: impl FnMut(Session) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut delay = CONNECT_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let result = capture::attach(
+            args.probe.as_deref(),
+            Duration::from_secs(args.wait_for_probe),
+            &args.target,
+            args.protocol.map(Into::into),
+            args.speed,
+            args.connect_under_reset,
+        )
+        .map_err(anyhow::Error::from)
+        .and_then(&mut setup);
+        let transient = matches!(
+            result.as_ref().map_err(|e| e.downcast_ref::<EtfError>()),
+            Err(Some(EtfError::Probe(_)))
+        );
+        if attempt > args.connect_retries || !transient {
+            return result;
+        }
+        warn!(
+            "Connecting failed ({:#}), retrying in {delay:?} ({attempt} of {})",
+            result.err().unwrap(),
+            args.connect_retries
+        );
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(CONNECT_RETRY_DELAY_MAX);
+    }
+}
+
+/// Create (or truncate) an output file.
+///
+/// # Args
+/// * `path` - The file to create.
+/// * `mkdir` - Create missing parent directories instead of failing.
+fn create_output(path: &str, mkdir: bool) -> anyhow::Result<std::fs::File> {
+    output_dir(path, mkdir)?;
     std::fs::OpenOptions::new()
         .create(true)
         .write(true)
-        .open(cli.output)?
-        .write_all(&itm_trace)?;
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("Opening {path}"))
+}
 
-    // Parse ITM trace and print.
-    let decoder = itm::Decoder::new(
-        itm_trace.as_slice(),
-        itm::DecoderOptions { ignore_eof: false },
-    );
-    let timestamp_cfg = itm::TimestampsConfiguration {
-        clock_frequency: cli.coreclk,
-        lts_prescaler: itm::LocalTimestampOptions::Enabled,
-        expect_malformed: false,
+/// Open an output file to append to it, see `--append`, or create it.
+///
+/// # Returns
+/// The file, and if it already had data the [SEAM] was appended to it, so that the decoder
+/// synchronizes again at the start of this capture.
+fn append_output(path: &str, mkdir: bool) -> anyhow::Result<(std::fs::File, bool)> {
+    output_dir(path, mkdir)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Opening {path}"))?;
+    let bytes = file.metadata()?.len();
+    if bytes > 0 {
+        info!("Appending the capture to {path} after {bytes} bytes");
+        file.write_all(&SEAM)
+            .with_context(|| format!("Writing {path}"))?;
+    }
+    Ok((file, bytes > 0))
+}
+
+/// Check that the parent directory of an output file exists, see [create_output].
+fn output_dir(path: &str, mkdir: bool) -> anyhow::Result<()> {
+    let parent = std::path::Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty());
+    if let Some(parent) = parent {
+        if !parent.is_dir() {
+            if mkdir {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("Creating directory {}", parent.display()))?;
+            } else {
+                anyhow::bail!(
+                    "Output directory {} does not exist, create it or pass `--mkdir`",
+                    parent.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("etf_trace=info"))
+        .init();
+
+    // Without arguments the help is printed, so only the exclusive flags come without a command.
+    let args = Args::parse();
+    if args.list_probes {
+        return probes(ProbesArgs { json: false });
+    }
+    let Some(command) = args.command else {
+        return self_test();
+    };
+    match command {
+        Command::Capture(args) => capture(args),
+        Command::Decode(args) => decode(args),
+        Command::Probes(args) => probes(args),
+        Command::Topology(args) => topology(args),
+        Command::Serve(args) => serve(args),
+        Command::Bench(args) => bench(args),
+    }
+}
+
+/// The trace of the `--self-test`, see `tests/decode.rs`.
+const SELF_TEST_TRACE: &[u8] = include_bytes!("../tests/fixtures/valid.dump");
+
+/// Deformat and decode a formatted [SELF_TEST_TRACE] with each decoder and compare the packets.
+fn self_test() -> anyhow::Result<()> {
+    let instrumentation = |port, payload: &[u8]| TracePacket::Instrumentation {
+        port,
+        payload: payload.to_vec(),
+    };
+    let expected = [
+        (1_000, vec![TracePacket::Sync, instrumentation(0, b"H")]),
+        (134_000, vec![instrumentation(1, &[0x34, 0x12])]),
+        (
+            136_000,
+            vec![TracePacket::ExceptionTrace {
+                exception: VectActive::Exception(Exception::SysTick),
+                action: ExceptionAction::Entered,
+            }],
+        ),
+    ];
+    // The ITM trace, interrupted by another source and a frame synchronization.
+    let (first, second) = SELF_TEST_TRACE.split_at(SELF_TEST_TRACE.len() / 2);
+    let frames = [
+        formatter::format(first, ITM_ID),
+        formatter::format(b"another source", ITM_ID + 1),
+        vec![0xff, 0xff, 0xff, 0x7f],
+        formatter::format(second, ITM_ID),
+    ]
+    .concat();
+    let itm_trace = formatter::deformat(&frames, ITM_ID);
+    anyhow::ensure!(itm_trace == SELF_TEST_TRACE, "Deformatting failed");
+    println!("deformat: ok");
+
+    for decoder in [Decoder::Itm, Decoder::Builtin] {
+        let options = DecodeOptions {
+            coreclk: 1_000_000,
+            on_error: OnError::Abort,
+            tolerate: vec![],
+            global_timestamps: false,
+            lenient: false,
+            decoder,
+            ignore_eof: false,
+            progress: None,
+            sequence: false,
+        };
+        let mut packets = vec![];
+        decode_trace(itm_trace.as_slice(), &options, |p| {
+            packets.push((output::timestamp_ns(&p.timestamp), p.packets))
+        })
+        .with_context(|| format!("Decoding with the {decoder:?} decoder"))?;
+        anyhow::ensure!(
+            packets == expected,
+            "The {decoder:?} decoder returned {packets:?}, expected {expected:?}"
+        );
+        println!("{decoder:?} decoder: ok");
+    }
+    Ok(())
+}
+
+fn capture(cli: CaptureArgs) -> anyhow::Result<()> {
+    capture_to(cli, None)
+}
+
+/// Capture, decoding to `sink` in the `--format` if given, else to stdout or the decoded output
+/// file.
+fn capture_to(cli: CaptureArgs, sink: Option<Box<dyn Write + Send>>) -> anyhow::Result<()> {
+    if cli.dump_raw_and_exit {
+        return dump_raw_and_exit(&cli);
+    }
+    if cli.inspect {
+        return inspect(&mut attach(&cli.probe)?);
+    }
+    let coreclk = coreclk(cli.decoder.coreclk, &cli.probe.target);
+    if cli.follow && cli.decoder.golden.is_some() {
+        anyhow::bail!("`--golden` can not be used with `--follow`");
+    }
+    if cli.words.is_some() && cli.mode != CaptureMode::Circular {
+        anyhow::bail!("`--words` requires `--mode circular`");
+    }
+    if cli.trigger_timeout.is_some() && cli.mode != CaptureMode::Circular {
+        anyhow::bail!("`--trigger-timeout` requires `--mode circular`");
+    }
+    if cli.post_trigger.is_some() && cli.mode != CaptureMode::Circular {
+        anyhow::bail!("`--post-trigger` requires `--mode circular`");
+    }
+    let watermarks = Watermarks::new(cli.low_watermark, cli.high_watermark)?;
+    if let Some(elf) = &cli.decoder.elf {
+        check_firmware(elf, &cli.decoder);
+    }
+    if cli.count_only {
+        return count_only(&cli);
+    }
+    if cli.mode == CaptureMode::Hardware {
+        return hardware_fifo(&cli);
+    }
+    if cli.duration.is_some() && !cli.follow {
+        anyhow::bail!("`--duration` requires `--follow` or `--mode hardware`");
+    }
+    // An `--output` file with the extension of a decoded format receives the decoded packets,
+    // unless they have their own file.
+    let (itm_path, decoded_path) = match (cli.output.as_deref(), cli.decoded_output.as_deref()) {
+        (Some(path), None)
+            if cli.decoder.format.is_none() && Format::from_extension(path).is_some() =>
+        {
+            (None, Some(path))
+        }
+        paths => paths,
+    };
+    // Fail on a bad output path before attaching to the target.
+    let create = |path: Option<&str>| path.map(|path| create_output(path, cli.mkdir)).transpose();
+    if cli.with_header && itm_path.is_none() {
+        anyhow::bail!("`--with-header` requires an ITM trace `--output`");
+    }
+    let mut resume = cli.resume.as_deref().map(Resume::load).transpose()?;
+    // An appended capture continues a dump, which already has its header.
+    let mut continued = false;
+    let itm = match (itm_path, resume.as_mut()) {
+        (Some(path), Some(resume)) => Some(resume.open_dump(path, cli.mkdir)?),
+        (None, Some(_)) => anyhow::bail!("`--resume` requires an ITM trace `--output`"),
+        (Some(path), None) if cli.append => {
+            let (file, appended) = append_output(path, cli.mkdir)?;
+            continued = appended;
+            Some(file)
+        }
+        (None, None) if cli.append => {
+            anyhow::bail!("`--append` requires an ITM trace `--output`")
+        }
+        (path, None) => create(path)?,
+    };
+    let mut dumps = Dumps {
+        itm,
+        raw: create(cli.raw_output.as_deref())?,
+        resume,
+        max_bytes: cli.max_bytes,
+        bytes: 0,
+        list_words: cli.list_words,
+        reverse: cli.reverse,
+    };
+    let printer = match (sink, decoded_path) {
+        (Some(sink), _) => Printer::new(
+            &cli.decoder,
+            cli.decoder.format.unwrap_or_default(),
+            sink,
+            false,
+            coreclk,
+        )?,
+        (None, Some(path)) => {
+            let format = cli
+                .decoder
+                .format
+                .or_else(|| Format::from_extension(path))
+                .unwrap_or(Format::Json);
+            info!("Writing decoded packets as {format:?} to {path}");
+            let file = create_output(path, cli.mkdir)?;
+            let out: Box<dyn Write + Send> = match cli.follow {
+                // The packets are written as they are decoded, e.g. to watch with `tail -f`.
+                true => Box::new(LineWriter::new(file)),
+                false => Box::new(BufWriter::new(file)),
+            };
+            Printer::new(&cli.decoder, format, out, false, coreclk)?
+        }
+        (None, None) => {
+            let format = cli.decoder.format.unwrap_or_default();
+            let terminal = std::io::stdout().is_terminal();
+            let out = Box::new(std::io::stdout());
+            Printer::new(&cli.decoder, format, out, terminal, coreclk)?
+        }
+    };
+    let options = capture_options(&cli, coreclk);
+    let (before, mut capture) = connect(&cli.probe, |mut session| {
+        let before = TraceState::read(&mut session, options.core)?;
+        catch_interrupt();
+        Ok((before, options.setup(session)?))
+    })?;
+    // The probe-rs readout deformats every read on its own and drops the data that continues
+    // the source of the previous read, the reads of a longer capture have to form one stream.
+    if dumps.raw.is_some() || cli.follow || cli.repeat > 1 {
+        capture.keep_frames();
+    }
+
+    #[cfg(feature = "plot")]
+    if let Some(mut plot) = cli.plot.then(|| FillPlot::new(PLOT_WIDTH)).flatten() {
+        capture.set_monitor(move |percent| plot.sample(percent));
+    }
+
+    if cli.flush_on_trigger || cli.trigger_on_flush || cli.stop_on_flush || cli.enable_formatting {
+        let mut etf = capture.etf()?;
+
+        // The formatter and flush control may only be changed while capture is disabled.
+        etf.stop_capture()?;
+        etf.set_flush_on_trigger_event(cli.flush_on_trigger)?;
+        etf.set_trigger_on_flush(cli.trigger_on_flush)?;
+        etf.stop_on_flush(cli.stop_on_flush)?;
+        if cli.enable_formatting {
+            etf.set_formatter(true)?;
+        }
+        etf.enable_capture()?;
+
+        // Bits that are not implemented read as zero.
+        let ffcr = etf.format_flush_control()?;
+        info!("ETF formatter and flush control: {ffcr:?}");
+        for (flag, requested, effective) in [
+            ("--flush-on-trigger", cli.flush_on_trigger, ffcr.fontrgev()),
+            ("--trigger-on-flush", cli.trigger_on_flush, ffcr.trigonfl()),
+            ("--stop-on-flush", cli.stop_on_flush, ffcr.stoponfl()),
+            ("--enable-formatting", cli.enable_formatting, ffcr.enft()),
+        ] {
+            if requested && !effective {
+                warn!("`{flag}` did not take effect, the ETF does not implement it");
+            }
+        }
+    }
+
+    if cli.trace_regs {
+        trace_regs(&mut capture)?;
+    }
+    if !cli.decoder.ports.is_empty() {
+        check_ports(&mut capture, &cli.decoder.ports);
+    }
+    // The core clock still applies to the cycle counts of `--cycles`.
+    let tsclk = cli
+        .decoder
+        .timestamp_clock(coreclk)
+        .unwrap_or_else(|| capture.timestamp_frequency(coreclk));
+    let config = capture.config(&cli.probe.target, tsclk)?;
+    debug!("Capture configuration: {}", config.to_json());
+    let with_header = cli.with_header && !continued;
+    if let (true, Some(path), Some(dump)) = (with_header, itm_path, dumps.itm.as_mut()) {
+        let header = DumpHeader {
+            tool: env!("CARGO_PKG_VERSION").into(),
+            captured: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            config: config.clone(),
+        };
+        dump.write_all(&header.to_bytes())
+            .with_context(|| format!("Writing {path}"))?;
+    }
+
+    let options = DecodeOptions {
+        coreclk: tsclk,
+        on_error: cli.decoder.on_error(),
+        tolerate: cli.decoder.tolerate.clone(),
+        global_timestamps: cli.decoder.global_timestamps,
+        lenient: cli.decoder.lenient,
+        decoder: cli.decoder.decoder,
+        ignore_eof: false,
+        progress: None,
+        sequence: cli.decoder.sequence(),
+    };
+    let result = record(&cli, &mut capture, &mut dumps, options, printer, watermarks);
+    if let Some(resume) = dumps.resume.as_mut() {
+        resume
+            .save()
+            .with_context(|| format!("Writing {}", resume.path))?;
+    }
+    // The report is also written if the capture failed, to describe the data saved before.
+    if let (Some(path), Some(dump)) = (itm_path, dumps.itm.as_ref()) {
+        let report = CaptureReport {
+            label: cli.decoder.label.clone(),
+            config,
+            bytes: dump.metadata()?.len(),
+            crc32: std::fs::File::open(path)
+                .and_then(report::crc32)
+                .with_context(|| format!("Reading {path}"))?,
+        };
+        let path = CaptureReport::sidecar_path(path);
+        std::fs::write(&path, report.to_json())
+            .with_context(|| format!("Writing {}", path.display()))?;
+    }
+    report_peak_fill(&mut capture, cli.quiet);
+    // A failed teardown, e.g. after the probe disconnected, does not hide the capture error.
+    let teardown = teardown(&cli, &before, &mut capture);
+    result?;
+    teardown
+}
+
+/// Report how close the capture came to filling the ETF, see [CaptureSession::peak_fill].
+fn report_peak_fill(capture: &mut CaptureSession, quiet: bool) {
+    let peak = capture.peak_fill();
+    if peak == 0 {
+        return;
+    }
+    let Ok(size) = capture.etf().and_then(|mut etf| etf.fifo_size()) else {
+        return;
+    };
+    if peak >= size as usize {
+        warn!("The ETF filled up to its {size} bytes between reads, the trace may have stalled");
+    } else if !quiet {
+        let percent = peak as u64 * 100 / size as u64;
+        info!("The ETF held at most {peak} of {size} bytes ({percent}%)");
+    }
+}
+
+/// Restore the trace configuration with `--restore-on-exit`, or report the changes to it.
+fn teardown(
+    cli: &CaptureArgs,
+    before: &TraceState,
+    capture: &mut CaptureSession,
+) -> anyhow::Result<()> {
+    if cli.restore_on_exit {
+        before.restore(capture)?;
+        info!("Restored the trace configuration");
+        capture.release()?;
+        return Ok(());
+    }
+    let core = capture.core();
+    let changes = before.changes(&TraceState::read(capture.session(), core)?);
+    if !changes.is_empty() {
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|(name, before, after)| format!("{name} {before:#010x} -> {after:#010x}"))
+            .collect();
+        warn!(
+            "The trace configuration is left modified, restore it with `--restore-on-exit`: {}",
+            changes.join(", ")
+        );
+    }
+    capture.release()?;
+    Ok(())
+}
+
+/// Warn about requested stimulus ports that the ITM does not enable, or does not implement.
+/// Their data can never arrive.
+fn check_ports(capture: &mut CaptureSession, ports: &[u8]) {
+    let enabled = match capture.itm_ports() {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            warn!("Can not read the enabled ITM stimulus ports: {e}");
+            return;
+        }
+    };
+    let missing: Vec<_> = ports
+        .iter()
+        .filter(|port| !enabled.contains(port))
+        .collect();
+    if !missing.is_empty() {
+        warn!("The ITM does not enable or implement the `--ports` {missing:?}, only {enabled:?}");
+    }
+}
+
+/// The library options of a capture. Only the attach and the setup use them, the rest of the
+/// capture is done here.
+fn capture_options(cli: &CaptureArgs, coreclk: u32) -> CaptureOptions {
+    let mut options = CaptureOptions::new(&cli.probe.target, coreclk);
+    options.probe = cli.probe.probe.clone();
+    options.wait_for_probe = Duration::from_secs(cli.probe.wait_for_probe);
+    options.protocol = cli.probe.protocol.map(Into::into);
+    options.speed = cli.probe.speed;
+    options.under_reset = cli.probe.connect_under_reset;
+    options.core = cli.core;
+    options.no_setup = cli.no_setup;
+    options.circular = cli.mode == CaptureMode::Circular;
+    options.words = cli.words;
+    options.trigger_timeout = cli.trigger_timeout.map(Duration::from_millis);
+    options.post_trigger = cli.post_trigger;
+    options.etr_buffer = cli.etr_buffer;
+    options.funnel_port = cli.funnel_port;
+    options.source = cli.source;
+    options.backoff = Backoff {
+        initial: Duration::from_micros(cli.drain_backoff),
+        max: Duration::from_micros(cli.drain_backoff_max),
+    };
+    options.timeout = Duration::from_millis(cli.session_timeout);
+    options
+}
+
+/// Longest wait for the ETF to fill before each drain of `bench`.
+const BENCH_FILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Measure the drain throughput of the single register and block read paths.
+///
+/// Each drain reads the ETF until it runs empty, after it ran full or after the
+/// [BENCH_FILL_TIMEOUT] if the target produces less trace data.
+fn bench(cli: BenchArgs) -> anyhow::Result<()> {
+    let mut capture = CaptureSession::new(attach(&cli.probe)?)?;
+    let mut etf = capture.etf()?;
+    let words = etf.fifo_size()? as usize / core::mem::size_of::<u32>();
+    let mut buf = vec![0; words];
+    for block in [false, true] {
+        let mut elapsed = Duration::ZERO;
+        let mut data = 0;
+        for _ in 0..cli.rounds {
+            let start = Instant::now();
+            while !etf.full()? && start.elapsed() < BENCH_FILL_TIMEOUT {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            let start = Instant::now();
+            let count = if block {
+                etf.read_block(&mut buf)
+                    .context("Block transfers of the RRD register failed")?
+            } else {
+                let mut count = 0;
+                while count < buf.len() {
+                    match etf.read()? {
+                        Some(word) => buf[count] = word,
+                        None => break,
+                    }
+                    count += 1;
+                }
+                count
+            };
+            elapsed += start.elapsed();
+            data += count * 4;
+        }
+        let bytes = cli.rounds as usize * words * 4;
+        println!(
+            "{:>8}: {:.1} KB/s, {data} bytes of trace data read, the ETF held up to {bytes}",
+            if block { "block" } else { "register" },
+            data as f64 / elapsed.as_secs_f64() / 1000.0
+        );
+        if data == 0 {
+            warn!("The target produced no trace data, only reads of the empty ETF were measured");
+        }
+    }
+    capture.release()?;
+    Ok(())
+}
+
+/// Timeout of a write to a client of `serve`. A client that does not keep up is disconnected
+/// instead of stalling the capture.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Capture with `--follow` and send the decoded packets to the clients connecting to `listen`.
+fn serve(args: ServeArgs) -> anyhow::Result<()> {
+    let mut cli = args.capture;
+    if cli.decoded_output.is_some() || cli.decoder.json_pretty {
+        anyhow::bail!(
+            "`serve` sends NDJSON and does not support `--decoded-output` or `--json-pretty`"
+        );
+    }
+    cli.follow = true;
+    cli.decoder.format = Some(Format::Json);
+    let listener = std::net::TcpListener::bind(args.listen)
+        .with_context(|| format!("Listening on {}", args.listen))?;
+    info!("Serving decoded packets on {}", args.listen);
+    let clients = Clients::default();
+    let streams = clients.streams.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream.and_then(|stream| {
+                stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                Ok(stream)
+            }) {
+                Ok(stream) => {
+                    info!("Client {:?} connected", stream.peer_addr());
+                    streams.lock().unwrap().push(stream);
+                }
+                Err(e) => warn!("Accepting a client failed: {e}"),
+            }
+        }
+    });
+    capture_to(cli, Some(Box::new(clients)))
+}
+
+/// The clients of `serve`, receiving the decoded packets.
+///
+/// Only complete lines are sent so that a client connecting during a capture receives whole
+/// records.
+#[derive(Default)]
+struct Clients {
+    streams: std::sync::Arc<std::sync::Mutex<Vec<std::net::TcpStream>>>,
+    /// The written data after the last complete line.
+    line: Vec<u8>,
+}
+
+impl Write for Clients {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        if let Some(end) = self.line.iter().rposition(|&byte| byte == b'\n') {
+            let lines: Vec<u8> = self.line.drain(..=end).collect();
+            self.streams.lock().unwrap().retain_mut(|stream| {
+                let result = stream.write_all(&lines);
+                if let Err(e) = &result {
+                    info!("Client {:?} disconnected: {e}", stream.peer_addr());
+                }
+                result.is_ok()
+            });
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Drain the ETF as it is to the output file, see `--dump-raw-and-exit`.
+fn dump_raw_and_exit(cli: &CaptureArgs) -> anyhow::Result<()> {
+    let path = cli.output.as_deref().context("`--output` is required")?;
+    let mut session = attach(&cli.probe)?;
+    let components = session.get_arm_components(DpAddress::Default)?;
+    let component = capture::find_etf(&components, session.get_arm_interface()?)?
+        .context("The target has no ETF")?;
+    let data = EmbeddedTraceFifo::new(session.get_arm_interface()?, component).drain()?;
+    std::fs::write(path, &data).with_context(|| format!("Writing {path}"))?;
+    info!("Wrote {} bytes to {path}", data.len());
+    Ok(())
+}
+
+/// Duration of the measurement of `--count-only`.
+const COUNT_ONLY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Measure and print the rate at which trace data enters the ETF.
+fn count_only(cli: &CaptureArgs) -> anyhow::Result<()> {
+    let mut capture = connect(&cli.probe, |session| {
+        Ok(match cli.no_setup {
+            true => CaptureSession::existing(session)?,
+            false => CaptureSession::with_core(session, cli.core)?,
+        })
+    })?;
+    if let Some(port) = cli.funnel_port {
+        capture.set_funnel_port(port)?;
+    }
+    let rate = capture.fill_rate(COUNT_ONLY_INTERVAL)?;
+    if rate.full {
+        warn!("The ETF ran full during the measurement, the trace source may have stalled");
+    } else if rate.bytes > 0 {
+        let size = capture.etf()?.fifo_size()?;
+        let fill = Duration::from_secs_f64(size as f64 / rate.bytes_per_second());
+        info!("The {size} byte ETF fills up in {fill:?} at this rate");
+    }
+    println!("{rate}");
+    capture.release()?;
+    Ok(())
+}
+
+/// Interval at which the ETF is checked while it drains to the TPIU.
+const HARDWARE_FIFO_POLL: Duration = Duration::from_millis(100);
+
+/// Drain the ETF to the TPIU until `--duration` passed or the capture is interrupted, see
+/// [CaptureMode::Hardware].
+fn hardware_fifo(cli: &CaptureArgs) -> anyhow::Result<()> {
+    if cli.output.is_some() || cli.raw_output.is_some() || cli.decoded_output.is_some() {
+        anyhow::bail!("`--mode hardware` drains to the TPIU, there is no trace data to store");
+    }
+    if cli.follow || cli.repeat > 1 || cli.halt_compare {
+        anyhow::bail!("`--mode hardware` drains to the TPIU, there is no trace data to read");
+    }
+    let mut capture = connect(&cli.probe, |session| {
+        Ok(match cli.no_setup {
+            true => CaptureSession::existing(session)?,
+            false => CaptureSession::with_core(session, cli.core)?,
+        })
+    })?;
+    if let Some(port) = cli.funnel_port {
+        capture.set_funnel_port(port)?;
+    }
+    catch_interrupt();
+    capture.etf()?.enable_hardware_fifo()?;
+    info!("Draining the ETF to the TPIU, stop with Ctrl-C");
+    let duration = cli.duration.map(Duration::from_secs);
+    let start = Instant::now();
+    let mut was_full = false;
+    while !interrupted() && duration.is_none_or(|duration| start.elapsed() < duration) {
+        // A full ETF stalls the trace sources: the trace port does not keep up.
+        let full = capture.etf()?.full()?;
+        if full && !was_full {
+            warn!("The ETF ran full, the trace port does not keep up with the trace data rate");
+        }
+        was_full = full;
+        std::thread::sleep(HARDWARE_FIFO_POLL);
+    }
+    capture.etf()?.flush_and_stop()?;
+    info!("Stopped draining to the TPIU after {:?}", start.elapsed());
+    capture.release()?;
+    Ok(())
+}
+
+/// Warn if the firmware does not appear to use the trace units the capture relies on.
+///
+/// This is advisory only, errors reading the ELF file are reported when loading its symbols.
+fn check_firmware(elf: &str, args: &DecoderArgs) {
+    let Ok(usage) = TraceUsage::from_elf(elf) else {
+        return;
     };
-    for packets in decoder.timestamps(timestamp_cfg) {
-        match packets {
-            Err(e) => return Err(e).context("Decoder error"),
-            Ok(packets) => info!("{packets:?}"),
+    if !usage.itm && !usage.dwt {
+        warn!("The firmware {elf} does not appear to use the ITM or DWT, the trace may be empty");
+    } else if args.watch_var && !usage.dwt {
+        warn!(
+            "The firmware {elf} does not appear to configure the DWT comparators \
+             that `--watch-var` relies on"
+        );
+    } else if (args.start_marker.is_some() || args.stop_marker.is_some()) && !usage.itm {
+        warn!("The firmware {elf} does not appear to write the ITM stimulus ports of the markers");
+    }
+}
+
+/// Capture, store and decode trace data as configured.
+fn record(
+    cli: &CaptureArgs,
+    capture: &mut CaptureSession,
+    dumps: &mut Dumps,
+    options: DecodeOptions,
+    printer: Printer,
+    watermarks: Watermarks,
+) -> anyhow::Result<()> {
+    if cli.halt_compare {
+        halt_compare(capture, dumps, &options)?;
+    } else if cli.follow {
+        let pace = Pace {
+            watermarks,
+            min_fill: cli.min_fill,
+            stats_interval: cli.stats_interval.map(Duration::from_secs),
+            duration: cli.duration.map(Duration::from_secs),
+            quiet: cli.quiet,
+        };
+        follow(capture, dumps, options, printer, pace)?;
+    } else {
+        let decoder = DecoderThread::spawn(options, printer);
+        let mut bytes = 0;
+        let mut result = Ok(());
+        for cycle in 0..cli.repeat {
+            if cli.repeat > 1 && !cli.quiet {
+                info!("Capture {} of {}", cycle + 1, cli.repeat);
+            }
+            let itm_trace = match dumps.read(capture) {
+                Ok(data) => data,
+                Err(e) => {
+                    result = Err(capture_error(e, bytes));
+                    break;
+                }
+            };
+            bytes += itm_trace.len();
+            if cli.trace_regs {
+                trace_regs(capture)?;
+            }
+            if !decoder.send(itm_trace) || dumps.full() {
+                break;
+            }
+            if interrupted() {
+                match dumps.stop(capture) {
+                    Ok(rest) => {
+                        bytes += rest.len();
+                        if !rest.is_empty() {
+                            decoder.send(rest);
+                        }
+                        result = Err(anyhow::anyhow!(
+                            "Interrupted, the capture was stopped after {bytes} bytes"
+                        ));
+                    }
+                    Err(e) => result = Err(capture_error(e, bytes)),
+                }
+                break;
+            }
         }
+        let records = decoder.join(bytes)?.finish();
+        result?;
+        golden(&cli.decoder, records?)?;
     }
 
     Ok(())
 }
+
+fn decode(cli: DecodeArgs) -> anyhow::Result<()> {
+    let file = std::fs::File::open(&cli.input).with_context(|| format!("Opening {}", cli.input))?;
+    let (header, mut itm) =
+        DumpHeader::read(file).with_context(|| format!("Reading {}", cli.input))?;
+    if let Some(json) = &header {
+        debug!("Dump header: {json}");
+    }
+    // The timestamp clock of the capture, unless another one is given to decode the dump with.
+    let recorded = header
+        .or_else(|| std::fs::read_to_string(CaptureReport::sidecar_path(&cli.input)).ok())
+        .and_then(|json| CaptureReport::coreclk(&json))
+        .filter(|_| cli.decoder.coreclk.is_none() && cli.decoder.lts_prescaler.is_none());
+    if let Some(clk) = recorded {
+        info!("Using the timestamp clock {clk} Hz of the capture, override with `--coreclk`");
+    }
+    // The core clock still applies to the cycle counts of `--cycles`, the dump only records the
+    // timestamp clock.
+    let clk = match recorded {
+        Some(_) => family_coreclk(&cli.target).unwrap_or(DEFAULT_CORECLK),
+        None => coreclk(cli.decoder.coreclk, &cli.target),
+    };
+    let tsclk = recorded.unwrap_or_else(|| cli.decoder.timestamp_clock(clk).unwrap_or(clk));
+    let options = DecodeOptions {
+        coreclk: tsclk,
+        on_error: cli.decoder.on_error(),
+        tolerate: cli.decoder.tolerate.clone(),
+        global_timestamps: cli.decoder.global_timestamps,
+        lenient: cli.decoder.lenient,
+        decoder: cli.decoder.decoder,
+        ignore_eof: cli.ignore_eof,
+        progress: None,
+        sequence: cli.decoder.sequence(),
+    };
+    let format = cli.decoder.format.unwrap_or_default();
+    let terminal = std::io::stdout().is_terminal();
+    let out = Box::new(std::io::stdout());
+    let mut printer = Printer::new(&cli.decoder, format, out, terminal, clk)?;
+    let stats = if cli.ignore_eof {
+        // The dump is still growing, decode it as it is read.
+        decode_trace(itm, &options, |packets| printer.print(packets))?
+    } else {
+        let dump = std::fs::read(&cli.input).with_context(|| format!("Reading {}", cli.input))?;
+        // The sidecar file describes the whole dump, including the header.
+        check_integrity(&cli.input, &dump);
+        let mut itm_trace = vec![];
+        itm.read_to_end(&mut itm_trace)
+            .with_context(|| format!("Reading {}", cli.input))?;
+        printer.summary.bytes = itm_trace.len();
+        decode_trace(itm_trace.as_slice(), &options, |packets| {
+            printer.print(packets)
+        })?
+    };
+    printer.add_stats(stats);
+    golden(&cli.decoder, printer.finish()?)?;
+
+    Ok(())
+}
+
+/// Warn if a dump does not match the size and checksum recorded in its sidecar file, i.e. it was
+/// truncated or corrupted after the capture.
+fn check_integrity(input: &str, itm_trace: &[u8]) {
+    let path = CaptureReport::sidecar_path(input);
+    let Ok(json) = std::fs::read_to_string(&path) else {
+        return;
+    };
+    let Some((bytes, crc32)) = CaptureReport::integrity(&json) else {
+        debug!("{} records no checksum of the dump", path.display());
+        return;
+    };
+    // Reading from a slice does not fail.
+    let actual = report::crc32(itm_trace).unwrap();
+    if bytes != itm_trace.len() as u64 || crc32 != actual {
+        warn!(
+            "{input} does not match {}: {} bytes with CRC-32 {actual:#010x}, \
+            recorded {bytes} bytes with CRC-32 {crc32:#010x}. The dump was modified \
+            after the capture.",
+            path.display(),
+            itm_trace.len(),
+        );
+    } else {
+        debug!(
+            "{input} matches the size and checksum in {}",
+            path.display()
+        );
+    }
+}
+
+fn probes(cli: ProbesArgs) -> anyhow::Result<()> {
+    if cli.json {
+        println!("{}", capture::probes_json(&Probe::list_all()));
+        return Ok(());
+    }
+    let probes = Probe::list_all();
+    if probes.is_empty() {
+        let hint = match cfg!(target_os = "linux") {
+            true => ", check the USB connection and that the udev rules grant access to the probe",
+            false => ", check the USB connection and the probe driver (WinUSB on Windows)",
+        };
+        anyhow::bail!("No debug probe found{hint}");
+    }
+    // The selector in parentheses can be passed to `--probe`.
+    for (index, probe) in probes.iter().enumerate() {
+        println!(
+            "{index}: {} ({})",
+            probe.identifier,
+            capture::probe_selector(probe)
+        );
+    }
+    Ok(())
+}
+
+fn topology(cli: TopologyArgs) -> anyhow::Result<()> {
+    let mut session = attach(&cli.probe)?;
+    inspect(&mut session)?;
+    if cli.dump_fifo_config {
+        let components = session.get_arm_components(DpAddress::Default)?;
+        let component = capture::find_etf(&components, session.get_arm_interface()?)?
+            .context("The target has no ETF")?;
+        let mut etf = EmbeddedTraceFifo::new(session.get_arm_interface()?, component);
+        println!("{}", etf.dump_registers()?);
+    }
+    Ok(())
+}
+
+/// Print the CoreSight components of the target and the state of the trace path, without
+/// changing the configuration.
+fn inspect(session: &mut Session) -> anyhow::Result<()> {
+    let components = session.get_arm_components(DpAddress::Default)?;
+    for component in components.iter().flat_map(|c| c.iter()) {
+        let id = component.component.id();
+        let part = id.peripheral_id().determine_part();
+        println!(
+            "{:#010x} {}",
+            id.component_address(),
+            part.map(|p| p.to_string())
+                .unwrap_or_else(|| format!("Unknown part {:#05x}", id.peripheral_id().part()))
+        );
+    }
+    for funnel in capture::funnels(session)? {
+        println!("{funnel}");
+    }
+    for replicator in capture::replicators(session)? {
+        let outputs: Vec<_> = (0..2)
+            .filter(|&output| replicator.passes(output, ITM_ID))
+            .collect();
+        println!("{replicator}, passes the ITM on outputs {outputs:?}");
+    }
+    let tmcs = capture::tmcs(session)?;
+    for tmc in &tmcs {
+        println!("{tmc}");
+    }
+    if !tmcs.iter().any(|tmc| tmc.selected) {
+        warn!("There is no ETF or ETB to capture into");
+    }
+    Ok(())
+}
+
+/// Number of the most sampled program counters listed by `--dwt-summary`.
+const DWT_SUMMARY_PCS: usize = 20;
+
+/// Presentation of decoded packets and the trace summary.
+struct Printer {
+    format: Format,
+    /// Destination of the decoded packets.
+    out: Box<dyn Write + Send>,
+    /// The first error writing to `out`.
+    error: Option<std::io::Error>,
+    json_pretty: bool,
+    /// Number of records printed into the pretty JSON array.
+    records: usize,
+    summary_only: bool,
+    /// Color the table rows, see [output::color_row].
+    color: bool,
+    watch_var: bool,
+    /// The text of the `--console` port.
+    console: Option<Console>,
+    symbols: Option<Symbols>,
+    window: MarkerWindow,
+    summary: Summary,
+    /// The profile of `--dwt-summary` and `--profile`.
+    dwt: Option<DwtSummary>,
+    /// Print the `--dwt-summary`.
+    dwt_summary: bool,
+    /// The file of the `--profile`.
+    profile: Option<String>,
+    /// The packet sequence recorded for the golden file comparison.
+    golden: Option<Vec<String>>,
+    /// The database of `--format sqlite`.
+    #[cfg(feature = "sqlite")]
+    database: Option<Database>,
+    golden_timestamps: bool,
+    relative_time: bool,
+    timestamp_offset: Duration,
+    /// The clock of `--cycles`.
+    cycles: Option<CycleClock>,
+    /// The stimulus ports of `--ports`, all if empty.
+    ports: Vec<u8>,
+    /// The packet types of `--only-types`, all if empty.
+    types: Vec<PacketType>,
+    /// Number of packets left out by `--only-types`.
+    filtered: usize,
+    /// The reassembly of `--binary-port` records.
+    binary: Option<BinaryRecords>,
+    /// The last time written to the VCD format.
+    vcd_time: Option<u128>,
+    /// The first timestamp, the origin of relative times.
+    origin: Option<Duration>,
+}
+
+impl Printer {
+    /// Construct a printer of decoded packets in `format` to `out`.
+    ///
+    /// # Args
+    /// * `terminal` - `out` is a terminal, for `--color auto`.
+    /// * `coreclk` - The core clock frequency in Hz, for `--cycles`.
+    fn new(
+        args: &DecoderArgs,
+        format: Format,
+        out: Box<dyn Write + Send>,
+        terminal: bool,
+        coreclk: u32,
+    ) -> anyhow::Result<Self> {
+        let format = if args.json_pretty {
+            Format::Json
+        } else {
+            format
+        };
+        let symbols = args
+            .elf
+            .as_ref()
+            .map(|elf| Symbols::from_elf(elf).with_context(|| format!("Loading {elf}")))
+            .transpose()?;
+        #[cfg(feature = "sqlite")]
+        let database = match (format, &args.database) {
+            (Format::Sqlite, Some(path)) => {
+                let mut database =
+                    Database::create(path).with_context(|| format!("Opening {path}"))?;
+                if let Some(label) = &args.label {
+                    database.set_metadata("label", label)?;
+                }
+                Some(database)
+            }
+            (Format::Sqlite, None) => anyhow::bail!("`--format sqlite` requires `--database`"),
+            _ => None,
+        };
+        // The sequence indices are no times to shift, unless the times come from the cycles.
+        if args.sequence()
+            && args.cycles.is_none()
+            && (args.relative_time || args.timestamp_offset > 0)
+        {
+            anyhow::bail!(
+                "`--relative-time` and `--timestamp-offset` require timestamps or `--cycles`"
+            );
+        }
+        if let (Some(port), Some(format)) = (args.binary_port, &args.binary_format) {
+            if format.size() > port.len {
+                anyhow::bail!(
+                    "`--binary-format` needs {} bytes, but the records have {}",
+                    format.size(),
+                    port.len
+                );
+            }
+        }
+        let mut printer = Self {
+            format,
+            out,
+            error: None,
+            json_pretty: args.json_pretty,
+            records: 0,
+            summary_only: args.summary_only,
+            color: format == Format::Table && args.color.enabled(terminal),
+            watch_var: args.watch_var,
+            console: args.console.map(Console::new),
+            symbols,
+            window: MarkerWindow::new(args.start_marker, args.stop_marker),
+            summary: Summary::default(),
+            dwt: (args.dwt_summary || args.profile.is_some()).then(DwtSummary::default),
+            dwt_summary: args.dwt_summary,
+            profile: args.profile.clone(),
+            golden: args.golden.as_ref().map(|_| vec![]),
+            #[cfg(feature = "sqlite")]
+            database,
+            golden_timestamps: args.golden_timestamps,
+            relative_time: args.relative_time,
+            timestamp_offset: Duration::from_nanos(args.timestamp_offset),
+            cycles: args
+                .cycles
+                .map(|port| CycleClock::new(port, coreclk))
+                .transpose()?,
+            ports: args.ports.clone(),
+            types: args.only_types.clone(),
+            filtered: 0,
+            binary: args
+                .binary_port
+                .map(|port| BinaryRecords::new(port, args.binary_format.clone())),
+            vcd_time: None,
+            origin: None,
+        };
+        if !args.summary_only && !args.watch_var && args.console.is_none() {
+            if format == Format::Table {
+                if let Some(label) = &args.label {
+                    printer.write(format_args!("# label: {label}\n"));
+                }
+                printer.write(format_args!("{}\n", output::table_header()));
+            } else if format == Format::Csv {
+                printer.write(format_args!("{}\n", output::csv_header()));
+            } else if format == Format::Vcd {
+                printer.write(format_args!("{}\n", output::vcd_header()));
+            } else if format == Format::Sql {
+                printer.write(format_args!("{}\n", output::sql_schema()));
+                if let Some(label) = &args.label {
+                    printer.write(format_args!("{}\n", output::sql_metadata("label", label)));
+                }
+            } else if args.json_pretty {
+                printer.write(format_args!("["));
+            }
+        }
+        Ok(printer)
+    }
+
+    /// Write to the output. Only the first error is kept, it is reported by [Printer::finish].
+    fn write(&mut self, args: std::fmt::Arguments) {
+        if self.error.is_none() {
+            self.error = self.out.write_fmt(args).err();
+        }
+    }
+
+    /// Print a set of decoded packets in the configured format.
+    ///
+    /// All packets are accounted for in the summary, but only those within the marker window are
+    /// printed.
+    fn print(&mut self, mut packets: itm::TimestampedTracePackets) {
+        self.summary.add(&packets);
+        if let Some(dwt) = self.dwt.as_mut() {
+            packets.packets.iter().for_each(|packet| dwt.add(packet));
+        }
+        if let Some(clock) = self.cycles.as_mut() {
+            // Packets before the first counter value are at time zero.
+            packets.timestamp = Timestamp::Sync(clock.update(&packets.packets).unwrap_or_default());
+        }
+        if self.relative_time {
+            let origin = *self
+                .origin
+                .get_or_insert(Duration::from_nanos(
+                    output::timestamp_ns(&packets.timestamp) as u64,
+                ));
+            packets.timestamp = output::relative_timestamp(&packets.timestamp, origin);
+        }
+        if !self.timestamp_offset.is_zero() {
+            packets.timestamp = output::offset_timestamp(&packets.timestamp, self.timestamp_offset);
+        }
+        self.window.filter(&mut packets.packets);
+        if !self.ports.is_empty() {
+            packets.packets.retain(|packet| match packet {
+                TracePacket::Instrumentation { port, .. } => self.ports.contains(port),
+                _ => true,
+            });
+        }
+        if !self.types.is_empty() {
+            let len = packets.packets.len();
+            packets
+                .packets
+                .retain(|packet| self.types.contains(&PacketType::of(packet)));
+            self.filtered += len - packets.packets.len();
+        }
+        if let Some(golden) = self.golden.as_mut() {
+            let timestamp = self.golden_timestamps.then_some(&packets.timestamp);
+            golden.extend(
+                packets
+                    .packets
+                    .iter()
+                    .map(|packet| output::golden_record(timestamp, packet)),
+            );
+        }
+        let records = match self.binary.as_mut() {
+            Some(binary) => binary.extract(&mut packets.packets),
+            None => vec![],
+        };
+        if self.summary_only || (packets.packets.is_empty() && records.is_empty()) {
+            return;
+        }
+        if let Some(console) = self.console.as_mut() {
+            let text = console.push(&packets.packets);
+            if !text.is_empty() {
+                self.write(format_args!("{text}"));
+                // The text is printed as it arrives, not once a buffer fills.
+                if self.error.is_none() {
+                    self.error = self.out.flush().err();
+                }
+            }
+            return;
+        }
+        if self.watch_var {
+            for event in output::watch_events(&packets.packets) {
+                let line = output::watch_line(&packets.timestamp, &event, self.symbols.as_ref());
+                self.write(format_args!("{line}\n"));
+            }
+            return;
+        }
+        for packet in &packets.packets {
+            let record = match self.format {
+                Format::Log => {
+                    info!("{packets:?}");
+                    break;
+                }
+                Format::Table if self.color => {
+                    output::color_row(packet, &output::table_row(&packets.timestamp, packet))
+                }
+                Format::Table => output::table_row(&packets.timestamp, packet),
+                Format::Sql => output::sql_record(&packets.timestamp, packet),
+                #[cfg(feature = "sqlite")]
+                Format::Sqlite => {
+                    self.insert(|database| database.insert_packet(&packets.timestamp, packet));
+                    continue;
+                }
+                Format::Csv => output::csv_record(&packets.timestamp, packet),
+                Format::Json if self.json_pretty => {
+                    self.write_pretty(&output::json_record_pretty(&packets.timestamp, packet));
+                    continue;
+                }
+                Format::Json => output::json_record(&packets.timestamp, packet),
+                Format::Vcd => {
+                    let Some(change) = output::vcd_change(packet) else {
+                        continue;
+                    };
+                    // The VCD times have to increase, changes at the same time share one.
+                    let time = output::timestamp_ns(&packets.timestamp);
+                    if self.vcd_time.is_none_or(|last| time > last) {
+                        self.vcd_time = Some(time);
+                        self.write(format_args!("#{time}\n"));
+                    }
+                    change
+                }
+            };
+            self.write(format_args!("{record}\n"));
+        }
+        let Some(port) = self.binary.as_ref().map(BinaryRecords::port) else {
+            return;
+        };
+        for text in records {
+            if self.json_pretty {
+                self.write_pretty(&output::binary_record_pretty(
+                    &packets.timestamp,
+                    port,
+                    &text,
+                ));
+                continue;
+            }
+            #[cfg(feature = "sqlite")]
+            if self.format == Format::Sqlite {
+                self.insert(|database| database.insert_record(&packets.timestamp, port, &text));
+                continue;
+            }
+            let record = output::binary_record(self.format, &packets.timestamp, port, &text);
+            match self.format {
+                Format::Log => info!("{record}"),
+                _ => self.write(format_args!("{record}\n")),
+            }
+        }
+    }
+
+    /// Write to the database of `--format sqlite`. As with [Printer::write], only the first error
+    /// is kept.
+    #[cfg(feature = "sqlite")]
+    fn insert(&mut self, f: impl FnOnce(&mut Database) -> Result<(), EtfError>) {
+        if let (None, Some(database)) = (&self.error, self.database.as_mut()) {
+            self.error = f(database).err().map(std::io::Error::other);
+        }
+    }
+
+    /// Write a record into the pretty JSON array.
+    fn write_pretty(&mut self, record: &str) {
+        let separator = if self.records == 0 { "" } else { "," };
+        self.records += 1;
+        self.write(format_args!("{separator}\n{record}"));
+    }
+
+    /// Account for the statistics of a decoded stream in the summary.
+    fn add_stats(&mut self, stats: DecodeStats) {
+        if let Some(frequency) = stats.clock_suggestion {
+            warn!(
+                "Select a timestamp clock of {frequency} Hz with `--coreclk` and \
+                 `--lts-prescaler` if the global timestamps are right"
+            );
+        }
+        self.summary.add_stats(stats);
+    }
+
+    /// Report the summary at the end of the trace.
+    ///
+    /// # Returns
+    /// The packet sequence recorded for the golden file comparison, if enabled, or the first
+    /// error writing the output.
+    fn finish(mut self) -> anyhow::Result<Option<Vec<String>>> {
+        if let Some(console) = self.console.as_mut() {
+            let text = console.finish();
+            self.write(format_args!("{text}"));
+        }
+        if !self.summary_only && !self.watch_var && self.console.is_none() {
+            if self.format == Format::Sql {
+                self.write(format_args!("{}\n", output::sql_end()));
+            } else if self.json_pretty {
+                self.write(format_args!("\n]\n"));
+            }
+        }
+        let dwt = self.dwt.take().unwrap_or_default();
+        if self.dwt_summary {
+            let table = dwt.table(self.symbols.as_ref(), DWT_SUMMARY_PCS);
+            if dwt.is_empty() {
+                warn!("The trace has no PC sample, exception trace or event counter packets");
+            } else if self.format == Format::Table {
+                self.write(format_args!("\n{table}"));
+            } else {
+                eprint!("{table}");
+            }
+        }
+        if let Some(path) = &self.profile {
+            if dwt.pc_samples.is_empty() && dwt.sleep_samples == 0 {
+                warn!("The trace has no PC samples, enable the DWT PC sampling for a profile");
+            }
+            std::fs::write(path, dwt.folded(self.symbols.as_ref()))
+                .with_context(|| format!("Writing the profile {path}"))?;
+        }
+        if self.error.is_none() {
+            self.error = self.out.flush().err();
+        }
+        #[cfg(feature = "sqlite")]
+        if let (None, Some(database)) = (&self.error, self.database.take()) {
+            self.error = database.commit().err().map(std::io::Error::other);
+        }
+        if !self.window.started() {
+            warn!("The start marker was not found in the trace");
+        }
+        if let Some(binary) = self.binary.as_ref().filter(|binary| binary.pending() > 0) {
+            warn!(
+                "The trace ends within a binary record, {} bytes were left",
+                binary.pending()
+            );
+        }
+        if self.filtered > 0 {
+            info!(
+                "Left out {} packets not of the `--only-types` {:?}",
+                self.filtered, self.types
+            );
+        }
+        if self.summary.skipped > 0 {
+            warn!("Skipped {} malformed packets", self.summary.skipped);
+        }
+        if self.summary.unknown > 0 {
+            warn!("Ignored {} packets of unknown type", self.summary.unknown);
+        }
+        if self.summary.overflows() > 0 {
+            warn!(
+                "{} overflow events detected, trace data was dropped",
+                self.summary.overflows()
+            );
+        }
+        if self.summary.resyncs > 0 {
+            warn!(
+                "Resynchronized {} times after overflows or malformed packets, trace data was \
+                 discarded",
+                self.summary.resyncs
+            );
+        }
+        info!("Summary: {}", self.summary);
+        if let Some(error) = self.error {
+            return Err(anyhow::Error::from(error).context("Writing the decoded packets"));
+        }
+        Ok(self.golden)
+    }
+}
+
+/// Compare a decoded packet sequence against the golden file, or update the golden file.
+fn golden(args: &DecoderArgs, records: Option<Vec<String>>) -> anyhow::Result<()> {
+    let (Some(path), Some(records)) = (&args.golden, records) else {
+        return Ok(());
+    };
+    if args.update_golden {
+        let mut contents = records.join("\n");
+        contents.push('\n');
+        std::fs::write(path, contents).with_context(|| format!("Writing {path}"))?;
+        info!("Wrote {} packets to {path}", records.len());
+        return Ok(());
+    }
+    let expected = std::fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+    let expected: Vec<&str> = expected.lines().collect();
+    let divergence = expected
+        .iter()
+        .zip(records.iter())
+        .position(|(expected, actual)| expected != actual);
+    match divergence {
+        Some(index) => anyhow::bail!(
+            "Packet {index} differs from {path}:\n expected: {}\n   actual: {}",
+            expected[index],
+            records[index]
+        ),
+        None if expected.len() != records.len() => anyhow::bail!(
+            "Decoded {} packets, but {path} has {}",
+            records.len(),
+            expected.len()
+        ),
+        None => {
+            info!("Packet sequence matches {path}");
+            Ok(())
+        }
+    }
+}
+
+/// Decoding of trace data on a worker thread, concurrently with the capture.
+struct DecoderThread {
+    sender: std::sync::mpsc::Sender<Vec<u8>>,
+    thread: std::thread::JoinHandle<(Result<DecodeStats, EtfError>, Printer)>,
+}
+
+impl DecoderThread {
+    /// Start decoding the trace data that will be sent, printing it with `printer`.
+    fn spawn(options: DecodeOptions, mut printer: Printer) -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let stats = decode_trace(ChannelReader::new(receiver), &options, |packets| {
+                printer.print(packets)
+            });
+            (stats, printer)
+        });
+        Self { sender, thread }
+    }
+
+    /// Pass trace data to the decoder.
+    ///
+    /// # Returns
+    /// False if the decoder has terminated, e.g. on a decoding error.
+    fn send(&self, itm_trace: Vec<u8>) -> bool {
+        self.sender.send(itm_trace).is_ok()
+    }
+
+    /// Wait for the decoder to process all data.
+    ///
+    /// # Args
+    /// * `bytes` - Number of trace bytes sent, for the summary.
+    ///
+    /// # Returns
+    /// The printer with the complete summary, or the decoding error.
+    fn join(self, bytes: usize) -> anyhow::Result<Printer> {
+        drop(self.sender);
+        let (stats, mut printer) = self.thread.join().expect("Decoder thread panicked");
+        printer.summary.bytes = bytes;
+        printer.add_stats(stats?);
+        Ok(printer)
+    }
+}
+
+/// Number of consecutive polls of [follow] that find the ETF full before it is reported as
+/// stalled.
+const STALL_POLLS: usize = 3;
+
+/// The polling and the limits of [follow].
+struct Pace {
+    /// The ETF is polled at an interval that keeps its fill level between these.
+    watermarks: Watermarks,
+    /// The ETF is only drained once it holds this many bytes, or the session timeout passed
+    /// since the last drain.
+    min_fill: u32,
+    /// The progress is logged at this interval.
+    stats_interval: Option<Duration>,
+    /// The capture is stopped after this duration, see [Dumps::stop].
+    duration: Option<Duration>,
+    /// Do not log stalls as they happen, see `--quiet`.
+    quiet: bool,
+}
+
+/// Continuously read trace data, store it in `dumps` and decode it concurrently.
+///
+/// This returns once reading from the target or decoding fails, the `--max-bytes` limit is
+/// reached, or the capture was stopped after the duration of the `pace`.
+fn follow(
+    capture: &mut CaptureSession,
+    dumps: &mut Dumps,
+    options: DecodeOptions,
+    printer: Printer,
+    pace: Pace,
+) -> anyhow::Result<()> {
+    let Pace {
+        watermarks,
+        min_fill,
+        stats_interval,
+        duration,
+        quiet,
+    } = pace;
+    let size = capture.etf()?.fifo_size()?;
+    if min_fill > size {
+        anyhow::bail!("`--min-fill` {min_fill} exceeds the ETF size of {size} bytes");
+    }
+    let progress = Arc::new(Progress::default());
+    let options = DecodeOptions {
+        progress: Some(progress.clone()),
+        ..options
+    };
+    let decoder = DecoderThread::spawn(options, printer);
+    let mut pacer = Pacer::new(watermarks);
+    let mut bytes = 0;
+    let timeout = capture.timeout();
+    let start = Instant::now();
+    let mut drained = start;
+    let mut stats = start;
+    // The fill level before draining shows how much data accumulated since the last poll.
+    let mut poll = |capture: &mut CaptureSession,
+                    dumps: &mut Dumps,
+                    pacer: &mut Pacer|
+     -> Result<_, EtfError> {
+        let mut etf = capture.etf()?;
+        let percent = etf.fill_percent()?;
+        let full = etf.full()?;
+        let wait = pacer.next(percent);
+        if min_fill > 0 && etf.fill_level()? < min_fill && drained.elapsed() < timeout {
+            return Ok((vec![], wait, percent, full));
+        }
+        drained = Instant::now();
+        Ok((dumps.read(capture)?, wait, percent, full))
+    };
+
+    // Number of polls that found the ETF newly full.
+    let mut stalls = 0;
+    // Number of consecutive polls that found the ETF full.
+    let mut full_polls = 0;
+    let mut stall_warned = quiet;
+    let result = loop {
+        let (itm_trace, wait, percent, full) = match poll(capture, dumps, &mut pacer) {
+            Ok(polled) => polled,
+            Err(e) => break Err(e),
+        };
+        full_polls = if full { full_polls + 1 } else { 0 };
+        if full_polls == 1 {
+            stalls += 1;
+            debug!("The ETF ran full, the trace source stalled and may have dropped trace data");
+        }
+        // A single full poll may be a burst, the ETF staying full is a drain that does not keep
+        // up. This is only warned about once, the summary follows at the end.
+        if full_polls == STALL_POLLS && !stall_warned {
+            stall_warned = true;
+            warn!(
+                "The ETF stalled: it stayed full for {STALL_POLLS} polls, \
+                 the trace source outpaces the drain"
+            );
+        }
+        if stats_interval.is_some_and(|interval| stats.elapsed() >= interval) {
+            stats = Instant::now();
+            info!(
+                "{} bytes drained, {} packets decoded, {} malformed packets skipped, fill {}%",
+                bytes + itm_trace.len(),
+                progress.packets.load(Ordering::Relaxed),
+                progress.skipped.load(Ordering::Relaxed),
+                percent
+            );
+        }
+        if !itm_trace.is_empty() {
+            bytes += itm_trace.len();
+            if !decoder.send(itm_trace) || dumps.full() {
+                break Ok(());
+            }
+        }
+        if interrupted() || duration.is_some_and(|duration| start.elapsed() >= duration) {
+            let rest = match dumps.stop(capture) {
+                Ok(rest) => rest,
+                Err(e) => break Err(e),
+            };
+            info!("Stopped the capture after {:?}", start.elapsed());
+            bytes += rest.len();
+            if !rest.is_empty() {
+                decoder.send(rest);
+            }
+            break Ok(());
+        }
+        std::thread::sleep(wait);
+    };
+
+    if stalls > 0 {
+        warn!("The ETF ran full {stalls} times, poll faster or lower the trace data rate");
+    }
+    let finished = decoder.join(bytes)?.finish();
+    result.map_err(|e| capture_error(e, bytes))?;
+    finished?;
+    if interrupted() {
+        anyhow::bail!("Interrupted, the capture was stopped after {bytes} bytes");
+    }
+    Ok(())
+}
+
+/// Duration of each of the two captures of `--halt-compare`.
+const HALT_COMPARE_PHASE: Duration = Duration::from_millis(200);
+
+/// Duration of each halt of the core during `--halt-compare`.
+const HALT_COMPARE_HALT: Duration = Duration::from_millis(1);
+
+/// Capture with the core running and periodically halted and compare the timestamp deltas.
+///
+/// The trace data of both captures is stored in `dumps`, one after the other.
+fn halt_compare(
+    capture: &mut CaptureSession,
+    dumps: &mut Dumps,
+    options: &DecodeOptions,
+) -> anyhow::Result<()> {
+    for halt in [false, true] {
+        let mut itm_trace = vec![];
+        let start = Instant::now();
+        while start.elapsed() < HALT_COMPARE_PHASE {
+            if halt {
+                let timeout = capture.timeout();
+                let index = capture.core();
+                let mut core = capture.session().core(index)?;
+                core.halt(timeout)?;
+                std::thread::sleep(HALT_COMPARE_HALT);
+                core.run()?;
+            }
+            itm_trace.extend(dumps.read(capture)?);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // The second capture does not necessarily start on a packet boundary.
+        let options = DecodeOptions {
+            on_error: OnError::Skip,
+            ..options.clone()
+        };
+        let mut timestamps = vec![];
+        decode_trace(itm_trace.as_slice(), &options, |packets| {
+            timestamps.push(output::timestamp_ns(&packets.timestamp))
+        })?;
+        let label = if halt {
+            "periodically halted"
+        } else {
+            "running"
+        };
+        println!("{label:>20}: {}", TimestampDeltas::new(&timestamps));
+    }
+    Ok(())
+}
+
+/// The files the captured trace data is stored in.
+struct Dumps {
+    /// The ITM trace data.
+    itm: Option<std::fs::File>,
+    /// The data as read from the ETF.
+    raw: Option<std::fs::File>,
+    /// The state of a `--resume` capture into `itm`.
+    resume: Option<Resume>,
+    /// The limit of `--max-bytes`.
+    max_bytes: Option<u64>,
+    /// Number of ITM trace bytes read.
+    bytes: u64,
+    /// List the words of every capture, see [list_words].
+    list_words: bool,
+    reverse: bool,
+}
+
+impl Dumps {
+    /// Read the trace data captured since the previous read and store it.
+    ///
+    /// # Returns
+    /// The ITM trace data.
+    fn read(&mut self, capture: &mut CaptureSession) -> Result<Vec<u8>, EtfError> {
+        let raw = capture.read_raw()?;
+        self.store(capture, raw)
+    }
+
+    /// Stop the capture, read the rest of the trace data and store it, see
+    /// [CaptureSession::stop].
+    ///
+    /// # Returns
+    /// The ITM trace data.
+    fn stop(&mut self, capture: &mut CaptureSession) -> Result<Vec<u8>, EtfError> {
+        let raw = capture.stop()?;
+        self.store(capture, raw)
+    }
+
+    /// Store the data of a read.
+    fn store(&mut self, capture: &mut CaptureSession, raw: RawTrace) -> Result<Vec<u8>, EtfError> {
+        if self.list_words {
+            list_words(&raw, self.reverse);
+        }
+        if let Some(file) = self.raw.as_mut() {
+            file.write_all(&raw.data)?;
+        }
+        let mut itm_trace = capture.itm(raw)?;
+        if let Some(max) = self.max_bytes {
+            itm_trace.truncate(max.saturating_sub(self.bytes) as usize);
+        }
+        self.bytes += itm_trace.len() as u64;
+        if let Some(file) = self.itm.as_mut() {
+            file.write_all(&itm_trace)?;
+        }
+        if let Some(resume) = self.resume.as_mut() {
+            resume.state.bytes += itm_trace.len() as u64;
+            if resume.saved.elapsed() >= RESUME_SAVE_INTERVAL {
+                resume.save()?;
+            }
+        }
+        Ok(itm_trace)
+    }
+
+    /// Check if the `--max-bytes` limit is reached, the capture then ends.
+    fn full(&self) -> bool {
+        let full = self.max_bytes.is_some_and(|max| self.bytes >= max);
+        if full {
+            info!("Captured the maximum of {} bytes", self.bytes);
+        }
+        full
+    }
+}
+
+/// Interval at which the state of a `--resume` capture is saved during the capture.
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of a `--resume` capture and its file.
+struct Resume {
+    path: String,
+    state: ResumeState,
+    /// The state was loaded from the file of a previous run.
+    resumed: bool,
+    /// When the state was last saved.
+    saved: Instant,
+}
+
+impl Resume {
+    /// Load the state, or start a new state if the file does not exist.
+    fn load(path: &str) -> anyhow::Result<Self> {
+        let (state, resumed) = match std::fs::read_to_string(path) {
+            Ok(json) => {
+                let state = ResumeState::from_json(&json)
+                    .with_context(|| format!("Invalid resume state in {path}"))?;
+                (state, true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (ResumeState::default(), false),
+            Err(e) => return Err(e).with_context(|| format!("Reading {path}")),
+        };
+        Ok(Self {
+            path: path.into(),
+            state,
+            resumed,
+            saved: Instant::now(),
+        })
+    }
+
+    /// Open the ITM trace dump, and append the [SEAM] to the dump of the previous run.
+    fn open_dump(&mut self, path: &str, mkdir: bool) -> anyhow::Result<std::fs::File> {
+        if !self.resumed {
+            return create_output(path, mkdir);
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Opening {path} to resume the capture"))?;
+        let bytes = file.metadata()?.len();
+        if bytes < self.state.bytes {
+            anyhow::bail!(
+                "{path} has {bytes} bytes, less than the {} bytes recorded in {}",
+                self.state.bytes,
+                self.path
+            );
+        }
+        info!("Resuming the capture in {path} after {bytes} bytes");
+        file.write_all(&SEAM)?;
+        self.state.bytes = bytes + SEAM.len() as u64;
+        self.state.resumptions += 1;
+        self.save()?;
+        Ok(file)
+    }
+
+    /// Write the state to its file.
+    fn save(&mut self) -> std::io::Result<()> {
+        std::fs::write(&self.path, self.state.to_json())?;
+        self.saved = Instant::now();
+        Ok(())
+    }
+}
+
+/// Print the words of a capture to stderr, in read order or newest first.
+fn list_words(raw: &RawTrace, reverse: bool) {
+    let mut words: Vec<_> = raw.words().into_iter().enumerate().collect();
+    if reverse {
+        words.reverse();
+    }
+    for (index, word) in words {
+        eprintln!("{index:>6}: {word:08x}");
+    }
+}
+
+/// Log the ETF registers that describe the state of the trace RAM.
+fn trace_regs(capture: &mut CaptureSession) -> anyhow::Result<()> {
+    let mut etf = capture.etf()?;
+    let pointer = |pointer: Result<u32, EtfError>| match pointer {
+        Ok(pointer) => format!("{pointer:#06x}"),
+        Err(e) => e.to_string(),
+    };
+    let status = etf.status()?;
+    info!(
+        "ETF: CBUFLVL {} bytes, RRP {}, RWP {}, full {}, empty {}, triggered {}",
+        etf.fill_level()?,
+        pointer(etf.read_pointer()),
+        pointer(etf.write_pointer()),
+        status.full(),
+        status.empty(),
+        status.trigd()
+    );
+    Ok(())
+}
+
+/// Convert an error that ended a capture after `bytes` were written to the output.
+fn capture_error(error: EtfError, bytes: usize) -> anyhow::Error {
+    let disconnect = is_disconnect(&error);
+    let error = anyhow::Error::from(error);
+    if disconnect {
+        error.context(format!(
+            "Probe disconnected, the {bytes} bytes captured before were saved"
+        ))
+    } else {
+        error
+    }
+}
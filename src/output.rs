@@ -0,0 +1,1207 @@
+//! Presentation of decoded trace packets
+use crate::{symbols::Symbols, EtfError};
+use itm::{ExceptionAction, MemoryAccessType, Timestamp, TimestampedTracePackets, TracePacket};
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+/// Maximum number of payload bytes shown in the table format.
+const TABLE_PAYLOAD_BYTES: usize = 8;
+
+/// Output format of decoded trace packets.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Log the timestamped packet sets in their debug representation.
+    #[default]
+    Log,
+    /// Print one packet per line in aligned columns.
+    Table,
+    /// Print one JSON object per packet and line (NDJSON).
+    Json,
+    /// Print SQL statements that create and fill an SQLite table of packets, see [sql_schema].
+    Sql,
+    /// Write the packets into an SQLite database file with the tables of [sql_schema], see
+    /// [crate::sqlite::Database].
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    /// Print one comma separated row per packet after a header row, see [csv_header].
+    Csv,
+    /// Print a Value Change Dump of the stimulus ports and the active exception, e.g. for
+    /// GTKWave, see [vcd_header].
+    Vcd,
+}
+
+impl Format {
+    /// The format implied by the extension of an output file.
+    ///
+    /// | Extension          | Format         |
+    /// |--------------------|----------------|
+    /// | `.json`, `.ndjson` | [Format::Json] |
+    /// | `.sql`             | [Format::Sql]  |
+    /// | `.csv`             | [Format::Csv]  |
+    /// | `.vcd`             | [Format::Vcd]  |
+    ///
+    /// # Returns
+    /// `None` for all other extensions, e.g. `.dump` or `.bin`, which denote raw trace data.
+    pub fn from_extension(path: impl AsRef<Path>) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" | "ndjson" => Some(Self::Json),
+            "sql" => Some(Self::Sql),
+            "csv" => Some(Self::Csv),
+            "vcd" => Some(Self::Vcd),
+            _ => None,
+        }
+    }
+}
+
+/// The time of a timestamped packet set in nanoseconds.
+///
+/// For timestamps with an unknown delay the current (upper bound) timestamp is used.
+pub fn timestamp_ns(timestamp: &Timestamp) -> u128 {
+    match timestamp {
+        Timestamp::Sync(t) | Timestamp::AssocEventDelay(t) => t.as_nanos(),
+        Timestamp::UnknownDelay { curr, .. } | Timestamp::UnknownAssocEventDelay { curr, .. } => {
+            curr.as_nanos()
+        }
+    }
+}
+
+/// The relation of a timestamp to the packets it applies to, as named in the ITM architecture.
+pub fn data_relation(timestamp: &Timestamp) -> &'static str {
+    match timestamp {
+        Timestamp::Sync(_) => "sync",
+        Timestamp::UnknownDelay { .. } => "unknown_delay",
+        Timestamp::AssocEventDelay(_) => "assoc_event_delay",
+        Timestamp::UnknownAssocEventDelay { .. } => "unknown_assoc_event_delay",
+    }
+}
+
+/// Shift a timestamp to be relative to an origin, e.g. the first timestamp of a capture.
+///
+/// Times before the origin saturate at zero.
+pub fn relative_timestamp(timestamp: &Timestamp, origin: Duration) -> Timestamp {
+    map_timestamp(timestamp, |t| t.saturating_sub(origin))
+}
+
+/// Shift a timestamp by an offset, e.g. to place a capture on an external timeline.
+pub fn offset_timestamp(timestamp: &Timestamp, offset: Duration) -> Timestamp {
+    map_timestamp(timestamp, |t| t + offset)
+}
+
+/// Apply `f` to all times of a timestamp.
+fn map_timestamp(timestamp: &Timestamp, f: impl Fn(Duration) -> Duration) -> Timestamp {
+    match *timestamp {
+        Timestamp::Sync(t) => Timestamp::Sync(f(t)),
+        Timestamp::AssocEventDelay(t) => Timestamp::AssocEventDelay(f(t)),
+        Timestamp::UnknownDelay { prev, curr } => Timestamp::UnknownDelay {
+            prev: f(prev),
+            curr: f(curr),
+        },
+        Timestamp::UnknownAssocEventDelay { prev, curr } => Timestamp::UnknownAssocEventDelay {
+            prev: f(prev),
+            curr: f(curr),
+        },
+    }
+}
+
+/// Time from the DWT cycle counter (CYCCNT) values the firmware writes to a stimulus port.
+///
+/// This has the resolution of a core cycle, unlike the ITM local timestamps, which are usually
+/// prescaled and coarser. The 32 bit counter values are extended, assuming that consecutive
+/// values are less than a counter wrap (2^32 cycles) apart.
+#[derive(Clone, Debug)]
+pub struct CycleClock {
+    port: u8,
+    frequency: u32,
+    last: Option<u32>,
+    /// Number of cycles since the first counter value.
+    cycles: u64,
+}
+
+impl CycleClock {
+    /// Construct a clock for counter values on stimulus `port` of a core at `frequency` in Hz.
+    pub fn new(port: u8, frequency: u32) -> Result<Self, EtfError> {
+        if frequency == 0 {
+            return Err(EtfError::ZeroFrequency("core clock"));
+        }
+        Ok(Self {
+            port,
+            frequency,
+            last: None,
+            cycles: 0,
+        })
+    }
+
+    /// Update the clock with the counter values among `packets`, 32 bit instrumentation packets
+    /// on the port of the clock.
+    ///
+    /// # Returns
+    /// The time of the latest counter value since the first one, `None` before the first one.
+    pub fn update(&mut self, packets: &[TracePacket]) -> Option<Duration> {
+        for packet in packets {
+            let TracePacket::Instrumentation { port, payload } = packet else {
+                continue;
+            };
+            let Ok(value) = <[u8; 4]>::try_from(payload.as_slice()) else {
+                continue;
+            };
+            if *port != self.port {
+                continue;
+            }
+            let value = u32::from_le_bytes(value);
+            if let Some(last) = self.last {
+                self.cycles += value.wrapping_sub(last) as u64;
+            }
+            self.last = Some(value);
+        }
+        self.last?;
+        let nanos = self.cycles as u128 * 1_000_000_000 / self.frequency as u128;
+        Some(Duration::from_nanos(nanos as u64))
+    }
+
+    /// Number of cycles from the first to the latest counter value.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+/// A short name of the packet type.
+pub fn packet_kind(packet: &TracePacket) -> &'static str {
+    match packet {
+        TracePacket::Sync => "sync",
+        TracePacket::Overflow => "overflow",
+        TracePacket::LocalTimestamp1 { .. } => "lts1",
+        TracePacket::LocalTimestamp2 { .. } => "lts2",
+        TracePacket::GlobalTimestamp1 { .. } => "gts1",
+        TracePacket::GlobalTimestamp2 { .. } => "gts2",
+        TracePacket::Extension { .. } => "extension",
+        TracePacket::Instrumentation { .. } => "instrumentation",
+        TracePacket::EventCounterWrap { .. } => "counter-wrap",
+        TracePacket::ExceptionTrace { .. } => "exception",
+        TracePacket::PCSample { .. } => "pc-sample",
+        TracePacket::DataTracePC { .. } => "data-pc",
+        TracePacket::DataTraceAddress { .. } => "data-address",
+        TracePacket::DataTraceValue { .. } => "data-value",
+    }
+}
+
+/// A packet type, named as by [packet_kind], e.g. to select the packets to print.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PacketType {
+    Sync,
+    Overflow,
+    Lts1,
+    Lts2,
+    Gts1,
+    Gts2,
+    Extension,
+    Instrumentation,
+    CounterWrap,
+    Exception,
+    PcSample,
+    DataPc,
+    DataAddress,
+    DataValue,
+}
+
+impl PacketType {
+    /// The type of a packet.
+    pub fn of(packet: &TracePacket) -> Self {
+        match packet {
+            TracePacket::Sync => Self::Sync,
+            TracePacket::Overflow => Self::Overflow,
+            TracePacket::LocalTimestamp1 { .. } => Self::Lts1,
+            TracePacket::LocalTimestamp2 { .. } => Self::Lts2,
+            TracePacket::GlobalTimestamp1 { .. } => Self::Gts1,
+            TracePacket::GlobalTimestamp2 { .. } => Self::Gts2,
+            TracePacket::Extension { .. } => Self::Extension,
+            TracePacket::Instrumentation { .. } => Self::Instrumentation,
+            TracePacket::EventCounterWrap { .. } => Self::CounterWrap,
+            TracePacket::ExceptionTrace { .. } => Self::Exception,
+            TracePacket::PCSample { .. } => Self::PcSample,
+            TracePacket::DataTracePC { .. } => Self::DataPc,
+            TracePacket::DataTraceAddress { .. } => Self::DataAddress,
+            TracePacket::DataTraceValue { .. } => Self::DataValue,
+        }
+    }
+}
+
+/// The stimulus port or DWT comparator the packet originates from.
+pub fn packet_source(packet: &TracePacket) -> Option<u8> {
+    match packet {
+        TracePacket::Instrumentation { port, .. } => Some(*port),
+        TracePacket::DataTracePC { comparator, .. }
+        | TracePacket::DataTraceAddress { comparator, .. }
+        | TracePacket::DataTraceValue { comparator, .. } => Some(*comparator),
+        _ => None,
+    }
+}
+
+/// A short summary of the packet payload.
+pub fn packet_summary(packet: &TracePacket) -> String {
+    payload_text(packet, TABLE_PAYLOAD_BYTES)
+}
+
+/// A textual representation of the packet payload with at most `limit` payload bytes.
+fn payload_text(packet: &TracePacket, limit: usize) -> String {
+    match packet {
+        TracePacket::Instrumentation { payload, .. }
+        | TracePacket::DataTraceAddress { data: payload, .. }
+        | TracePacket::DataTraceValue { value: payload, .. } => hex(payload, limit),
+        TracePacket::ExceptionTrace { exception, action } => format!("{exception:?} {action:?}"),
+        TracePacket::PCSample { pc: Some(pc) } | TracePacket::DataTracePC { pc, .. } => {
+            format!("{pc:#010x}")
+        }
+        TracePacket::PCSample { pc: None } => "sleep".into(),
+        TracePacket::Extension { page } => format!("page {page}"),
+        TracePacket::LocalTimestamp1 { ts, .. } => ts.to_string(),
+        TracePacket::LocalTimestamp2 { ts } => ts.to_string(),
+        TracePacket::GlobalTimestamp1 { ts, .. } | TracePacket::GlobalTimestamp2 { ts } => {
+            ts.to_string()
+        }
+        TracePacket::EventCounterWrap {
+            cyc,
+            fold,
+            lsu,
+            sleep,
+            exc,
+            cpi,
+        } => [
+            (cyc, "cyc"),
+            (fold, "fold"),
+            (lsu, "lsu"),
+            (sleep, "sleep"),
+            (exc, "exc"),
+            (cpi, "cpi"),
+        ]
+        .iter()
+        .filter(|(set, _)| **set)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(","),
+        TracePacket::Sync | TracePacket::Overflow => String::new(),
+    }
+}
+
+/// Hex representation of a payload, truncated to `limit` bytes.
+fn hex(payload: &[u8], limit: usize) -> String {
+    let mut s = payload
+        .iter()
+        .take(limit)
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if payload.len() > limit {
+        s.push_str(" ...");
+    }
+    s
+}
+
+/// The header line of the table format.
+pub fn table_header() -> String {
+    format!(
+        "{:>16} {:<16} {:>6} payload",
+        "timestamp_ns", "type", "source"
+    )
+}
+
+/// A line of the table format.
+pub fn table_row(timestamp: &Timestamp, packet: &TracePacket) -> String {
+    let source = packet_source(packet).map(|s| s.to_string());
+    format!(
+        "{:>16} {:<16} {:>6} {}",
+        timestamp_ns(timestamp),
+        packet_kind(packet),
+        source.as_deref().unwrap_or("-"),
+        packet_summary(packet)
+    )
+}
+
+/// When to color the table output.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Color {
+    /// Color the output if it is a terminal.
+    #[default]
+    Auto,
+    /// Always color the output.
+    Always,
+    /// Never color the output.
+    Never,
+}
+
+impl Color {
+    /// Whether to color an output.
+    ///
+    /// # Args
+    /// * `terminal` - The output is a terminal.
+    pub fn enabled(self, terminal: bool) -> bool {
+        match self {
+            Self::Auto => terminal,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Color a [table_row] of `packet` with ANSI escape sequences: exceptions red, timestamps and
+/// synchronization dim, all other packets unchanged.
+pub fn color_row(packet: &TracePacket, row: &str) -> String {
+    let style = match packet {
+        TracePacket::ExceptionTrace { .. } => "31",
+        TracePacket::Sync
+        | TracePacket::LocalTimestamp1 { .. }
+        | TracePacket::LocalTimestamp2 { .. }
+        | TracePacket::GlobalTimestamp1 { .. }
+        | TracePacket::GlobalTimestamp2 { .. } => "2",
+        _ => return row.into(),
+    };
+    format!("\x1b[{style}m{row}\x1b[0m")
+}
+
+/// The fields of a JSON packet record with their JSON encoded values.
+fn json_fields(timestamp: &Timestamp, packet: &TracePacket) -> [(&'static str, String); 5] {
+    record_fields(
+        timestamp,
+        packet_kind(packet),
+        packet_source(packet),
+        &payload_text(packet, usize::MAX),
+    )
+}
+
+/// The fields of a JSON record of any kind with their JSON encoded values.
+fn record_fields(
+    timestamp: &Timestamp,
+    kind: &str,
+    source: Option<u8>,
+    payload: &str,
+) -> [(&'static str, String); 5] {
+    [
+        ("timestamp_ns", timestamp_ns(timestamp).to_string()),
+        ("data_relation", json_string(data_relation(timestamp))),
+        ("type", json_string(kind)),
+        (
+            "source",
+            source.map_or_else(|| "null".into(), |s| s.to_string()),
+        ),
+        ("payload", json_string(payload)),
+    ]
+}
+
+/// Format JSON fields as a single line JSON object.
+fn json_line(fields: &[(&str, String)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("\"{key}\":{value}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{fields}}}")
+}
+
+/// Format JSON fields as an indented multi-line JSON object.
+fn json_pretty(fields: &[(&str, String)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("    \"{key}\": {value}"))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!("  {{\n{fields}\n  }}")
+}
+
+/// A packet as a single line JSON object.
+pub fn json_record(timestamp: &Timestamp, packet: &TracePacket) -> String {
+    json_line(&json_fields(timestamp, packet))
+}
+
+/// A packet as a single line JSON object for comparison against a golden file.
+///
+/// This leaves out the timestamp data relation so that golden files of earlier versions still
+/// compare equal.
+///
+/// # Args
+/// * `timestamp` - The timestamp to include, if timestamps are compared.
+/// * `packet` - The packet.
+pub fn golden_record(timestamp: Option<&Timestamp>, packet: &TracePacket) -> String {
+    let fields = json_fields(
+        timestamp.unwrap_or(&Timestamp::Sync(Default::default())),
+        packet,
+    )
+    .iter()
+    .filter(|(key, _)| timestamp.is_some() || *key != "timestamp_ns")
+    .filter(|(key, _)| *key != "data_relation")
+    .map(|(key, value)| format!("\"{key}\":{value}"))
+    .collect::<Vec<_>>()
+    .join(",");
+    format!("{{{fields}}}")
+}
+
+/// A packet as an indented multi-line JSON object, suitable as an element of a top level array.
+pub fn json_record_pretty(timestamp: &Timestamp, packet: &TracePacket) -> String {
+    json_pretty(&json_fields(timestamp, packet))
+}
+
+/// The SQL statements that precede the packet records of the SQL format.
+///
+/// The packets are stored in a single table:
+///
+/// | Column         | Type    | Content                                                  |
+/// |----------------|---------|----------------------------------------------------------|
+/// | `id`           | INTEGER | Sequence number of the packet in the trace               |
+/// | `timestamp_ns` | INTEGER | Time of the packet in nanoseconds, see [timestamp_ns]    |
+/// | `type`         | TEXT    | The packet type, see [packet_kind]                       |
+/// | `port`         | INTEGER | Stimulus port or DWT comparator or NULL, [packet_source] |
+/// | `payload`      | TEXT    | The payload, as in the JSON format                       |
+///
+/// Information about the capture, e.g. its label, is stored as `key` and `value` text columns
+/// in a `metadata` table.
+///
+/// The statements are meant to be piped into `sqlite3`, e.g.
+/// `etf-trace decode trace.bin --format sql | sqlite3 trace.db`. With the `sqlite` feature, the
+/// database can also be written directly, see `Format::Sqlite`.
+pub fn sql_schema() -> &'static str {
+    "CREATE TABLE IF NOT EXISTS events (\n\
+     \x20   id INTEGER PRIMARY KEY,\n\
+     \x20   timestamp_ns INTEGER NOT NULL,\n\
+     \x20   type TEXT NOT NULL,\n\
+     \x20   port INTEGER,\n\
+     \x20   payload TEXT NOT NULL\n\
+     );\n\
+     CREATE INDEX IF NOT EXISTS events_type ON events (type, timestamp_ns);\n\
+     CREATE TABLE IF NOT EXISTS metadata (key TEXT PRIMARY KEY, value TEXT NOT NULL);\n\
+     BEGIN;"
+}
+
+/// A packet as an SQL insert statement into the table of [sql_schema].
+pub fn sql_record(timestamp: &Timestamp, packet: &TracePacket) -> String {
+    sql_insert(
+        timestamp,
+        packet_kind(packet),
+        packet_source(packet),
+        &payload_text(packet, usize::MAX),
+    )
+}
+
+/// An SQL insert statement of an event into the table of [sql_schema].
+fn sql_insert(timestamp: &Timestamp, kind: &str, port: Option<u8>, payload: &str) -> String {
+    format!(
+        "INSERT INTO events (timestamp_ns, type, port, payload) VALUES ({}, {}, {}, {});",
+        timestamp_ns(timestamp),
+        sql_string(kind),
+        port.map_or_else(|| "NULL".into(), |port| port.to_string()),
+        sql_string(payload)
+    )
+}
+
+/// An SQL statement recording information about the capture in the `metadata` table.
+pub fn sql_metadata(key: &str, value: &str) -> String {
+    format!(
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES ({}, {});",
+        sql_string(key),
+        sql_string(value)
+    )
+}
+
+/// The SQL statement that follows the packet records of the SQL format.
+pub fn sql_end() -> &'static str {
+    "COMMIT;"
+}
+
+/// The header row of the CSV format.
+///
+/// The columns are the time in nanoseconds (see [timestamp_ns]), the packet type (see
+/// [packet_kind]), the stimulus port or DWT comparator (see [packet_source], empty if none) and
+/// the payload as in the JSON format.
+pub fn csv_header() -> &'static str {
+    "timestamp_ns,type,port,value"
+}
+
+/// A packet as a row of the CSV format.
+pub fn csv_record(timestamp: &Timestamp, packet: &TracePacket) -> String {
+    format!(
+        "{},{},{},{}",
+        timestamp_ns(timestamp),
+        packet_kind(packet),
+        packet_source(packet)
+            .map(|s| s.to_string())
+            .unwrap_or_default(),
+        csv_field(&payload_text(packet, usize::MAX))
+    )
+}
+
+/// Number of stimulus ports of the ITM, in up to 8 pages of 32 ports.
+const STIMULUS_PORTS: u16 = 256;
+
+/// The declarations that precede the value changes of the VCD format.
+///
+/// Each stimulus port `<n>` is a 32 bit signal `port<n>` holding the last value written to it,
+/// little endian. `exception` is a 9 bit level signal holding the number of the active
+/// exception, 0 in thread mode. The time unit is a nanosecond, see [timestamp_ns].
+pub fn vcd_header() -> String {
+    let mut header = String::from("$timescale 1ns $end\n$scope module itm $end\n");
+    for port in 0..STIMULUS_PORTS {
+        header.push_str(&format!("$var wire 32 p{port} port{port} $end\n"));
+    }
+    header.push_str("$var wire 9 e exception $end\n$upscope $end\n$enddefinitions $end\n");
+    header.push_str("$dumpvars\n");
+    for port in 0..STIMULUS_PORTS {
+        header.push_str(&format!("bx p{port}\n"));
+    }
+    header.push_str("b0 e\n$end");
+    header
+}
+
+/// The value change of the VCD format a packet causes, see [vcd_header].
+///
+/// # Returns
+/// `None` for packets that do not change a signal. An exception exit does not change the
+/// level, the following return packet does.
+pub fn vcd_change(packet: &TracePacket) -> Option<String> {
+    match packet {
+        TracePacket::Instrumentation { port, payload } => {
+            Some(format!("b{:b} p{port}", le_value(payload)))
+        }
+        TracePacket::ExceptionTrace {
+            exception,
+            action: ExceptionAction::Entered | ExceptionAction::Returned,
+        } => {
+            let number = crate::dwt::exception_number(exception);
+            Some(format!("b{number:b} e"))
+        }
+        _ => None,
+    }
+}
+
+/// Encode a CSV field, quoting it if it contains a separator, a quote or a line break.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.into()
+    }
+}
+
+/// Encode a string as an SQL string literal.
+fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Encode a string as a JSON string literal.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// An access to a variable watched by a DWT comparator.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchEvent {
+    /// The DWT comparator that matched.
+    pub comparator: u8,
+    /// Whether the variable was read or written.
+    pub access: MemoryAccessType,
+    /// The value that was read or written.
+    pub value: u32,
+    /// The PC of the accessing instruction, if traced.
+    pub pc: Option<u32>,
+    /// The lower 16 bits of the accessed address, if traced.
+    pub address: Option<u16>,
+}
+
+/// Collect the data trace events of a set of packets.
+///
+/// The PC or address packet a comparator emits before its data value packet is attached to the
+/// event.
+pub fn watch_events(packets: &[TracePacket]) -> Vec<WatchEvent> {
+    let mut events = vec![];
+    let mut pcs = [None; 4];
+    let mut addresses = [None; 4];
+    for packet in packets {
+        match packet {
+            TracePacket::DataTracePC { comparator, pc } => {
+                pcs[*comparator as usize % 4] = Some(*pc);
+            }
+            TracePacket::DataTraceAddress { comparator, data } => {
+                addresses[*comparator as usize % 4] = Some(le_value(data) as u16);
+            }
+            TracePacket::DataTraceValue {
+                comparator,
+                access_type,
+                value,
+            } => events.push(WatchEvent {
+                comparator: *comparator,
+                access: access_type.clone(),
+                value: le_value(value),
+                pc: pcs[*comparator as usize % 4].take(),
+                address: addresses[*comparator as usize % 4].take(),
+            }),
+            _ => {}
+        }
+    }
+    events
+}
+
+/// A line describing a data trace event.
+///
+/// # Args
+/// * `timestamp` - The timestamp of the event.
+/// * `event` - The event.
+/// * `symbols` - Used to name the accessing function, if given.
+pub fn watch_line(timestamp: &Timestamp, event: &WatchEvent, symbols: Option<&Symbols>) -> String {
+    let access = match event.access {
+        MemoryAccessType::Read => "read",
+        MemoryAccessType::Write => "write",
+    };
+    let mut line = format!(
+        "{:>16} comparator {} {access} {:#010x}",
+        timestamp_ns(timestamp),
+        event.comparator,
+        event.value
+    );
+    if let Some(pc) = event.pc {
+        match symbols {
+            Some(symbols) => line.push_str(&format!(" pc {}", symbols.describe(pc.into()))),
+            None => line.push_str(&format!(" pc {pc:#010x}")),
+        }
+    }
+    if let Some(address) = event.address {
+        line.push_str(&format!(" address ....{address:04x}"));
+    }
+    line
+}
+
+/// The value of a little endian payload of up to four bytes.
+fn le_value(payload: &[u8]) -> u32 {
+    payload
+        .iter()
+        .rev()
+        .fold(0, |value, b| (value << 8) | *b as u32)
+}
+
+/// Distribution of the time between consecutive timestamps.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimestampDeltas {
+    /// Number of deltas.
+    pub count: usize,
+    /// Smallest delta in nanoseconds.
+    pub min: u128,
+    /// Median delta in nanoseconds.
+    pub median: u128,
+    /// 99th percentile of the deltas in nanoseconds.
+    pub p99: u128,
+    /// Largest delta in nanoseconds.
+    pub max: u128,
+}
+
+impl TimestampDeltas {
+    /// Compute the distribution of the deltas of a sequence of times in nanoseconds.
+    pub fn new(timestamps: &[u128]) -> Self {
+        let mut deltas: Vec<u128> = timestamps
+            .windows(2)
+            .map(|pair| pair[1].saturating_sub(pair[0]))
+            .collect();
+        deltas.sort_unstable();
+        let Some(&max) = deltas.last() else {
+            return Self::default();
+        };
+        let quantile = |q: usize| deltas[(deltas.len() - 1) * q / 100];
+        Self {
+            count: deltas.len(),
+            min: deltas[0],
+            median: quantile(50),
+            p99: quantile(99),
+            max,
+        }
+    }
+}
+
+impl std::fmt::Display for TimestampDeltas {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} deltas, min {} ns, median {} ns, p99 {} ns, max {} ns",
+            self.count, self.min, self.median, self.p99, self.max
+        )
+    }
+}
+
+/// Statistics of a decoded trace.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    /// Number of raw trace bytes.
+    pub bytes: usize,
+    /// Number of timestamped packet sets.
+    pub timestamps: usize,
+    /// Number of packets by [packet_kind].
+    pub packets: BTreeMap<&'static str, usize>,
+    /// Number of malformed packets that were skipped.
+    pub skipped: usize,
+    /// Number of resynchronizations after an overflow.
+    pub resyncs: usize,
+    /// Number of packets of unknown type that were ignored.
+    pub unknown: usize,
+}
+
+impl Summary {
+    /// Account for the statistics of a decoded stream.
+    pub fn add_stats(&mut self, stats: crate::DecodeStats) {
+        self.skipped += stats.skipped;
+        self.resyncs += stats.resyncs;
+        self.unknown += stats.unknown;
+    }
+
+    /// Account for a set of timestamped packets.
+    pub fn add(&mut self, packets: &TimestampedTracePackets) {
+        self.timestamps += 1;
+        for packet in &packets.packets {
+            *self.packets.entry(packet_kind(packet)).or_default() += 1;
+        }
+    }
+
+    /// Number of ITM overflow packets, i.e. how often the target dropped trace data.
+    pub fn overflows(&self) -> usize {
+        self.packets
+            .get(packet_kind(&TracePacket::Overflow))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes, {} packets in {} timestamps, {} overflows, {} resyncs, \
+             {} malformed packets skipped, {} unknown packets ignored",
+            self.bytes,
+            self.packets.values().sum::<usize>(),
+            self.timestamps,
+            self.overflows(),
+            self.resyncs,
+            self.skipped,
+            self.unknown
+        )?;
+        for (kind, count) in &self.packets {
+            write!(f, "\n{kind:>16}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An instrumentation packet with a specific value on a stimulus port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Marker {
+    /// The stimulus port.
+    pub port: u8,
+    /// The value written to the port, little endian.
+    pub value: u32,
+}
+
+impl Marker {
+    /// Check if a packet is this marker.
+    pub fn matches(&self, packet: &TracePacket) -> bool {
+        match packet {
+            TracePacket::Instrumentation { port, payload } if *port == self.port => {
+                le_value(payload) == self.value
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Marker {
+    type Err = String;
+
+    /// Parse a marker from `<port>:<value>`. The value may be given in hex with a `0x` prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected `<port>:<value>`, got `{s}`"))?;
+        let port = port
+            .parse()
+            .map_err(|e| format!("invalid port `{port}`: {e}"))?;
+        let parsed = match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+        let value = parsed.map_err(|e| format!("invalid value `{value}`: {e}"))?;
+        Ok(Self { port, value })
+    }
+}
+
+/// The region of the trace delimited by optional start and stop markers.
+///
+/// The marker packets themselves are not part of the region.
+#[derive(Clone, Debug)]
+pub struct MarkerWindow {
+    start: Option<Marker>,
+    stop: Option<Marker>,
+    started: bool,
+    stopped: bool,
+}
+
+impl MarkerWindow {
+    /// Construct a window.
+    ///
+    /// # Args
+    /// * `start` - The marker after which packets are inside the window. If not given, the window
+    ///   starts at the beginning of the trace.
+    /// * `stop` - The marker after which no more packets are inside the window. If not given, the
+    ///   window extends to the end of the trace.
+    pub fn new(start: Option<Marker>, stop: Option<Marker>) -> Self {
+        Self {
+            start,
+            stop,
+            started: start.is_none(),
+            stopped: false,
+        }
+    }
+
+    /// Remove the packets outside of the window.
+    pub fn filter(&mut self, packets: &mut Vec<TracePacket>) {
+        packets.retain(|packet| {
+            if self.stopped {
+                false
+            } else if !self.started {
+                self.started = self.start.is_some_and(|m| m.matches(packet));
+                false
+            } else if self.stop.is_some_and(|m| m.matches(packet)) {
+                self.stopped = true;
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Check if the start of the window was found.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+}
+
+/// Fixed-length binary records on a stimulus port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BinaryPort {
+    /// The stimulus port.
+    pub port: u8,
+    /// Length of a record in bytes.
+    pub len: usize,
+}
+
+impl std::str::FromStr for BinaryPort {
+    type Err = String;
+
+    /// Parse from `<port>:<record-len>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, len) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected `<port>:<record-len>`, got `{s}`"))?;
+        let port = port
+            .parse()
+            .map_err(|e| format!("invalid port `{port}`: {e}"))?;
+        let len = len
+            .parse()
+            .map_err(|e| format!("invalid record length `{len}`: {e}"))?;
+        if len == 0 {
+            return Err("the record length must not be zero".into());
+        }
+        Ok(Self { port, len })
+    }
+}
+
+/// A field of a [RecordFormat].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RecordField {
+    Text(String),
+    Unsigned(usize),
+    Signed(usize),
+    Float(usize),
+    /// The remaining bytes of the record in hex.
+    Hex,
+}
+
+/// A format string for binary records.
+///
+/// The placeholders `{u8}`, `{u16}`, `{u32}`, `{u64}`, `{i8}`, `{i16}`, `{i32}`, `{i64}`,
+/// `{f32}` and `{f64}` are replaced by consecutive little endian values of the record, `{hex}`
+/// by the remaining bytes in hex, so it has to be the last placeholder. `{{` and `}}` are literal
+/// braces. E.g. `x={i16} y={i16}` formats a record of two 16 bit coordinates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordFormat {
+    fields: Vec<RecordField>,
+}
+
+impl RecordFormat {
+    /// Minimum length of the records the format applies to, the size of all its values.
+    pub fn size(&self) -> usize {
+        self.fields
+            .iter()
+            .map(|field| match field {
+                RecordField::Unsigned(size)
+                | RecordField::Signed(size)
+                | RecordField::Float(size) => *size,
+                RecordField::Text(_) | RecordField::Hex => 0,
+            })
+            .sum()
+    }
+
+    /// Format a record of at least [RecordFormat::size] bytes.
+    ///
+    /// The values beyond the end of a shorter record are formatted as `?`.
+    pub fn apply(&self, record: &[u8]) -> String {
+        let mut offset = 0;
+        let mut s = String::new();
+        for field in &self.fields {
+            let value = match field {
+                RecordField::Unsigned(size)
+                | RecordField::Signed(size)
+                | RecordField::Float(size) => {
+                    let value = record.get(offset..offset + size).map(|value| {
+                        let mut bytes = [0; 8];
+                        bytes[..*size].copy_from_slice(value);
+                        u64::from_le_bytes(bytes)
+                    });
+                    offset += size;
+                    value
+                }
+                _ => None,
+            };
+            match (field, value) {
+                (RecordField::Text(text), _) => s.push_str(text),
+                (RecordField::Hex, _) => {
+                    s.push_str(&hex(record.get(offset..).unwrap_or_default(), usize::MAX));
+                    offset = record.len();
+                }
+                (_, None) => s.push('?'),
+                (RecordField::Unsigned(_), Some(value)) => s.push_str(&value.to_string()),
+                (RecordField::Signed(size), Some(value)) => {
+                    let shift = 64 - 8 * *size as u32;
+                    s.push_str(&(((value << shift) as i64) >> shift).to_string());
+                }
+                (RecordField::Float(4), Some(value)) => {
+                    s.push_str(&f32::from_bits(value as u32).to_string())
+                }
+                (RecordField::Float(_), Some(value)) => {
+                    s.push_str(&f64::from_bits(value).to_string())
+                }
+            }
+        }
+        s
+    }
+}
+
+impl std::str::FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let (name, tail) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or_else(|| format!("unterminated placeholder in `{s}`"))?;
+                    let field: fn(usize) -> RecordField = match name {
+                        "u8" | "u16" | "u32" | "u64" => RecordField::Unsigned,
+                        "i8" | "i16" | "i32" | "i64" => RecordField::Signed,
+                        "f32" | "f64" => RecordField::Float,
+                        "hex" => |_| RecordField::Hex,
+                        _ => return Err(format!("unknown placeholder `{{{name}}}`")),
+                    };
+                    if fields.contains(&RecordField::Hex) {
+                        return Err(format!("`{{hex}}` has to be the last value in `{s}`"));
+                    }
+                    let bits: usize = name[1..].parse().unwrap_or_default();
+                    if !text.is_empty() {
+                        fields.push(RecordField::Text(std::mem::take(&mut text)));
+                    }
+                    fields.push(field(bits / 8));
+                    chars = tail.chars();
+                }
+                '}' => return Err(format!("unmatched `}}` in `{s}`")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            fields.push(RecordField::Text(text));
+        }
+        Ok(Self { fields })
+    }
+}
+
+/// Reassembly of the byte stream of a stimulus port into fixed-length binary records.
+///
+/// The payloads of the instrumentation packets on the port are concatenated regardless of their
+/// size, so the firmware may write a record with any mix of 8, 16 and 32 bit stimulus writes.
+#[derive(Clone, Debug)]
+pub struct BinaryRecords {
+    port: BinaryPort,
+    format: Option<RecordFormat>,
+    buffer: Vec<u8>,
+}
+
+impl BinaryRecords {
+    /// Construct the reassembly of records on a port.
+    ///
+    /// # Args
+    /// * `format` - The format of the records, hex if not given. Its [RecordFormat::size] must not
+    ///   exceed the record length.
+    pub fn new(port: BinaryPort, format: Option<RecordFormat>) -> Self {
+        Self {
+            port,
+            format,
+            buffer: vec![],
+        }
+    }
+
+    /// The stimulus port of the records.
+    pub fn port(&self) -> u8 {
+        self.port.port
+    }
+
+    /// Remove the instrumentation packets of the port from `packets` and collect their payload.
+    ///
+    /// # Returns
+    /// The formatted records that were completed.
+    pub fn extract(&mut self, packets: &mut Vec<TracePacket>) -> Vec<String> {
+        packets.retain(|packet| match packet {
+            TracePacket::Instrumentation { port, payload } if *port == self.port.port => {
+                self.buffer.extend_from_slice(payload);
+                false
+            }
+            _ => true,
+        });
+        let complete = self.buffer.len() - self.buffer.len() % self.port.len;
+        let records: Vec<u8> = self.buffer.drain(..complete).collect();
+        records
+            .chunks(self.port.len)
+            .map(|record| match &self.format {
+                Some(format) => format.apply(record),
+                None => hex(record, usize::MAX),
+            })
+            .collect()
+    }
+
+    /// Number of bytes collected towards the next record.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// The text written by the firmware to a stimulus port, as a console.
+///
+/// The payloads of the instrumentation packets on the port are decoded as UTF-8. A character
+/// may be split across stimulus writes and reads of the trace, so an incomplete sequence at the
+/// end is kept until it is completed. Invalid sequences are replaced with U+FFFD.
+#[derive(Clone, Debug)]
+pub struct Console {
+    port: u8,
+    buffer: Vec<u8>,
+}
+
+impl Console {
+    /// Construct the console of a stimulus port.
+    pub fn new(port: u8) -> Self {
+        Self {
+            port,
+            buffer: vec![],
+        }
+    }
+
+    /// Collect the payload of the instrumentation packets of the port.
+    ///
+    /// # Returns
+    /// The text that was completed.
+    pub fn push(&mut self, packets: &[TracePacket]) -> String {
+        for packet in packets {
+            if let TracePacket::Instrumentation { port, payload } = packet {
+                if *port == self.port {
+                    self.buffer.extend_from_slice(payload);
+                }
+            }
+        }
+        let mut text = String::new();
+        let mut rest = self.buffer.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    // The checked prefix is valid UTF-8.
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    rest = invalid;
+                    // Without an error length the sequence is incomplete.
+                    let Some(len) = e.error_len() else {
+                        break;
+                    };
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    rest = &rest[len..];
+                }
+            }
+        }
+        self.buffer = rest.to_vec();
+        text
+    }
+
+    /// The rest of the text at the end of the trace, an incomplete sequence replaced with U+FFFD.
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.buffer).into_owned();
+        self.buffer.clear();
+        text
+    }
+}
+
+/// A binary record in an output format, as [table_row], [json_record], [sql_record] or
+/// [csv_record] print packets, with the type `binary`.
+///
+/// # Args
+/// * `port` - The stimulus port of the record.
+/// * `text` - The formatted record, see [BinaryRecords::extract].
+pub fn binary_record(format: Format, timestamp: &Timestamp, port: u8, text: &str) -> String {
+    match format {
+        Format::Log | Format::Table => format!(
+            "{:>16} {:<16} {port:>6} {text}",
+            timestamp_ns(timestamp),
+            "binary"
+        ),
+        Format::Json => json_line(&record_fields(timestamp, "binary", Some(port), text)),
+        Format::Sql => sql_insert(timestamp, "binary", Some(port), text),
+        #[cfg(feature = "sqlite")]
+        Format::Sqlite => sql_insert(timestamp, "binary", Some(port), text),
+        Format::Csv => format!(
+            "{},binary,{port},{}",
+            timestamp_ns(timestamp),
+            csv_field(text)
+        ),
+        // Records are not signals, they are kept as comments after the preceding change.
+        Format::Vcd => format!("$comment binary port {port}: {text} $end"),
+    }
+}
+
+/// A binary record as an element of the pretty printed JSON array, see [json_record_pretty].
+pub fn binary_record_pretty(timestamp: &Timestamp, port: u8, text: &str) -> String {
+    json_pretty(&record_fields(timestamp, "binary", Some(port), text))
+}
@@ -0,0 +1,67 @@
+//! Live terminal plot of the ETF fill level
+//!
+//! The fill level is sampled on every poll of the trace memory and rendered as a sparkline on
+//! stderr. This shows whether the trace memory fills steadily or in bursts, which helps with
+//! tuning the rate of the instrumentation relative to the buffer size.
+use std::{
+    collections::VecDeque,
+    io::{IsTerminal, Write},
+};
+
+/// Bar characters for increasing levels.
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Render fill level samples (in percent) as a sparkline, one character per sample.
+pub fn sparkline(samples: impl IntoIterator<Item = u8>) -> String {
+    samples
+        .into_iter()
+        .map(|percent| {
+            let level = percent.min(100) as usize * (BARS.len() - 1) / 100;
+            BARS[level]
+        })
+        .collect()
+}
+
+/// A sparkline of the most recent fill level samples, redrawn in place on stderr.
+pub struct FillPlot {
+    samples: VecDeque<u8>,
+    width: usize,
+}
+
+impl FillPlot {
+    /// Construct a plot of the most recent `width` samples.
+    ///
+    /// # Returns
+    /// `None` if stderr is not a terminal, as the plot can not be drawn in place.
+    pub fn new(width: usize) -> Option<Self> {
+        std::io::stderr().is_terminal().then(|| Self {
+            samples: VecDeque::with_capacity(width),
+            width,
+        })
+    }
+
+    /// Add a fill level sample in percent and redraw the plot.
+    pub fn sample(&mut self, percent: u8) {
+        if self.samples.len() == self.width {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(percent);
+        let mut stderr = std::io::stderr().lock();
+        // Drawing is best effort and must not interfere with the capture.
+        write!(
+            stderr,
+            "\r\x1b[2K{percent:>3}% {}",
+            sparkline(self.samples.iter().copied())
+        )
+        .and_then(|_| stderr.flush())
+        .ok();
+    }
+}
+
+impl Drop for FillPlot {
+    fn drop(&mut self) {
+        if !self.samples.is_empty() {
+            eprintln!();
+        }
+    }
+}
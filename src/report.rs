@@ -0,0 +1,282 @@
+//! Capture metadata
+//!
+//! The raw trace dump carries no information about how it was captured. A [CaptureReport] is
+//! written next to it as a JSON sidecar file.
+//!
+//! The report records the size and a CRC-32 checksum of the dump. Checking them before decoding
+//! tells a dump that was truncated or corrupted after the capture from a corrupt trace.
+//!
+//! Alternatively, the dump can start with a [DumpHeader] that describes the capture, so that it
+//! can be decoded without its sidecar file.
+use crate::{etf::Mode, output::json_string, EtfError};
+use std::{
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+/// The resolved configuration of a capture.
+///
+/// This records the configuration as found on the target after the setup, not as requested, so
+/// that two captures can be compared.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaptureConfig {
+    /// The target name.
+    pub target: String,
+    /// Index of the traced core.
+    pub core: usize,
+    /// The ETF mode, `None` if it is not a valid mode.
+    pub mode: Option<Mode>,
+    /// Frequency of the ITM timestamp clock in Hz used for decoding.
+    pub coreclk: u32,
+    /// The enabled input ports of the trace funnel, `None` if there is no funnel or it can not be
+    /// determined.
+    pub funnel_ports: Option<Vec<u8>>,
+    /// Base address of the ETF.
+    pub etf_address: u64,
+    /// Size of the ETF trace memory in bytes.
+    pub fifo_size: u32,
+    /// The ETF formatter is enabled.
+    pub formatter: bool,
+    /// Trace ID of the decoded trace source.
+    pub trace_id: u8,
+}
+
+impl CaptureConfig {
+    /// The JSON encoded fields of the configuration.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mode = self
+            .mode
+            .map(|mode| format!("{mode:?}").to_lowercase())
+            .map_or_else(|| "null".into(), |mode| json_string(&mode));
+        let funnel_ports = self.funnel_ports.as_ref().map_or_else(
+            || "null".into(),
+            |ports| {
+                let ports: Vec<_> = ports.iter().map(|port| port.to_string()).collect();
+                format!("[{}]", ports.join(", "))
+            },
+        );
+        vec![
+            ("target", json_string(&self.target)),
+            ("core", self.core.to_string()),
+            ("mode", mode),
+            ("coreclk", self.coreclk.to_string()),
+            ("funnel_ports", funnel_ports),
+            (
+                "etf_address",
+                json_string(&format!("{:#x}", self.etf_address)),
+            ),
+            ("fifo_size", self.fifo_size.to_string()),
+            ("formatter", self.formatter.to_string()),
+            ("trace_id", self.trace_id.to_string()),
+        ]
+    }
+
+    /// The configuration as a JSON object.
+    pub fn to_json(&self) -> String {
+        json_object(&self.fields(), 0)
+    }
+}
+
+/// Description of a capture.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaptureReport {
+    /// The user provided label of the capture.
+    pub label: Option<String>,
+    /// The configuration of the capture.
+    pub config: CaptureConfig,
+    /// Number of raw trace bytes in the dump.
+    pub bytes: u64,
+    /// CRC-32 of the dump, see [crc32].
+    pub crc32: u32,
+}
+
+impl CaptureReport {
+    /// The path of the sidecar file of a trace dump, the dump path with `.json` appended.
+    pub fn sidecar_path(dump: impl AsRef<Path>) -> PathBuf {
+        let mut path = dump.as_ref().as_os_str().to_owned();
+        path.push(".json");
+        path.into()
+    }
+
+    /// The report as a JSON object.
+    pub fn to_json(&self) -> String {
+        let fields = [
+            (
+                "label",
+                self.label
+                    .as_deref()
+                    .map_or_else(|| "null".into(), json_string),
+            ),
+            ("config", json_object(&self.config.fields(), 1)),
+            ("bytes", self.bytes.to_string()),
+            ("crc32", json_string(&format!("{:#010x}", self.crc32))),
+        ];
+        format!("{}\n", json_object(&fields, 0))
+    }
+
+    /// The size and checksum of the dump recorded in a sidecar file, see [Self::to_json].
+    ///
+    /// # Returns
+    /// `None` if they are missing, e.g. in the sidecar of an older version.
+    pub fn integrity(json: &str) -> Option<(u64, u32)> {
+        let (mut bytes, mut crc32) = (None, None);
+        for (key, value) in json_values(json, 1) {
+            match key {
+                "bytes" => bytes = value.parse().ok(),
+                "crc32" => {
+                    let value = value.trim_matches('"').strip_prefix("0x")?;
+                    crc32 = u32::from_str_radix(value, 16).ok();
+                }
+                _ => {}
+            }
+        }
+        Some((bytes?, crc32?))
+    }
+
+    /// The timestamp clock frequency recorded in a sidecar file or a [DumpHeader], see
+    /// [CaptureConfig::coreclk].
+    pub fn coreclk(json: &str) -> Option<u32> {
+        json_values(json, 2)
+            .find(|(key, _)| *key == "coreclk")
+            .and_then(|(_, value)| value.parse().ok())
+    }
+}
+
+/// The magic bytes at the start of a dump with a [DumpHeader].
+pub const HEADER_MAGIC: [u8; 8] = *b"ETFTRACE";
+
+/// The version of the [DumpHeader] format.
+pub const HEADER_VERSION: u16 = 1;
+
+/// Size of the fixed part of a [DumpHeader]: the magic, the version and the JSON length.
+const HEADER_PREFIX: usize = HEADER_MAGIC.len() + 2 + 4;
+
+/// A header prepended to the ITM trace data of a dump.
+///
+/// The header consists of the [HEADER_MAGIC], the [HEADER_VERSION] (u16) and the length of the
+/// JSON object that follows (u32), both little endian, and that JSON object. A dump without the
+/// magic is raw ITM trace data.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DumpHeader {
+    /// Version of the tool that wrote the dump.
+    pub tool: String,
+    /// Time of the capture, in seconds since the Unix epoch.
+    pub captured: u64,
+    /// The configuration of the capture.
+    pub config: CaptureConfig,
+}
+
+impl DumpHeader {
+    /// The header as a JSON object, see [CaptureReport::coreclk].
+    pub fn to_json(&self) -> String {
+        let fields = [
+            ("tool", json_string(&self.tool)),
+            ("captured", self.captured.to_string()),
+            ("config", json_object(&self.config.fields(), 1)),
+        ];
+        format!("{}\n", json_object(&fields, 0))
+    }
+
+    /// The encoded header, to be written before the trace data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let json = self.to_json();
+        let mut bytes = HEADER_MAGIC.to_vec();
+        bytes.extend_from_slice(&HEADER_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(json.as_bytes());
+        bytes
+    }
+
+    /// Read the header at the start of a dump.
+    ///
+    /// # Returns
+    /// The JSON object of the header, `None` if the dump has no header, and the trace data.
+    pub fn read<R: Read>(mut reader: R) -> Result<(Option<String>, impl Read), EtfError> {
+        let mut magic = vec![];
+        (&mut reader)
+            .take(HEADER_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if magic != HEADER_MAGIC {
+            // Raw trace data, including the bytes read.
+            return Ok((None, Cursor::new(magic).chain(reader)));
+        }
+        let mut prefix = [0; HEADER_PREFIX - HEADER_MAGIC.len()];
+        reader
+            .read_exact(&mut prefix)
+            .map_err(|_| EtfError::DumpHeader("truncated".into()))?;
+        let version = u16::from_le_bytes([prefix[0], prefix[1]]);
+        if version != HEADER_VERSION {
+            return Err(EtfError::DumpHeader(format!(
+                "version {version} is not supported"
+            )));
+        }
+        let len = u32::from_le_bytes([prefix[2], prefix[3], prefix[4], prefix[5]]);
+        let mut json = vec![0; len as usize];
+        reader
+            .read_exact(&mut json)
+            .map_err(|_| EtfError::DumpHeader("truncated".into()))?;
+        let json =
+            String::from_utf8(json).map_err(|_| EtfError::DumpHeader("invalid UTF-8".into()))?;
+        Ok((Some(json), Cursor::new(vec![]).chain(reader)))
+    }
+}
+
+/// The keys and raw values of the fields at a nesting `level` of a JSON object formatted by
+/// [json_object], e.g. 1 for the fields of the top level object.
+fn json_values(json: &str, level: usize) -> impl Iterator<Item = (&str, &str)> {
+    let indent = "  ".repeat(level);
+    json.lines().filter_map(move |line| {
+        let field = line.strip_prefix(&indent)?.strip_prefix('"')?;
+        let (key, value) = field.split_once("\":")?;
+        Some((key, value.trim().trim_end_matches(',')))
+    })
+}
+
+/// Lookup table of the CRC-32 with the reflected polynomial 0xEDB88320.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 (as used by zlib and PNG) of all data read from `reader`.
+pub fn crc32(mut reader: impl Read) -> std::io::Result<u32> {
+    let mut crc = !0u32;
+    let mut buf = [0; 4096];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(!crc),
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for byte in &buf[..len] {
+            crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+    }
+}
+
+/// Format JSON encoded fields as a pretty printed JSON object at a nesting `level`.
+fn json_object(fields: &[(&str, String)], level: usize) -> String {
+    let indent = "  ".repeat(level);
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("{indent}  \"{key}\": {value}"))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!("{{\n{fields}\n{indent}}}")
+}
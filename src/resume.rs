@@ -0,0 +1,60 @@
+//! Resumption of a capture across restarts
+//!
+//! A capture with `--resume` appends to its ITM trace dump and records its progress in a state
+//! file. A later run with the same state file continues the dump.
+//!
+//! The trace data produced while no capture runs is lost: attaching discards the residual data
+//! of the ETF. The end of the previous run is also not necessarily a packet boundary. Each
+//! resumption therefore appends a [SEAM] that lets the decoder synchronize again and marks the
+//! loss. Neither the ETF write pointer nor the position of the last synchronization are saved in
+//! the [ResumeState], the resumed run starts from the ETF contents at attach like a new one.
+use crate::output::json_string;
+
+/// The bytes appended to the dump where a capture is resumed.
+///
+/// The zeros complete a partial packet at the end of the previous run, with up to four payload
+/// bytes, and leave enough zeros for a synchronization packet. The following overflow packet
+/// marks the trace data lost between the runs.
+pub const SEAM: [u8; 12] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x80, 0x70];
+
+/// The progress of a resumable capture.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResumeState {
+    /// Number of bytes in the ITM trace dump when the state was saved.
+    pub bytes: u64,
+    /// Number of times the capture was resumed.
+    pub resumptions: u32,
+}
+
+impl ResumeState {
+    /// The state as a JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\n  {}: {},\n  {}: {}\n}}\n",
+            json_string("bytes"),
+            self.bytes,
+            json_string("resumptions"),
+            self.resumptions
+        )
+    }
+
+    /// Parse a state written by [Self::to_json].
+    ///
+    /// # Returns
+    /// `None` if the state is not a flat JSON object with the integer fields of the state.
+    /// Unknown fields are ignored.
+    pub fn from_json(json: &str) -> Option<Self> {
+        let mut state = Self::default();
+        let fields = json.trim().strip_prefix('{')?.strip_suffix('}')?;
+        for field in fields.split(',').filter(|field| !field.trim().is_empty()) {
+            let (key, value) = field.split_once(':')?;
+            let value = value.trim();
+            match key.trim().strip_prefix('"')?.strip_suffix('"')? {
+                "bytes" => state.bytes = value.parse().ok()?,
+                "resumptions" => state.resumptions = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        Some(state)
+    }
+}
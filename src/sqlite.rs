@@ -0,0 +1,63 @@
+//! SQLite database output
+//!
+//! A [Database] holds the decoded packets in the tables of [sql_schema], the same as the SQL
+//! statements of [Format::Sql] create when piped into `sqlite3`.
+use crate::{
+    output::{binary_record, sql_end, sql_metadata, sql_record, sql_schema, Format},
+    EtfError,
+};
+use itm::{Timestamp, TracePacket};
+use rusqlite::Connection;
+use std::path::Path;
+
+/// An SQLite database the packets of a trace are written to.
+///
+/// All packets are inserted in a single transaction, which [Database::commit] ends. Without the
+/// commit, e.g. after a failed write, the database holds no packets.
+pub struct Database {
+    connection: Connection,
+}
+
+impl Database {
+    /// Open or create the database at `path` and create the tables of [sql_schema] unless they
+    /// exist. Packets of a previous trace in the database are kept.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, EtfError> {
+        let connection = Connection::open(path)?;
+        // The schema ends by beginning the transaction of the packets.
+        connection.execute_batch(sql_schema())?;
+        Ok(Self { connection })
+    }
+
+    /// Insert a packet, see [sql_record].
+    pub fn insert_packet(
+        &mut self,
+        timestamp: &Timestamp,
+        packet: &TracePacket,
+    ) -> Result<(), EtfError> {
+        self.execute(&sql_record(timestamp, packet))
+    }
+
+    /// Insert a binary record, see [binary_record].
+    pub fn insert_record(
+        &mut self,
+        timestamp: &Timestamp,
+        port: u8,
+        text: &str,
+    ) -> Result<(), EtfError> {
+        self.execute(&binary_record(Format::Sql, timestamp, port, text))
+    }
+
+    /// Record information about the capture, see [sql_metadata].
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> Result<(), EtfError> {
+        self.execute(&sql_metadata(key, value))
+    }
+
+    /// Commit the packets inserted.
+    pub fn commit(self) -> Result<(), EtfError> {
+        self.execute(sql_end())
+    }
+
+    fn execute(&self, sql: &str) -> Result<(), EtfError> {
+        Ok(self.connection.execute_batch(sql)?)
+    }
+}
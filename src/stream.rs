@@ -0,0 +1,220 @@
+//! Pacing of a streaming capture
+//!
+//! A streaming capture polls the ETF and drains it on every poll. Polling too rarely lets the
+//! FIFO run full and stall the trace source (or lose data in circular mode), polling too often
+//! spends probe bandwidth on empty reads. A [Pacer] adapts the polling interval so that the fill
+//! level found at each poll stays between two watermarks.
+//!
+//! With the `async` feature, `follow` runs a streaming capture that awaits the polling interval
+//! instead of sleeping.
+use crate::EtfError;
+use std::time::Duration;
+#[cfg(feature = "async")]
+use {
+    crate::capture::CaptureSession,
+    std::{
+        future::Future,
+        pin::Pin,
+        sync::{
+            mpsc::{RecvTimeoutError, Sender},
+            Arc, Mutex, OnceLock,
+        },
+        task::{Context, Poll, Waker},
+        time::Instant,
+    },
+};
+
+/// Fill level thresholds in percent of the FIFO size.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Below this fill level the polling interval is increased.
+    pub low: u8,
+    /// At or above this fill level the FIFO is drained again immediately and the polling interval
+    /// is decreased.
+    pub high: u8,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Self { low: 25, high: 75 }
+    }
+}
+
+impl Watermarks {
+    /// Construct watermarks, validating that `low < high <= 100`.
+    pub fn new(low: u8, high: u8) -> Result<Self, EtfError> {
+        if low >= high || high > 100 {
+            return Err(EtfError::Watermarks { low, high });
+        }
+        Ok(Self { low, high })
+    }
+}
+
+/// Shortest polling interval.
+pub const MIN_INTERVAL: Duration = Duration::from_micros(50);
+
+/// Longest polling interval. This bounds the time until the pacer reacts to an increase of the
+/// trace data rate: a source that fills the FIFO faster than this stalls once before the interval
+/// has adapted.
+pub const MAX_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Adaptive polling interval of a streaming capture.
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    watermarks: Watermarks,
+    interval: Duration,
+}
+
+impl Pacer {
+    /// Construct a pacer starting at the shortest interval.
+    pub fn new(watermarks: Watermarks) -> Self {
+        Self {
+            watermarks,
+            interval: MIN_INTERVAL,
+        }
+    }
+
+    /// The current polling interval.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Update the interval with the fill level found at a poll, before draining.
+    ///
+    /// # Returns
+    /// The time to wait after draining before the next poll.
+    pub fn next(&mut self, percent: u8) -> Duration {
+        if percent >= self.watermarks.high {
+            self.interval = (self.interval / 2).max(MIN_INTERVAL);
+            Duration::ZERO
+        } else {
+            if percent < self.watermarks.low {
+                self.interval = (self.interval * 2).min(MAX_INTERVAL);
+            }
+            self.interval
+        }
+    }
+}
+
+/// Drain the ETF of `capture` until `f` returns `false`, awaiting the polling interval.
+///
+/// The capture is switched to this crate's readout, see [CaptureSession::keep_frames].
+///
+/// The register accesses still block the task. Run this on a task that may block, e.g. a
+/// dedicated thread of the runtime, and pass the data to the other tasks from `f`.
+///
+/// # Args
+/// * `f` - Called with the ITM trace data of each poll that returned data.
+///
+/// # Returns
+/// The number of ITM trace bytes captured.
+#[cfg(feature = "async")]
+pub async fn follow(
+    capture: &mut CaptureSession,
+    watermarks: Watermarks,
+    mut f: impl FnMut(Vec<u8>) -> bool,
+) -> Result<usize, EtfError> {
+    // The reads form one stream, which the probe-rs readout does not continue across reads.
+    capture.keep_frames();
+    let mut pacer = Pacer::new(watermarks);
+    let mut bytes = 0;
+    loop {
+        // The fill level before draining shows how much data accumulated since the last poll.
+        let wait = pacer.next(capture.etf()?.fill_percent()?);
+        let itm_trace = capture.read()?;
+        if !itm_trace.is_empty() {
+            bytes += itm_trace.len();
+            if !f(itm_trace) {
+                return Ok(bytes);
+            }
+        }
+        Delay::new(wait).await;
+    }
+}
+
+/// A future that completes after a duration.
+///
+/// This does not depend on an async runtime: a single timer thread, started with the first
+/// pending delay, wakes the task when the duration has passed.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct Delay {
+    deadline: Instant,
+    /// The waker of the last poll, shared with the timer thread once the delay is scheduled.
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+#[cfg(feature = "async")]
+impl Delay {
+    /// Construct a delay of `duration` from now.
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            deadline: Instant::now() + duration,
+            waker: None,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.waker {
+            Some(waker) => waker.lock().unwrap().clone_from(cx.waker()),
+            None => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                schedule(self.deadline, waker.clone());
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// The deadline of a pending [Delay] and the waker to wake at it.
+#[cfg(feature = "async")]
+type Timer = (Instant, Arc<Mutex<Waker>>);
+
+/// Wake `waker` at `deadline` from the timer thread, starting it on first use.
+#[cfg(feature = "async")]
+fn schedule(deadline: Instant, waker: Arc<Mutex<Waker>>) {
+    static TIMER: OnceLock<Sender<Timer>> = OnceLock::new();
+    let sender = TIMER.get_or_init(|| {
+        let (sender, receiver) = std::sync::mpsc::channel::<Timer>();
+        std::thread::spawn(move || {
+            let mut pending: Vec<Timer> = vec![];
+            loop {
+                let next = pending.iter().map(|(deadline, _)| *deadline).min();
+                let received = match next {
+                    Some(next) => {
+                        receiver.recv_timeout(next.saturating_duration_since(Instant::now()))
+                    }
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(timer) => pending.push(timer),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                let now = Instant::now();
+                pending.retain(|(deadline, waker)| {
+                    let expired = *deadline <= now;
+                    if expired {
+                        waker.lock().unwrap().wake_by_ref();
+                    }
+                    !expired
+                });
+            }
+        });
+        sender
+    });
+    // The sender is never dropped, so the timer thread does not exit.
+    sender
+        .send((deadline, waker))
+        .expect("The timer thread exited");
+}
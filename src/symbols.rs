@@ -0,0 +1,117 @@
+//! Symbolication of addresses using the ELF file of the firmware
+use crate::EtfError;
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind};
+use std::ops::Range;
+
+/// Address range of the ITM registers.
+const ITM_REGISTERS: Range<u32> = 0xE000_0000..0xE000_1000;
+
+/// Address range of the DWT registers.
+const DWT_REGISTERS: Range<u32> = 0xE000_1000..0xE000_2000;
+
+/// A symbol covering a range of addresses.
+#[derive(Clone, Debug)]
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+/// The function and data symbols of a firmware image.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// Symbols sorted by address.
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    /// Load the symbol table of an ELF file.
+    pub fn from_elf(path: impl AsRef<std::path::Path>) -> Result<Self, EtfError> {
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data)?;
+        let mut symbols: Vec<_> = file
+            .symbols()
+            .filter(|s| matches!(s.kind(), SymbolKind::Text | SymbolKind::Data))
+            .filter_map(|s| {
+                Some(Symbol {
+                    // The LSB of Thumb function addresses is set.
+                    address: s.address() & !1,
+                    size: s.size(),
+                    name: s.name().ok()?.to_string(),
+                })
+            })
+            .collect();
+        symbols.sort_by_key(|s| s.address);
+        Ok(Self { symbols })
+    }
+
+    /// Find the symbol containing an address.
+    ///
+    /// # Returns
+    /// The symbol name and the offset of the address into the symbol.
+    pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = &self.symbols[..index]
+            .iter()
+            .rev()
+            .find(|s| address < s.address + s.size.max(1))?;
+        Some((&symbol.name, address - symbol.address))
+    }
+
+    /// Format an address with its symbol, if known.
+    pub fn describe(&self, address: u64) -> String {
+        match self.lookup(address) {
+            Some((name, 0)) => format!("{address:#010x} ({name})"),
+            Some((name, offset)) => format!("{address:#010x} ({name}+{offset:#x})"),
+            None => format!("{address:#010x}"),
+        }
+    }
+}
+
+/// Indications that a firmware image uses the trace units.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceUsage {
+    /// The firmware appears to access the ITM.
+    pub itm: bool,
+    /// The firmware appears to access the DWT.
+    pub dwt: bool,
+}
+
+impl TraceUsage {
+    /// Inspect an ELF file for the use of the ITM and DWT.
+    ///
+    /// # Note
+    /// This is a heuristic. A unit is considered in use if a symbol name contains its name as a
+    /// word (e.g. `ITM_SendChar` or `cortex_m::itm::write_all`) or if the code contains the
+    /// address of one of its registers as a literal. Addresses constructed by instructions are
+    /// not found.
+    pub fn from_elf(path: impl AsRef<std::path::Path>) -> Result<Self, EtfError> {
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data)?;
+        let mut usage = Self::default();
+        for symbol in file.symbols() {
+            let Ok(name) = symbol.name() else {
+                continue;
+            };
+            // Mangled names separate path components with their lengths, so split on digits too.
+            for word in name.split(|c: char| !c.is_ascii_alphabetic()) {
+                usage.itm |= word.eq_ignore_ascii_case("itm");
+                usage.dwt |= word.eq_ignore_ascii_case("dwt");
+            }
+        }
+        for section in file.sections() {
+            if section.kind() != SectionKind::Text {
+                continue;
+            }
+            let Ok(code) = section.data() else {
+                continue;
+            };
+            for word in code.chunks_exact(4) {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                usage.itm |= ITM_REGISTERS.contains(&word);
+                usage.dwt |= DWT_REGISTERS.contains(&word);
+            }
+        }
+        Ok(usage)
+    }
+}
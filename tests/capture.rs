@@ -0,0 +1,104 @@
+use etf_trace::{
+    capture::{probe_selector, probes_json, select_probe, Funnel, Tmc},
+    etf::{Configuration, Mode, Status},
+    EtfError,
+};
+use probe_rs::{DebugProbeInfo, DebugProbeType};
+
+fn probe(serial: &str) -> DebugProbeInfo {
+    DebugProbeInfo::new(
+        "STLink V3",
+        0x0483,
+        0x374e,
+        Some(serial.into()),
+        DebugProbeType::StLink,
+        None,
+    )
+}
+
+#[test]
+fn probe_selection() {
+    let probes = [probe("0039"), probe("003900"), probe("004A")];
+    let serial =
+        |selected: Result<&DebugProbeInfo, EtfError>| selected.unwrap().serial_number.clone();
+    assert_eq!(serial(select_probe(&probes, None)), Some("0039".into()));
+    // An exact match wins over other probes with that prefix.
+    assert_eq!(
+        serial(select_probe(&probes, Some("0039"))),
+        Some("0039".into())
+    );
+    assert_eq!(
+        serial(select_probe(&probes, Some("004"))),
+        Some("004A".into())
+    );
+    assert!(matches!(
+        select_probe(&probes, Some("003")),
+        Err(EtfError::AmbiguousProbe { matches, .. }) if matches == ["0039", "003900"]
+    ));
+    // An index, the serial numbers with leading zeros are not mistaken for one.
+    assert_eq!(
+        serial(select_probe(&probes, Some("1"))),
+        Some("003900".into())
+    );
+    assert!(matches!(
+        select_probe(&probes, Some("3")),
+        Err(EtfError::ProbeNotFound { probes, .. }) if probes[2] == "2: STLink V3 (0483:374e:004A)"
+    ));
+    // USB IDs, with a serial number if they are ambiguous.
+    assert_eq!(probe_selector(&probes[2]), "0483:374e:004A");
+    assert_eq!(
+        serial(select_probe(&probes, Some("0483:374e:004A"))),
+        Some("004A".into())
+    );
+    assert!(matches!(
+        select_probe(&probes, Some("0483:374e")),
+        Err(EtfError::AmbiguousProbe { matches, .. }) if matches.len() == 3
+    ));
+    assert!(matches!(
+        select_probe(&probes, Some("0483:374f")),
+        Err(EtfError::ProbeNotFound { .. })
+    ));
+}
+
+#[test]
+fn probe_list_json() {
+    assert_eq!(probes_json(&[]), "[]");
+    let mut unnamed = probe("");
+    unnamed.serial_number = None;
+    assert_eq!(
+        probes_json(&[probe("0039"), unnamed]),
+        r#"[
+  {"index": 0, "identifier": "STLink V3", "vid": 1155, "pid": 14158, "serial": "0039"},
+  {"index": 1, "identifier": "STLink V3", "vid": 1155, "pid": 14158, "serial": null}
+]"#
+    );
+}
+
+#[test]
+fn inspection() {
+    let funnel = Funnel {
+        address: 0xE00E_4000,
+        ports: vec![0, 2],
+        selected: true,
+    };
+    assert_eq!(
+        funnel.to_string(),
+        "Funnel at 0xe00e4000: enabled inputs [0, 2], feeds the ETF"
+    );
+    let tmc = Tmc {
+        address: 0xE00E_3000,
+        part: 0x961,
+        configuration: Some(Configuration::Etf),
+        size: Some(4096),
+        mode: Some(Mode::Circular),
+        capture_enabled: false,
+        formatter: true,
+        status: Status::from(0b1_1101),
+        selected: true,
+    };
+    assert_eq!(
+        tmc.to_string(),
+        "ETF at 0xe00e3000 (part 0x961): 4096 bytes, mode Some(Circular), capture disabled, \
+         formatter enabled, status 0x1d [full, ready, formatter empty, empty], captured into"
+    );
+}
@@ -0,0 +1,376 @@
+use etf_trace::{
+    decode_trace, ClockCheck, DecodeOptions, DecodeStats, Decoder, EtfError, OnError, PacketClass,
+    Progress,
+};
+use itm::{
+    cortex_m::{Exception, VectActive},
+    DecoderError, ExceptionAction, MalformedPacket, Timestamp, TimestampDataRelation, TracePacket,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+type Packets = Vec<(Timestamp, Vec<TracePacket>)>;
+
+/// Decode a fixture with a 1 MHz timestamp clock, i.e. one tick per microsecond.
+fn decode(
+    fixture: &str,
+    on_error: OnError,
+    global_timestamps: bool,
+) -> (Result<DecodeStats, EtfError>, Packets) {
+    let options = DecodeOptions {
+        on_error,
+        global_timestamps,
+        ..Default::default()
+    };
+    decode_with(fixture, &options)
+}
+
+fn decode_with(fixture: &str, options: &DecodeOptions) -> (Result<DecodeStats, EtfError>, Packets) {
+    let data = std::fs::read(format!("tests/fixtures/{fixture}")).unwrap();
+    let mut packets = vec![];
+    let res = decode_trace(data.as_slice(), options, |p| {
+        assert!(p.malformed_packets.is_empty());
+        packets.push((p.timestamp, p.packets));
+    });
+    (res, packets)
+}
+
+fn instrumentation(port: u8, payload: &[u8]) -> TracePacket {
+    TracePacket::Instrumentation {
+        port,
+        payload: payload.to_vec(),
+    }
+}
+
+fn at(us: u64) -> Timestamp {
+    Timestamp::Sync(Duration::from_micros(us))
+}
+
+#[test]
+fn valid() {
+    let (res, packets) = decode("valid.dump", OnError::Abort, false);
+    assert_eq!(res.unwrap().skipped, 0);
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"H")]),
+            (at(134), vec![instrumentation(1, &[0x34, 0x12])]),
+            (
+                at(136),
+                vec![TracePacket::ExceptionTrace {
+                    exception: VectActive::Exception(Exception::SysTick),
+                    action: ExceptionAction::Entered,
+                }]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn malformed_abort() {
+    let (res, packets) = decode("malformed.dump", OnError::Abort, false);
+    assert!(matches!(
+        res,
+        Err(EtfError::Decode(DecoderError::MalformedPacket(
+            MalformedPacket::InvalidHardwareDisc { disc_id: 3, .. }
+        )))
+    ));
+    assert_eq!(
+        packets,
+        [(at(1), vec![TracePacket::Sync, instrumentation(0, b"A")])]
+    );
+}
+
+#[test]
+fn malformed_skip() {
+    let (res, packets) = decode("malformed.dump", OnError::Skip, false);
+    assert_eq!(res.unwrap().skipped, 1);
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"A")]),
+            (at(3), vec![instrumentation(0, b"B")]),
+            (at(6), vec![instrumentation(0, b"C")]),
+        ]
+    );
+}
+
+#[test]
+fn malformed_resync() {
+    let (res, packets) = decode("resync.dump", OnError::Resync, false);
+    assert_eq!(
+        res.unwrap(),
+        DecodeStats {
+            skipped: 0,
+            resyncs: 1,
+            unknown: 0,
+            clock_suggestion: None,
+        }
+    );
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"A")]),
+            // The packets up to the synchronization are discarded.
+            (at(3), vec![TracePacket::Sync, instrumentation(0, b"C")]),
+        ]
+    );
+}
+
+#[test]
+fn tolerate() {
+    let options = |tolerate| DecodeOptions {
+        on_error: OnError::Abort,
+        tolerate,
+        ..Default::default()
+    };
+    // The malformed packet is a hardware source packet.
+    let (res, packets) = decode_with("malformed.dump", &options(vec![PacketClass::Hardware]));
+    assert_eq!(res.unwrap().skipped, 1);
+    assert_eq!(packets, decode("malformed.dump", OnError::Skip, false).1);
+    let (res, _) = decode_with("malformed.dump", &options(vec![PacketClass::Software]));
+    assert!(matches!(res, Err(EtfError::Decode(_))));
+}
+
+#[test]
+fn progress() {
+    let progress = Arc::new(Progress::default());
+    let options = DecodeOptions {
+        on_error: OnError::Skip,
+        progress: Some(progress.clone()),
+        ..Default::default()
+    };
+    let (res, packets) = decode_with("malformed.dump", &options);
+    assert_eq!(res.unwrap().skipped, 1);
+    assert_eq!(progress.skipped.load(Ordering::Relaxed), 1);
+    let total: usize = packets.iter().map(|(_, packets)| packets.len()).sum();
+    assert_eq!(progress.packets.load(Ordering::Relaxed), total);
+}
+
+#[test]
+fn extension_page() {
+    let expected = [
+        (at(1), vec![TracePacket::Sync, instrumentation(1, b"A")]),
+        // Port 1 of page 1.
+        (
+            at(3),
+            vec![
+                TracePacket::Extension { page: 1 },
+                instrumentation(33, b"B"),
+            ],
+        ),
+        (
+            at(4),
+            vec![TracePacket::Extension { page: 0 }, instrumentation(1, b"C")],
+        ),
+    ];
+    let (res, packets) = decode("page.dump", OnError::Abort, false);
+    assert_eq!(res.unwrap().skipped, 0);
+    assert_eq!(packets, expected);
+
+    let options = DecodeOptions {
+        on_error: OnError::Abort,
+        decoder: Decoder::Builtin,
+        ..Default::default()
+    };
+    let (res, packets) = decode_with("page.dump", &options);
+    assert_eq!(res.unwrap().unknown, 0);
+    assert_eq!(packets, expected);
+}
+
+#[test]
+fn sequence() {
+    let options = DecodeOptions {
+        on_error: OnError::Abort,
+        sequence: true,
+        ..Default::default()
+    };
+    let (res, packets) = decode_with("valid.dump", &options);
+    assert_eq!(res.unwrap().skipped, 0);
+    let (_, timed) = decode("valid.dump", OnError::Abort, false);
+    // The same packets in the same order, the timestamps are reported as packets.
+    let timed: Vec<_> = timed.into_iter().flat_map(|(_, packets)| packets).collect();
+    let untimed: Vec<_> = packets
+        .iter()
+        .flat_map(|(_, packets)| packets)
+        .filter(|packet| !matches!(packet, TracePacket::LocalTimestamp1 { .. }))
+        .filter(|packet| !matches!(packet, TracePacket::LocalTimestamp2 { .. }))
+        .cloned()
+        .collect();
+    assert_eq!(untimed, timed);
+    for (index, (timestamp, packets)) in packets.iter().enumerate() {
+        assert_eq!(
+            *timestamp,
+            Timestamp::Sync(Duration::from_nanos(index as u64))
+        );
+        assert_eq!(packets.len(), 1);
+    }
+}
+
+#[test]
+fn global_timestamps() {
+    let (res, packets) = decode("gts.dump", OnError::Abort, true);
+    assert_eq!(res.unwrap().skipped, 0);
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"A")]),
+            // The global timestamp moves the clock forward to 10 us.
+            (at(11), vec![instrumentation(0, b"B")]),
+            // A global timestamp behind the local time does not move the clock backwards.
+            (at(13), vec![instrumentation(0, b"C")]),
+        ]
+    );
+}
+
+#[test]
+fn global_timestamps_disabled() {
+    let (res, packets) = decode("gts.dump", OnError::Abort, false);
+    assert_eq!(res.unwrap().skipped, 0);
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"A")]),
+            (
+                at(2),
+                vec![
+                    TracePacket::GlobalTimestamp1 {
+                        ts: 10,
+                        wrap: false,
+                        clkch: false
+                    },
+                    TracePacket::GlobalTimestamp2 { ts: 0 },
+                    instrumentation(0, b"B")
+                ]
+            ),
+            (
+                at(4),
+                vec![
+                    TracePacket::GlobalTimestamp1 {
+                        ts: 5,
+                        wrap: false,
+                        clkch: false
+                    },
+                    instrumentation(0, b"C")
+                ]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn overflow_resync() {
+    let (res, packets) = decode("overflow.dump", OnError::Abort, false);
+    assert_eq!(
+        res.unwrap(),
+        DecodeStats {
+            skipped: 0,
+            resyncs: 1,
+            unknown: 0,
+            clock_suggestion: None,
+        }
+    );
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"A")]),
+            // The packets between the malformed packet and the synchronization are discarded.
+            (
+                at(3),
+                vec![
+                    TracePacket::Overflow,
+                    TracePacket::Sync,
+                    instrumentation(0, b"C")
+                ]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn lenient() {
+    let options = DecodeOptions {
+        on_error: OnError::Abort,
+        lenient: true,
+        ..Default::default()
+    };
+    let (res, packets) = decode_with("unknown.dump", &options);
+    // An unknown hardware source packet and an extension packet with a continuation.
+    assert_eq!(res.unwrap().unknown, 2);
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"A")]),
+            (at(3), vec![instrumentation(0, b"B")]),
+        ]
+    );
+}
+
+#[test]
+fn builtin() {
+    let options = DecodeOptions {
+        on_error: OnError::Abort,
+        decoder: Decoder::Builtin,
+        ..Default::default()
+    };
+    let (res, itm) = decode("valid.dump", OnError::Abort, false);
+    res.unwrap();
+    let (res, packets) = decode_with("valid.dump", &options);
+    assert_eq!(res.unwrap().unknown, 0);
+    assert_eq!(packets, itm);
+
+    // Packets the built-in decoder does not support are skipped instead of failing.
+    let (res, packets) = decode_with("unknown.dump", &options);
+    assert_eq!(res.unwrap().unknown, 2);
+    assert_eq!(
+        packets,
+        [
+            (at(1), vec![TracePacket::Sync, instrumentation(0, b"A")]),
+            (at(3), vec![instrumentation(0, b"B")]),
+        ]
+    );
+}
+
+/// Global timestamps 2000 ticks apart with 20 local timestamps of `local` ticks in between.
+fn clock_check(local: u32) -> ClockCheck {
+    let global = |ts| TracePacket::GlobalTimestamp1 {
+        ts,
+        wrap: false,
+        clkch: false,
+    };
+    let mut packets = vec![
+        global(1000),
+        // The upper bits complete the first global timestamp.
+        TracePacket::GlobalTimestamp2 { ts: 0 },
+    ];
+    packets.extend((0..20).map(|_| TracePacket::LocalTimestamp1 {
+        ts: local,
+        data_relation: TimestampDataRelation::Sync,
+    }));
+    packets.push(global(3000));
+    let mut check = ClockCheck::default();
+    packets.iter().for_each(|packet| check.add(packet));
+    check
+}
+
+#[test]
+fn clock_consistent() {
+    let check = clock_check(100);
+    assert_eq!(check.ratio(), Some(1.0));
+    assert_eq!(check.suggest(64_000_000), None);
+}
+
+#[test]
+fn clock_prescaled() {
+    // The local timestamps are prescaled by 4.
+    let check = clock_check(25);
+    assert_eq!(check.ratio(), Some(0.25));
+    assert_eq!(check.suggest(64_000_000), Some(16_000_000));
+
+    // An overflow may have lost local timestamps, the measurement starts over.
+    let mut check = clock_check(25);
+    check.add(&TracePacket::Overflow);
+    assert_eq!(check.ratio(), None);
+}
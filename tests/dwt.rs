@@ -0,0 +1,69 @@
+use etf_trace::dwt::DwtSummary;
+use itm::{
+    cortex_m::{Exception, VectActive},
+    ExceptionAction, TracePacket,
+};
+
+#[test]
+fn summary() {
+    let mut summary = DwtSummary::default();
+    assert!(summary.is_empty());
+    let packets = [
+        TracePacket::PCSample {
+            pc: Some(0x0800_0100),
+        },
+        TracePacket::PCSample {
+            pc: Some(0x0800_0200),
+        },
+        TracePacket::PCSample {
+            pc: Some(0x0800_0200),
+        },
+        TracePacket::PCSample { pc: None },
+        TracePacket::ExceptionTrace {
+            exception: VectActive::Exception(Exception::SysTick),
+            action: ExceptionAction::Entered,
+        },
+        TracePacket::ExceptionTrace {
+            exception: VectActive::Exception(Exception::SysTick),
+            action: ExceptionAction::Exited,
+        },
+        TracePacket::ExceptionTrace {
+            exception: VectActive::ThreadMode,
+            action: ExceptionAction::Returned,
+        },
+        TracePacket::EventCounterWrap {
+            cyc: false,
+            fold: false,
+            lsu: false,
+            sleep: true,
+            exc: false,
+            cpi: true,
+        },
+        TracePacket::Sync,
+    ];
+    packets.iter().for_each(|packet| summary.add(packet));
+    assert_eq!(summary.sleep_samples, 1);
+    assert_eq!(
+        summary.hottest(1),
+        [(0x0800_0200, 2)],
+        "the most sampled program counter first"
+    );
+    let systick = summary.exceptions[&15];
+    assert_eq!(
+        (systick.entered, systick.exited, systick.returned),
+        (1, 1, 0)
+    );
+    assert_eq!(summary.exceptions[&0].returned, 1);
+    assert_eq!(summary.counter_wraps, [1, 0, 1, 0, 0, 0]);
+
+    let table = summary.table(None, 1);
+    assert!(table.contains("       2  50.00  0x08000200"), "{table}");
+    assert!(table.contains("1 other program counters"), "{table}");
+    assert!(table.contains("SysTick"), "{table}");
+    assert!(table.contains("      cpi        1      >=256"), "{table}");
+
+    assert_eq!(
+        summary.folded(None),
+        "0x08000100 1\n0x08000200 2\nsleep 1\n"
+    );
+}
@@ -0,0 +1,96 @@
+use etf_trace::{
+    etf::{circular_window, remove_empty, Status, EMPTY},
+    etr::EtrBuffer,
+};
+
+#[test]
+fn status_drained() {
+    // Ready and formatter pipeline empty.
+    assert!(Status::from(0b0_1100).drained());
+}
+
+#[test]
+fn status_ready_formatter_pending() {
+    // Ready but the formatter pipeline still holds the final frame.
+    let status = Status::from(0b0_0100);
+    assert!(status.ready());
+    assert!(!status.ftempty());
+    assert!(!status.drained());
+}
+
+#[test]
+fn status_not_ready() {
+    // Formatter empty but the capture has not stopped yet.
+    assert!(!Status::from(0b0_1000).drained());
+}
+
+#[test]
+fn empty_sentinels_removed() {
+    // The FIFO ran empty during the block transfer and data arrived again.
+    let mut buf = [1, 2, EMPTY, EMPTY, 3, EMPTY, 4, EMPTY];
+    let count = remove_empty(&mut buf);
+    assert_eq!(buf[..count], [1, 2, 3, 4]);
+    assert!(!buf[..count].contains(&EMPTY));
+    assert_eq!(remove_empty(&mut [EMPTY; 4]), 0);
+}
+
+/// Read a window of a trace RAM of words, wrapping the read pointer at its end as the ETF does.
+fn read_window(memory: &[u32], (start, len): (u32, u32)) -> Vec<u32> {
+    (0..len as usize / 4)
+        .map(|i| memory[(start as usize / 4 + i) % memory.len()])
+        .collect()
+}
+
+#[test]
+fn circular_window_wrapped() {
+    // A 64 byte trace RAM that was written with the words 0 to 19: the oldest word left is 4 at
+    // the write pointer.
+    let mut memory = [0; 16];
+    for word in 0..20 {
+        memory[word as usize % 16] = word;
+    }
+    let write_pointer = 4 * 4;
+    let window = circular_window(64, write_pointer, true, None);
+    assert_eq!(window, (16, 64));
+    assert_eq!(read_window(&memory, window), (4..20).collect::<Vec<_>>());
+
+    // The most recent formatter frame only.
+    let window = circular_window(64, write_pointer, true, Some(6));
+    assert_eq!(window, (0, 16));
+    assert_eq!(read_window(&memory, window), [16, 17, 18, 19]);
+}
+
+#[test]
+fn circular_window_not_wrapped() {
+    // Only the data up to the write pointer is valid.
+    assert_eq!(circular_window(64, 48, false, None), (0, 48));
+    assert_eq!(circular_window(64, 48, false, Some(5)), (32, 16));
+    assert_eq!(circular_window(64, 0, false, None), (0, 0));
+    // A TMC without trace RAM.
+    assert_eq!(circular_window(0, 16, true, None), (0, 0));
+}
+
+#[test]
+fn etr_buffer() {
+    let buffer: EtrBuffer = "0x30000000:64".parse().unwrap();
+    assert_eq!(
+        buffer,
+        EtrBuffer {
+            address: 0x3000_0000,
+            len: 64
+        }
+    );
+    // Not wrapped: the data up to the write pointer.
+    assert_eq!(buffer.regions(0x3000_0000, false), []);
+    assert_eq!(buffer.regions(0x3000_0020, false), [(0x3000_0000, 32)]);
+    // Wrapped: the oldest data at the write pointer first.
+    assert_eq!(
+        buffer.regions(0x3000_0010, true),
+        [(0x3000_0010, 48), (0x3000_0000, 16)]
+    );
+    assert_eq!(buffer.regions(0x3000_0000, true), [(0x3000_0000, 64)]);
+
+    assert!("0x30000002:64".parse::<EtrBuffer>().is_err());
+    assert!("0x30000000:10".parse::<EtrBuffer>().is_err());
+    assert!("0x30000000".parse::<EtrBuffer>().is_err());
+}
@@ -0,0 +1,166 @@
+use etf_trace::formatter::{deformat, format, sources, split, Deformatter, ITM_ID};
+
+/// A frame switching to the ITM ID and carrying 14 data bytes.
+const ITM_FRAME: [u8; 16] = [
+    (ITM_ID << 1) | 1,
+    0x11,
+    0x22, // 0x23, LSB in the auxiliary byte
+    0x33,
+    0x44,
+    0x55,
+    0x66,
+    0x77,
+    0x88,
+    0x99,
+    0xaa,
+    0xbb,
+    0xcc,
+    0xdd,
+    0xee,
+    0b0000_0010,
+];
+
+#[test]
+fn single_source() {
+    assert_eq!(
+        deformat(&ITM_FRAME, ITM_ID),
+        [0x11, 0x23, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]
+    );
+}
+
+#[test]
+fn source_switch() {
+    let mut frame = ITM_FRAME;
+    // Switch to ID 2 at byte 4, the following byte still belongs to the ITM.
+    frame[4] = (2 << 1) | 1;
+    frame[15] |= 1 << 2;
+    let data = [ITM_FRAME, frame].concat();
+
+    let mut itm = deformat(&ITM_FRAME, ITM_ID);
+    itm.extend([0x11, 0x23, 0x33, 0x55]);
+    assert_eq!(deformat(&data, ITM_ID), itm);
+    assert_eq!(
+        deformat(&data, 2),
+        [0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]
+    );
+    assert_eq!(
+        sources(&data).into_iter().collect::<Vec<_>>(),
+        [(2, 9), (ITM_ID, 18)]
+    );
+}
+
+#[test]
+fn partial_frame() {
+    // A trailing partial frame, e.g. after a manual flush, is ignored.
+    let data = [&ITM_FRAME[..], &ITM_FRAME[..7]].concat();
+    assert_eq!(deformat(&data, ITM_ID), deformat(&ITM_FRAME, ITM_ID));
+}
+
+#[test]
+fn frame_sync() {
+    // Full frame syncs between frames are removed and do not misalign the frames.
+    let data = [
+        &[0xff, 0xff, 0xff, 0x7f][..],
+        &ITM_FRAME[..],
+        &[0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff, 0x7f][..],
+        &ITM_FRAME[..],
+    ]
+    .concat();
+    assert_eq!(
+        deformat(&data, ITM_ID),
+        deformat(&[ITM_FRAME, ITM_FRAME].concat(), ITM_ID)
+    );
+}
+
+#[test]
+fn halfword_sync() {
+    let mut frame = ITM_FRAME;
+    frame[4] = 0xff;
+    frame[5] = 0x7f;
+    // The sync neither carries data nor changes the source.
+    assert_eq!(
+        deformat(&frame, ITM_ID),
+        [0x11, 0x23, 0x33, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]
+    );
+}
+
+#[test]
+fn trigger() {
+    let mut frame = ITM_FRAME;
+    // A trigger byte at byte 5, then back to the ITM at byte 6.
+    frame[4] = (0x7d << 1) | 1;
+    frame[6] = (ITM_ID << 1) | 1;
+    let data = [frame, ITM_FRAME].concat();
+
+    let mut itm = vec![
+        0x11, 0x23, 0x33, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+    ];
+    itm.extend(deformat(&ITM_FRAME, ITM_ID));
+    assert_eq!(deformat(&data, ITM_ID), itm);
+    assert!(deformat(&data, 0x7d).is_empty());
+    assert_eq!(
+        sources(&data).into_iter().collect::<Vec<_>>(),
+        [(ITM_ID, itm.len())]
+    );
+}
+
+#[test]
+fn format_roundtrip() {
+    let data: Vec<u8> = (0..=255).rev().collect();
+    for len in 0..40 {
+        let frames = format(&data[..len], ITM_ID);
+        assert_eq!(frames.len(), len.div_ceil(14) * 16);
+        assert_eq!(deformat(&frames, ITM_ID), &data[..len]);
+    }
+    // Frames of different sources can be concatenated.
+    let frames = [format(&data[..20], 2), format(&data[..9], ITM_ID)].concat();
+    assert_eq!(deformat(&frames, 2), &data[..20]);
+    assert_eq!(deformat(&frames, ITM_ID), &data[..9]);
+}
+
+#[test]
+fn split_reads() {
+    // A frame with data only, continuing the source of the previous frame.
+    let data: Vec<u8> = (0x10..0x1f).collect();
+    let frame: Vec<u8> = [&data[..], &[0]].concat();
+    let frames = [&ITM_FRAME[..], &frame].concat();
+    let mut current = 0;
+    let whole = split(&frames, &mut current).remove(&ITM_ID).unwrap();
+    assert_eq!(current, ITM_ID);
+    assert_eq!(whole, [deformat(&ITM_FRAME, ITM_ID), data.clone()].concat());
+
+    // The same frames in two reads.
+    let mut current = 0;
+    let mut reads = split(&ITM_FRAME, &mut current).remove(&ITM_ID).unwrap();
+    reads.extend(split(&frame, &mut current).remove(&ITM_ID).unwrap());
+    assert_eq!(reads, whole);
+    // Without the source of the previous read, the data has no source.
+    assert!(deformat(&frame, ITM_ID).is_empty());
+}
+
+#[test]
+fn deformatter_chunks() {
+    let frames = [
+        format(b"first", ITM_ID),
+        vec![0xFF, 0xFF, 0xFF, 0x7F],
+        format(b"other", 2),
+        format(b"second", ITM_ID),
+    ]
+    .concat();
+    let mut deformatter = Deformatter::new();
+    let mut itm = vec![];
+    let mut other = vec![];
+    // Chunks that split frames and the synchronization sequence.
+    for chunk in frames.chunks(5) {
+        let mut found = deformatter.push(chunk);
+        itm.extend(found.remove(&ITM_ID).unwrap_or_default());
+        other.extend(found.remove(&2).unwrap_or_default());
+        assert!(found.is_empty());
+    }
+    assert_eq!(itm, b"firstsecond");
+    assert_eq!(other, b"other");
+    assert_eq!(deformatter.pending(), 0);
+
+    deformatter.push(&frames[..24]);
+    assert_eq!(deformatter.pending(), 4);
+}
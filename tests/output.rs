@@ -0,0 +1,258 @@
+use etf_trace::output::{
+    binary_record, color_row, csv_record, golden_record, json_record, offset_timestamp,
+    packet_kind, relative_timestamp, sql_metadata, sql_record, sql_schema, vcd_change, BinaryPort,
+    BinaryRecords, Console, CycleClock, Format, PacketType, RecordFormat,
+};
+use itm::{
+    cortex_m::{Exception, VectActive},
+    ExceptionAction, Timestamp, TracePacket,
+};
+use std::time::Duration;
+
+#[test]
+fn json_data_relation() {
+    let at = Timestamp::AssocEventDelay(Duration::from_micros(2));
+    let packet = TracePacket::Instrumentation {
+        port: 3,
+        payload: b"A".to_vec(),
+    };
+    assert_eq!(
+        json_record(&at, &packet),
+        concat!(
+            r#"{"timestamp_ns":2000,"data_relation":"assoc_event_delay","#,
+            r#""type":"instrumentation","source":3,"payload":"41"}"#
+        )
+    );
+    // Golden records are unchanged.
+    assert_eq!(
+        golden_record(Some(&at), &packet),
+        r#"{"timestamp_ns":2000,"type":"instrumentation","source":3,"payload":"41"}"#
+    );
+}
+
+#[test]
+fn csv_quoting() {
+    let at = Timestamp::Sync(Duration::from_micros(2));
+    let packet = TracePacket::Instrumentation {
+        port: 3,
+        payload: vec![0x2c, 0x00],
+    };
+    assert_eq!(csv_record(&at, &packet), "2000,instrumentation,3,2c 00");
+    let packet = TracePacket::EventCounterWrap {
+        cyc: true,
+        fold: false,
+        lsu: true,
+        sleep: false,
+        exc: false,
+        cpi: false,
+    };
+    assert_eq!(csv_record(&at, &packet), "2000,counter-wrap,,\"cyc,lsu\"");
+}
+
+#[test]
+fn sql_quoting() {
+    let schema = sql_schema();
+    assert!(
+        schema.contains("CREATE TABLE IF NOT EXISTS events ("),
+        "{schema}"
+    );
+    assert!(schema.ends_with("BEGIN;"), "{schema}");
+    let at = Timestamp::Sync(Duration::from_micros(2));
+    let packet = TracePacket::Instrumentation {
+        port: 3,
+        payload: vec![0x2c, 0x00],
+    };
+    assert_eq!(
+        sql_record(&at, &packet),
+        "INSERT INTO events (timestamp_ns, type, port, payload) \
+         VALUES (2000, 'instrumentation', 3, '2c 00');"
+    );
+    assert_eq!(
+        sql_record(&at, &TracePacket::Overflow),
+        "INSERT INTO events (timestamp_ns, type, port, payload) \
+         VALUES (2000, 'overflow', NULL, '');"
+    );
+    assert_eq!(
+        binary_record(Format::Sql, &at, 5, "it's"),
+        "INSERT INTO events (timestamp_ns, type, port, payload) \
+         VALUES (2000, 'binary', 5, 'it''s');"
+    );
+    assert_eq!(
+        sql_metadata("label", "'a'"),
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES ('label', '''a''');"
+    );
+}
+
+#[test]
+fn format_from_extension() {
+    assert_eq!(Format::from_extension("trace.CSV"), Some(Format::Csv));
+    assert_eq!(Format::from_extension("trace.ndjson"), Some(Format::Json));
+    assert_eq!(Format::from_extension("trace.bin"), None);
+    assert_eq!(Format::from_extension("trace"), None);
+}
+
+#[test]
+fn timestamp_offset() {
+    let at = |us| Duration::from_micros(us);
+    let timestamp = Timestamp::UnknownDelay {
+        prev: at(3),
+        curr: at(5),
+    };
+    // Relative to the first timestamp, then placed at the offset.
+    assert_eq!(
+        offset_timestamp(&relative_timestamp(&timestamp, at(3)), at(1000)),
+        Timestamp::UnknownDelay {
+            prev: at(1000),
+            curr: at(1002),
+        }
+    );
+}
+
+#[test]
+fn cycle_clock() {
+    let cyccnt = |value: u32| TracePacket::Instrumentation {
+        port: 2,
+        payload: value.to_le_bytes().to_vec(),
+    };
+    assert!(CycleClock::new(2, 0).is_err());
+    let mut clock = CycleClock::new(2, 100_000_000).unwrap();
+    assert_eq!(clock.update(&[]), None);
+    assert_eq!(clock.update(&[cyccnt(0xffff_ff00)]), Some(Duration::ZERO));
+    // Counter values on other ports are ignored.
+    let other = TracePacket::Instrumentation {
+        port: 3,
+        payload: vec![0; 4],
+    };
+    assert_eq!(
+        clock.update(&[other, cyccnt(0x0000_0100)]),
+        Some(Duration::from_nanos(5120))
+    );
+    assert_eq!(clock.cycles(), 0x200);
+}
+
+#[test]
+fn binary_records() {
+    let instrumentation = |port, payload: &[u8]| TracePacket::Instrumentation {
+        port,
+        payload: payload.to_vec(),
+    };
+    let port = "2:5".parse::<BinaryPort>().unwrap();
+    let format = "a={i16} b={u8} {{{hex}}}".parse::<RecordFormat>().unwrap();
+    assert_eq!(format.size(), 3);
+    let mut records = BinaryRecords::new(port, Some(format));
+    // A record written with a 32 bit and an 8 bit stimulus write, interleaved with another port.
+    let mut packets = vec![
+        instrumentation(2, &[0xfe, 0xff, 0x07, 0xaa]),
+        instrumentation(0, b"A"),
+    ];
+    assert!(records.extract(&mut packets).is_empty());
+    assert_eq!(packets, [instrumentation(0, b"A")]);
+    let mut packets = vec![instrumentation(2, &[0xbb, 0x01, 0x00])];
+    assert_eq!(records.extract(&mut packets), ["a=-2 b=7 {aa bb}"]);
+    assert!(packets.is_empty());
+    assert_eq!(records.pending(), 2);
+
+    assert!("{u24}".parse::<RecordFormat>().is_err());
+    assert!("{u8".parse::<RecordFormat>().is_err());
+    assert!("2:0".parse::<BinaryPort>().is_err());
+}
+
+#[test]
+fn record_format_hex_last() {
+    assert!("{hex} {u8}".parse::<RecordFormat>().is_err());
+    assert!("{hex} {hex}".parse::<RecordFormat>().is_err());
+    let format = "{u8} {hex} end".parse::<RecordFormat>().unwrap();
+    assert_eq!(format.apply(&[1, 2, 3, 4]), "1 02 03 04 end");
+    // A record shorter than the format does not panic.
+    let format = "{u16} {u32}".parse::<RecordFormat>().unwrap();
+    assert_eq!(format.apply(&[1, 0, 2]), "1 ?");
+}
+
+#[test]
+fn console() {
+    let instrumentation = |port, payload: &[u8]| TracePacket::Instrumentation {
+        port,
+        payload: payload.to_vec(),
+    };
+    let mut console = Console::new(1);
+    // "µs" with the two byte µ split across reads, interleaved with another port.
+    let packets = [instrumentation(1, b"1 \xc2"), instrumentation(0, b"A")];
+    assert_eq!(console.push(&packets), "1 ");
+    assert_eq!(console.push(&[instrumentation(1, b"\xb5s\n")]), "µs\n");
+    // An invalid byte is replaced, an incomplete sequence at the end is kept until the end.
+    assert_eq!(
+        console.push(&[instrumentation(1, b"a\xffb\xe2\x82")]),
+        "a\u{fffd}b"
+    );
+    assert_eq!(console.finish(), "\u{fffd}");
+    assert_eq!(console.finish(), "");
+}
+
+#[test]
+fn vcd() {
+    let instrumentation = TracePacket::Instrumentation {
+        port: 5,
+        payload: vec![0x02, 0x01],
+    };
+    assert_eq!(vcd_change(&instrumentation).unwrap(), "b100000010 p5");
+    let exception = |exception, action| TracePacket::ExceptionTrace { exception, action };
+    let systick = VectActive::Exception(Exception::SysTick);
+    assert_eq!(
+        vcd_change(&exception(systick, ExceptionAction::Entered)).unwrap(),
+        "b1111 e"
+    );
+    assert_eq!(
+        vcd_change(&exception(systick, ExceptionAction::Exited)),
+        None
+    );
+    assert_eq!(
+        vcd_change(&exception(
+            VectActive::ThreadMode,
+            ExceptionAction::Returned
+        ))
+        .unwrap(),
+        "b0 e"
+    );
+    assert_eq!(Format::from_extension("trace.vcd"), Some(Format::Vcd));
+}
+
+#[test]
+fn packet_types() {
+    use clap::ValueEnum;
+    let packets = [
+        TracePacket::Sync,
+        TracePacket::Overflow,
+        TracePacket::LocalTimestamp2 { ts: 1 },
+        TracePacket::Instrumentation {
+            port: 0,
+            payload: vec![0],
+        },
+        TracePacket::ExceptionTrace {
+            exception: VectActive::Exception(Exception::SysTick),
+            action: ExceptionAction::Entered,
+        },
+    ];
+    // The types are named as in the other outputs.
+    for packet in &packets {
+        assert_eq!(
+            PacketType::from_str(packet_kind(packet), false),
+            Ok(PacketType::of(packet))
+        );
+    }
+}
+
+#[test]
+fn colors() {
+    let exception = TracePacket::ExceptionTrace {
+        exception: VectActive::Exception(Exception::SysTick),
+        action: ExceptionAction::Entered,
+    };
+    assert_eq!(color_row(&exception, "row"), "\x1b[31mrow\x1b[0m");
+    let timestamp = TracePacket::LocalTimestamp2 { ts: 1 };
+    assert_eq!(color_row(&timestamp, "row"), "\x1b[2mrow\x1b[0m");
+    let packet = TracePacket::Instrumentation {
+        port: 0,
+        payload: vec![0],
+    };
+    assert_eq!(color_row(&packet, "row"), "row");
+}
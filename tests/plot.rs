@@ -0,0 +1,7 @@
+#![cfg(feature = "plot")]
+use etf_trace::plot::sparkline;
+
+#[test]
+fn levels() {
+    assert_eq!(sparkline([0, 14, 50, 99, 100, 255]), "▁▁▄▇██");
+}
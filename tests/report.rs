@@ -0,0 +1,96 @@
+use etf_trace::{
+    etf::Mode,
+    report::{crc32, CaptureConfig, CaptureReport, DumpHeader},
+};
+use std::io::Read;
+
+#[test]
+fn sidecar_json() {
+    let report = CaptureReport {
+        label: Some("build \"42\"".into()),
+        config: CaptureConfig {
+            target: "STM32H743ZITx".into(),
+            core: 0,
+            mode: Some(Mode::Software),
+            coreclk: 400_000_000,
+            funnel_ports: Some(vec![0, 2]),
+            etf_address: 0xe00f_3000,
+            fifo_size: 4096,
+            formatter: true,
+            trace_id: 13,
+        },
+        bytes: 123,
+        crc32: 0xcbf4_3926,
+    };
+    assert_eq!(
+        report.to_json(),
+        r#"{
+  "label": "build \"42\"",
+  "config": {
+    "target": "STM32H743ZITx",
+    "core": 0,
+    "mode": "software",
+    "coreclk": 400000000,
+    "funnel_ports": [0, 2],
+    "etf_address": "0xe00f3000",
+    "fifo_size": 4096,
+    "formatter": true,
+    "trace_id": 13
+  },
+  "bytes": 123,
+  "crc32": "0xcbf43926"
+}
+"#
+    );
+    assert_eq!(
+        CaptureReport::integrity(&report.to_json()),
+        Some((123, 0xcbf4_3926))
+    );
+    assert_eq!(CaptureReport::coreclk(&report.to_json()), Some(400_000_000));
+    // The sidecar of an older version without a checksum.
+    assert_eq!(CaptureReport::integrity("{\n  \"bytes\": 123\n}\n"), None);
+    assert_eq!(
+        CaptureReport::sidecar_path("trace.bin"),
+        std::path::Path::new("trace.bin.json")
+    );
+}
+
+#[test]
+fn checksum() {
+    assert_eq!(crc32(&b""[..]).unwrap(), 0);
+    // The check value of the CRC-32.
+    assert_eq!(crc32(&b"123456789"[..]).unwrap(), 0xcbf4_3926);
+}
+
+#[test]
+fn dump_header() {
+    let header = DumpHeader {
+        tool: "0.1.0".into(),
+        captured: 1_700_000_000,
+        config: CaptureConfig {
+            target: "STM32H743ZITx".into(),
+            coreclk: 100_000_000,
+            ..Default::default()
+        },
+    };
+    let mut dump = header.to_bytes();
+    dump.extend_from_slice(b"trace");
+    let (json, mut itm) = DumpHeader::read(dump.as_slice()).unwrap();
+    let json = json.unwrap();
+    assert_eq!(json, header.to_json());
+    assert_eq!(CaptureReport::coreclk(&json), Some(100_000_000));
+    let mut data = vec![];
+    itm.read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"trace");
+
+    // A dump without the magic is raw trace data, even if it is shorter than the magic.
+    for raw in [&b"\x00\x00\x00\x00\x00\x80\x01A"[..], b"\x01A"] {
+        let (json, mut itm) = DumpHeader::read(raw).unwrap();
+        assert!(json.is_none());
+        let mut data = vec![];
+        itm.read_to_end(&mut data).unwrap();
+        assert_eq!(data, raw);
+    }
+
+    assert!(DumpHeader::read(&dump[..20]).is_err());
+}
@@ -0,0 +1,42 @@
+use etf_trace::{
+    decode_trace,
+    resume::{ResumeState, SEAM},
+    DecodeOptions, Decoder, OnError,
+};
+use itm::TracePacket;
+
+#[test]
+fn state_json() {
+    let state = ResumeState {
+        bytes: 1234,
+        resumptions: 2,
+    };
+    assert_eq!(ResumeState::from_json(&state.to_json()), Some(state));
+    assert_eq!(ResumeState::from_json("{\"bytes\": x}"), None);
+}
+
+#[test]
+fn seam() {
+    let valid = std::fs::read("tests/fixtures/valid.dump").unwrap();
+    // The previous run ended within the payload of an instrumentation packet.
+    let data = [&valid[..11], &SEAM[..], &valid[..]].concat();
+    for decoder in [Decoder::Itm, Decoder::Builtin] {
+        let options = DecodeOptions {
+            on_error: OnError::Abort,
+            decoder,
+            ..Default::default()
+        };
+        let decode = |data: &[u8]| {
+            let mut packets = vec![];
+            decode_trace(data, &options, |p| packets.extend(p.packets)).unwrap();
+            packets
+        };
+        // The resumed run is decoded completely after the overflow marking the seam.
+        let packets = decode(&data);
+        let seam = packets
+            .iter()
+            .position(|packet| *packet == TracePacket::Overflow)
+            .unwrap();
+        assert_eq!(packets[seam + 1..], decode(&valid));
+    }
+}
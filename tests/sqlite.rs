@@ -0,0 +1,50 @@
+#![cfg(feature = "sqlite")]
+use etf_trace::sqlite::Database;
+use itm::{Timestamp, TracePacket};
+use std::time::Duration;
+
+#[test]
+fn database() {
+    let path = std::env::temp_dir().join(format!("etf-trace-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let at = Timestamp::Sync(Duration::from_micros(2));
+    let mut database = Database::create(&path).unwrap();
+    database.set_metadata("label", "it's").unwrap();
+    let packet = TracePacket::Instrumentation {
+        port: 3,
+        payload: vec![0x2c, 0x00],
+    };
+    database.insert_packet(&at, &packet).unwrap();
+    database.insert_record(&at, 5, "x=1").unwrap();
+    database.commit().unwrap();
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let mut query = connection
+        .prepare("SELECT timestamp_ns, type, port, payload FROM events ORDER BY id")
+        .unwrap();
+    let events: Vec<(i64, String, Option<u8>, String)> = query
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        events,
+        [
+            (2000, "instrumentation".into(), Some(3), "2c 00".into()),
+            (2000, "binary".into(), Some(5), "x=1".into()),
+        ]
+    );
+    let label: String = connection
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'label'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(label, "it's");
+    drop(query);
+    drop(connection);
+    std::fs::remove_file(&path).unwrap();
+}
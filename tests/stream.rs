@@ -0,0 +1,101 @@
+use etf_trace::stream::{Pacer, Watermarks, MAX_INTERVAL, MIN_INTERVAL};
+
+/// FIFO size in bytes.
+const SIZE: f64 = 4096.0;
+
+/// Simulate a streaming capture of a source producing `rate` bytes per microsecond.
+///
+/// Every poll drains the FIFO completely. A drain takes `drain_us` during which the source keeps
+/// producing.
+///
+/// # Returns
+/// The fill levels found at the polls, in percent, and whether the FIFO ran full.
+fn simulate(pacer: &mut Pacer, rate: f64, drain_us: f64, polls: usize) -> (Vec<u8>, bool) {
+    let mut fill = 0.0;
+    let mut levels = vec![];
+    let mut full = false;
+    for _ in 0..polls {
+        let percent = (fill / SIZE * 100.0).min(100.0) as u8;
+        levels.push(percent);
+        let wait = pacer.next(percent);
+        fill = rate * (drain_us + wait.as_secs_f64() * 1e6);
+        full |= fill >= SIZE;
+    }
+    (levels, full)
+}
+
+#[test]
+fn fast_producer() {
+    let mut pacer = Pacer::new(Watermarks::default());
+    // 2 MB/s fills the FIFO in about 2 ms.
+    let (levels, full) = simulate(&mut pacer, 2.0, 100.0, 1000);
+    assert!(!full);
+    // The fill level settles between the watermarks.
+    assert!(levels[100..].iter().all(|level| (25..75).contains(level)));
+}
+
+#[test]
+fn rate_increase() {
+    let mut pacer = Pacer::new(Watermarks::default());
+    let (_, full) = simulate(&mut pacer, 0.01, 100.0, 100);
+    assert!(!full);
+    assert_eq!(pacer.interval(), MAX_INTERVAL);
+    // A source that fills the FIFO in 1.5 ms does not stall even from the longest interval.
+    let (levels, full) = simulate(&mut pacer, 2.7, 100.0, 100);
+    assert!(!full);
+    assert!(levels[10..].iter().all(|level| *level < 75));
+    // Faster than the FIFO can be drained, polling is continuous.
+    let (_, full) = simulate(&mut pacer, 50.0, 100.0, 100);
+    assert!(full);
+    assert_eq!(pacer.interval(), MIN_INTERVAL);
+}
+
+#[test]
+fn invalid_watermarks() {
+    assert!(Watermarks::new(50, 50).is_err());
+    assert!(Watermarks::new(10, 101).is_err());
+    assert_eq!(
+        Watermarks::new(10, 100).unwrap(),
+        Watermarks { low: 10, high: 100 }
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn delay() {
+    use etf_trace::stream::Delay;
+    use std::{
+        future::Future,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+        thread::Thread,
+        time::{Duration, Instant},
+    };
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Arc::new(Unpark(std::thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let start = Instant::now();
+    let mut delay = std::pin::pin!(Delay::new(Duration::from_millis(10)));
+    while delay.as_mut().poll(&mut cx) == Poll::Pending {
+        std::thread::park();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(10));
+
+    // A shorter delay scheduled after a pending longer one completes first.
+    let mut long = std::pin::pin!(Delay::new(Duration::from_secs(10)));
+    assert_eq!(long.as_mut().poll(&mut cx), Poll::Pending);
+    let start = Instant::now();
+    let mut short = std::pin::pin!(Delay::new(Duration::from_millis(5)));
+    while short.as_mut().poll(&mut cx) == Poll::Pending {
+        std::thread::park();
+    }
+    assert!(start.elapsed() < Duration::from_secs(10));
+}